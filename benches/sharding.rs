@@ -0,0 +1,76 @@
+//! Compares the announce throughput of the sharded torrent store with a store
+//! behind a single lock, with each thread announcing to its own torrents.
+//! Run with `cargo bench`.
+
+use std::{
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use parking_lot::Mutex;
+use unit3d_announce::{Shard, Torrent, TorrentStore};
+
+const THREADS: u32 = 8;
+const TORRENTS_PER_THREAD: u32 = 64;
+
+fn announce(shard: &mut Shard, torrent_id: u32) {
+    let torrent = shard.get_mut(&torrent_id).unwrap();
+
+    torrent.times_completed = black_box(torrent.times_completed.wrapping_add(1));
+}
+
+/// Measures the time taken by all threads to announce the given amount of
+/// times in total.
+fn announce_concurrently(iterations: u64, announce: impl Fn(u32) + Sync) -> Duration {
+    let announces_per_thread = iterations.div_ceil(u64::from(THREADS));
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        for thread in 0..THREADS {
+            let announce = &announce;
+
+            scope.spawn(move || {
+                for i in 0..announces_per_thread {
+                    announce(thread * TORRENTS_PER_THREAD + i as u32 % TORRENTS_PER_THREAD);
+                }
+            });
+        }
+    });
+
+    start.elapsed()
+}
+
+fn sharding(c: &mut Criterion) {
+    let single_lock = Mutex::new(Shard::new());
+    let mut sharded = TorrentStore::new();
+
+    for id in 0..THREADS * TORRENTS_PER_THREAD {
+        let torrent = Torrent {
+            id,
+            ..Default::default()
+        };
+
+        single_lock.lock().insert(id, torrent.clone());
+        sharded.shard_mut(id).insert(id, torrent);
+    }
+
+    let mut group = c.benchmark_group("announce");
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single lock", |b| {
+        b.iter_custom(|iterations| {
+            announce_concurrently(iterations, |id| announce(&mut single_lock.lock(), id))
+        })
+    });
+    group.bench_function("sharded", |b| {
+        b.iter_custom(|iterations| {
+            announce_concurrently(iterations, |id| announce(&mut sharded.lock(id), id))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sharding);
+criterion_main!(benches);
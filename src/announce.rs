@@ -15,38 +15,45 @@ use std::{
     str::FromStr,
     sync::Arc,
 };
-use tokio::net::TcpStream;
+use tokio::time::Instant;
 
 use crate::{
+    bencode,
+    connectivity::{self, Connectivity},
     error::AnnounceError::{
-        self, AbnormalAccess, BlacklistedClient, BlacklistedPort, DownloadPrivilegesRevoked,
-        GroupNotEnabled, GroupNotFound, InfoHashNotFound, InternalTrackerError, InvalidCompact,
-        InvalidDownloaded, InvalidInfoHash, InvalidLeft, InvalidNumwant, InvalidPasskey,
-        InvalidPeerId, InvalidPort, InvalidQueryStringKey, InvalidQueryStringValue,
-        InvalidUploaded, InvalidUserAgent, MissingDownloaded, MissingInfoHash, MissingLeft,
-        MissingPeerId, MissingPort, MissingUploaded, NotAClient, PasskeyNotFound,
+        self, AbnormalAccess, BannedIpAddress, BlacklistedClient, BlacklistedPort, ClientMismatch,
+        DownloadPrivilegesRevoked, GroupNotEnabled, GroupNotFound, InfoHashNotFound,
+        InternalTrackerError, InvalidCompact, InvalidDownloaded, InvalidInfoHash, InvalidLeft,
+        InvalidNumwant, InvalidPasskey, InvalidPeerId, InvalidPort, InvalidQueryStringKey,
+        InvalidQueryStringValue, InvalidUploaded, InvalidUserAgent, MissingDownloaded,
+        MissingInfoHash, MissingLeft, MissingPeerId, MissingPort, MissingUploaded, PasskeyNotFound,
         PeersPerTorrentPerUserLimit, StoppedPeerDoesNotExist, TorrentIsDeleted,
         TorrentIsPendingModeration, TorrentIsPostponed, TorrentIsRejected, TorrentNotFound,
         TorrentUnknownModerationStatus, UnsupportedEvent, UserAgentTooLong, UserNotFound,
     },
+    fingerprint,
     model::{
-        info_hash::InfoHash, passkey::Passkey, peer_id::PeerId, torrent_status::TorrentStatus,
+        info_hash::InfoHash, info_hash_v2::InfoHashV2, info_hash_version::InfoHashVersion,
+        passkey::Passkey, peer_id::PeerId, torrent_status::TorrentStatus,
     },
     queue::{
         announce_update::AnnounceUpdate,
+        client_mismatch_update::{self, ClientMismatchUpdate},
         history_update::{self, HistoryUpdate},
         peer_update::{self, PeerUpdate},
         torrent_update::{self, TorrentUpdate},
         unregistered_info_hash_update::{self, UnregisteredInfoHashUpdate},
+        upload_anomaly_update::{self, UploadAnomalyUpdate},
         user_update::{self, UserUpdate},
     },
+    upload_anomaly::{self, Swarm},
     warning::{AnnounceWarning, WarningCollection},
+    websocket,
 };
 
 use crate::state::AppState;
 use crate::store::{
     self,
-    connectable_port::ConnectablePort,
     featured_torrent::FeaturedTorrent,
     freeleech_token::FreeleechToken,
     peer::{self, Peer},
@@ -89,16 +96,29 @@ impl FromStr for Event {
 }
 
 pub struct Announce {
-    info_hash: InfoHash,
-    peer_id: PeerId,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    left: u64,
-    event: Event,
-    numwant: usize,
-    corrupt: Option<u64>,
-    key: Option<String>,
+    pub info_hash: InfoHash,
+    /// Only known if the client sent the full v2 info hash.
+    pub info_hash_version: Option<InfoHashVersion>,
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    pub numwant: usize,
+    pub corrupt: Option<u64>,
+    pub key: Option<String>,
+    /// Respond with the BEP 23 compact peer lists instead of the BEP 3 list
+    /// of peer dictionaries.
+    pub compact: bool,
+    /// Omit the peer ids from the list of peer dictionaries.
+    pub no_peer_id: bool,
+    /// Ipv4 address reported by the client (BEP 7).
+    pub ipv4: Option<IpAddr>,
+    /// Ipv6 address reported by the client (BEP 7).
+    pub ipv6: Option<IpAddr>,
+    /// Announced through the websocket tracker by a WebRTC peer.
+    pub is_webrtc: bool,
 }
 
 pub struct Query<T>(pub T);
@@ -119,6 +139,7 @@ where
         let mut ampersand_positions = memchr::memchr_iter(b'&', query_bytes);
 
         let mut info_hash: Option<InfoHash> = None;
+        let mut info_hash_version: Option<InfoHashVersion> = None;
         let mut peer_id: Option<PeerId> = None;
         let mut port: Option<u16> = None;
         let mut uploaded: Option<u64> = None;
@@ -128,6 +149,10 @@ where
         let mut numwant: Option<usize> = None;
         let mut corrupt: Option<u64> = None;
         let mut key: Option<String> = None;
+        let mut compact: Option<bool> = None;
+        let mut no_peer_id: Option<bool> = None;
+        let mut ipv4: Option<IpAddr> = None;
+        let mut ipv6: Option<IpAddr> = None;
 
         for equal_sign_pos in memchr::memchr_iter(b'=', query_bytes) {
             let value_end_pos = ampersand_positions.next().unwrap_or(query_length);
@@ -141,9 +166,18 @@ where
 
             match parameter {
                 "info_hash" => {
-                    info_hash = Some(InfoHash::from(
-                        utils::urlencoded_to_bytes(value).or(Err(InvalidInfoHash))?,
-                    ))
+                    // Clients announce v2 torrents with the SHA-256 info hash
+                    // truncated to 20 bytes, but accept the full info hash too.
+                    if let Ok(bytes) = utils::urlencoded_to_bytes(value) {
+                        info_hash = Some(InfoHash::from(bytes));
+                    } else {
+                        let info_hash_v2 = InfoHashV2::from(
+                            utils::urlencoded_to_bytes(value).or(Err(InvalidInfoHash))?,
+                        );
+
+                        info_hash = Some(info_hash_v2.truncate());
+                        info_hash_version = Some(InfoHashVersion::V2);
+                    }
                 }
                 "peer_id" => {
                     peer_id = Some(PeerId::from(
@@ -155,10 +189,15 @@ where
                 "downloaded" => downloaded = Some(value.parse().or(Err(InvalidDownloaded))?),
                 "left" => left = Some(value.parse().or(Err(InvalidLeft))?),
                 "compact" => {
-                    if value != "1" {
-                        return Err(InvalidCompact);
+                    compact = match value {
+                        "1" => Some(true),
+                        "0" => Some(false),
+                        _ => return Err(InvalidCompact),
                     }
                 }
+                "no_peer_id" => no_peer_id = Some(value == "1"),
+                "ipv4" => ipv4 = utils::urlencoded_to_ip(value).ok().filter(IpAddr::is_ipv4),
+                "ipv6" => ipv6 = utils::urlencoded_to_ip(value).ok().filter(IpAddr::is_ipv6),
                 "event" => event = Some(value.parse()?),
                 "numwant" => numwant = Some(value.parse().or(Err(InvalidNumwant))?),
                 "corrupt" => corrupt = value.parse().ok(),
@@ -179,8 +218,15 @@ where
 
         let config = state.config.load();
 
+        let compact = compact.unwrap_or(true);
+
+        if !compact && !config.is_non_compact_peer_list_enabled {
+            return Err(InvalidCompact);
+        }
+
         Ok(Query(Announce {
             info_hash: info_hash.ok_or(MissingInfoHash)?,
+            info_hash_version,
             peer_id: peer_id.ok_or(MissingPeerId)?,
             port: port.ok_or(MissingPort)?,
             uploaded: uploaded.ok_or(MissingUploaded)?,
@@ -198,6 +244,11 @@ where
             },
             corrupt,
             key,
+            compact,
+            no_peer_id: no_peer_id.unwrap_or(false),
+            ipv4,
+            ipv6,
+            is_webrtc: false,
         }))
     }
 }
@@ -253,10 +304,31 @@ impl FromRequestParts<Arc<AppState>> for ClientIp {
 pub async fn announce(
     State(state): State<Arc<AppState>>,
     Path(passkey): Path<String>,
-    Query(queries): Query<Announce>,
+    queries: Result<Query<Announce>, AnnounceError>,
     headers: HeaderMap,
     ClientIp(client_ip): ClientIp,
 ) -> Result<Vec<u8>, AnnounceError> {
+    let start = Instant::now();
+    let result = handle(&state, &passkey, queries, &headers, client_ip).await;
+
+    state
+        .metrics
+        .record_announce(start.elapsed(), result.as_ref().err());
+
+    result
+}
+
+/// Validates the query parameters and headers of an HTTP announce before
+/// processing it.
+async fn handle(
+    state: &Arc<AppState>,
+    passkey: &str,
+    queries: Result<Query<Announce>, AnnounceError>,
+    headers: &HeaderMap,
+    client_ip: IpAddr,
+) -> Result<Vec<u8>, AnnounceError> {
+    let Query(queries) = queries?;
+
     // Validate headers
     if headers.contains_key(ACCEPT_LANGUAGE)
         || headers.contains_key(REFERER)
@@ -282,38 +354,55 @@ pub async fn announce(
         return Err(UserAgentTooLong);
     }
 
-    // Block user agent strings on the blacklist
+    Ok(
+        process(state, passkey, queries, Some(user_agent), client_ip)
+            .await?
+            .into_bencode(),
+    )
+}
+
+/// Bittorrent client agnostic part of an announce. Validates the announce
+/// against the in-memory stores, updates the swarm, queues the database
+/// updates and selects the peers to return to the client. The user agent is
+/// `None` for transports that don't send one, such as UDP and websockets.
+pub async fn process(
+    state: &Arc<AppState>,
+    passkey: &str,
+    queries: Announce,
+    user_agent: Option<&str>,
+    client_ip: IpAddr,
+) -> Result<AnnounceResponse, AnnounceError> {
+    // Block clients announcing from banned networks
+    if state.stores.banned_ip_ranges.read().contains(client_ip) {
+        return Err(BannedIpAddress);
+    }
+
+    // Block clients refused by the client rules
+    state.stores.client_rules.read().check(
+        user_agent,
+        &queries.peer_id.0,
+        state.config.load().is_client_allow_list_enabled,
+    )?;
+
+    // Block peer ids on the blacklist
     for client in state.stores.agent_blacklist.read().iter() {
         if queries.peer_id.starts_with(&client.peer_id_prefix) {
             return Err(BlacklistedClient);
         }
     }
 
-    // Block user agent strings on the regex blacklist
-    let user_agent_lower = user_agent.to_ascii_lowercase();
-
-    if user_agent_lower.contains("mozilla")
-        || user_agent_lower.contains("browser")
-        || user_agent_lower.contains("chrome")
-        || user_agent_lower.contains("safari")
-        || user_agent_lower.contains("applewebkit")
-        || user_agent_lower.contains("opera")
-        || user_agent_lower.contains("links")
-        || user_agent_lower.contains("lynx")
-        || user_agent_lower.contains("bot")
-        || user_agent_lower.contains("unknown")
-    {
-        return Err(NotAClient);
-    }
-
     // Validate port
     // Some clients send port 0 on the stopped event
-    if state.stores.port_blacklist.read().contains(&queries.port) && queries.event != Event::Stopped
-    {
-        return Err(BlacklistedPort(queries.port));
+    // WebRTC peers don't listen on a port
+    if queries.event != Event::Stopped && !queries.is_webrtc {
+        let port_blacklist = state.stores.port_blacklist.read();
+
+        if port_blacklist.contains(queries.port) {
+            return Err(BlacklistedPort(queries.port, port_blacklist.describe()));
+        }
     }
 
-    let passkey: Passkey = Passkey::from_str(&passkey).or(Err(InvalidPasskey))?;
+    let passkey: Passkey = Passkey::from_str(passkey).or(Err(InvalidPasskey))?;
 
     // Validate passkey
     let user_id = state
@@ -337,7 +426,7 @@ pub async fn announce(
     };
 
     // Validate torrent
-    let torrent_id_res = state
+    let mapping_res = state
         .stores
         .infohash2id
         .read()
@@ -348,13 +437,14 @@ pub async fn announce(
     let now = Utc::now();
 
     if let Ok(user) = &user {
-        if let Err(InfoHashNotFound) = torrent_id_res {
+        if let Err(InfoHashNotFound) = mapping_res {
             state.queues.unregistered_info_hashes.lock().upsert(
                 unregistered_info_hash_update::Index {
                     user_id: user.id,
                     info_hash: queries.info_hash,
                 },
                 UnregisteredInfoHashUpdate {
+                    info_hash_version: queries.info_hash_version,
                     created_at: now,
                     updated_at: now,
                 },
@@ -362,9 +452,59 @@ pub async fn announce(
         }
     }
 
-    let torrent_id = torrent_id_res?;
+    let mapping = mapping_res?;
+    let torrent_id = mapping.torrent_id;
+
+    // Flag or block clients whose peer id and user agent identify different
+    // clients. Only transports without a user agent skip the check, since
+    // an empty user agent doesn't identify the peer id's client either.
+    let client_fingerprint_mode = state.config.load().client_fingerprint_mode;
+
+    if client_fingerprint_mode != fingerprint::Mode::Off
+        && let Some(user_agent) = user_agent
+        && let Ok(user) = &user
+        && fingerprint::mismatch(&queries.peer_id.0, user_agent).is_some()
+    {
+        state.queues.client_mismatches.lock().upsert(
+            client_mismatch_update::Index {
+                user_id: user.id,
+                torrent_id,
+                peer_id: queries.peer_id,
+            },
+            ClientMismatchUpdate {
+                user_agent: user_agent.to_string(),
+                created_at: now,
+                updated_at: now,
+            },
+        );
 
-    let is_connectable = check_connectivity(&state, client_ip, queries.port).await;
+        if client_fingerprint_mode == fingerprint::Mode::Reject {
+            return Err(ClientMismatch);
+        }
+    }
+
+    // WebRTC peers can only be connected to through the offers relayed by the
+    // websocket tracker. Other peers are answered with their last known
+    // connectivity, and are probed in the background if it's outdated.
+    let socket = SocketAddr::from((client_ip, queries.port));
+    let connectivity = if queries.is_webrtc {
+        Connectivity {
+            is_connectable: true,
+            is_outdated: false,
+        }
+    } else {
+        connectivity::lookup(state, socket)
+    };
+    let is_connectable = connectivity.is_connectable;
+
+    // Only accept a publicly routable address of the ip family the client
+    // didn't connect with, so that clients can't announce other hosts in
+    // their place or make peers connect into private networks.
+    let alternate_ip_address = match client_ip.to_canonical() {
+        IpAddr::V4(_) => queries.ipv6,
+        IpAddr::V6(_) => queries.ipv4,
+    }
+    .filter(|&ip| utils::is_global(ip));
 
     let mut warnings = WarningCollection::new();
 
@@ -374,11 +514,16 @@ pub async fn announce(
         warnings.add(AnnounceWarning::ConnectivityIssueDetected);
     }
 
+    if state.queues.is_full() {
+        warnings.add(AnnounceWarning::TrackerOverloaded);
+    }
+
     let (
         upload_factor,
         download_factor,
         uploaded_delta,
         downloaded_delta,
+        upload_anomaly,
         seeder_delta,
         leecher_delta,
         times_completed_delta,
@@ -391,7 +536,7 @@ pub async fn announce(
         has_requested_leech_list,
         response,
     ) = {
-        let mut torrent_guard = state.stores.torrents.lock();
+        let mut torrent_guard = state.stores.torrents.lock(torrent_id);
         let torrent = torrent_guard.get_mut(&torrent_id).ok_or(TorrentNotFound)?;
 
         if torrent.is_deleted {
@@ -402,6 +547,7 @@ pub async fn announce(
                         info_hash: queries.info_hash,
                     },
                     UnregisteredInfoHashUpdate {
+                        info_hash_version: Some(mapping.version),
                         created_at: now,
                         updated_at: now,
                     },
@@ -458,6 +604,7 @@ pub async fn announce(
         let times_completed_delta;
         let is_visible;
         let mut is_active_after_stop = false;
+        let mut previous_announced_at = None;
 
         if queries.event == Event::Stopped {
             // Try and remove the peer
@@ -471,6 +618,7 @@ pub async fn announce(
                 // announce
                 uploaded_delta = queries.uploaded.saturating_sub(peer.uploaded);
                 downloaded_delta = queries.downloaded.saturating_sub(peer.downloaded);
+                previous_announced_at = Some(peer.updated_at);
 
                 leecher_delta = 0 - peer.is_included_in_leech_list(&config) as i32;
                 seeder_delta = 0 - peer.is_included_in_seed_list(&config) as i32;
@@ -504,6 +652,7 @@ pub async fn announce(
                     old_peer = Some(*peer);
 
                     peer.ip_address = client_ip;
+                    peer.alternate_ip_address = alternate_ip_address;
                     peer.port = queries.port;
                     peer.is_seeder = queries.left == 0;
                     peer.is_connectable = is_connectable;
@@ -518,6 +667,7 @@ pub async fn announce(
                 })
                 .or_insert(store::peer::Peer {
                     ip_address: client_ip,
+                    alternate_ip_address,
                     port: queries.port,
                     is_seeder: queries.left == 0,
                     is_active: true,
@@ -527,6 +677,7 @@ pub async fn announce(
                     updated_at: now,
                     uploaded: queries.uploaded,
                     downloaded: queries.downloaded,
+                    is_webrtc: queries.is_webrtc,
                 });
 
             is_visible = new_peer.is_visible;
@@ -561,6 +712,8 @@ pub async fn announce(
                         downloaded_delta = queries.downloaded - old_peer.downloaded;
                     }
 
+                    previous_announced_at = Some(old_peer.updated_at);
+
                     // Warn user if peer last announced less than
                     // announce_min_enforced seconds ago and it's
                     // not their first completed event
@@ -608,6 +761,17 @@ pub async fn announce(
             }
         }
 
+        // Flag uploads that are implausible given the time since the previous
+        // announce and the other peers in the swarm
+        let upload_anomaly = previous_announced_at.and_then(|previous_announced_at| {
+            upload_anomaly::detect(
+                &config.upload_anomaly_thresholds,
+                uploaded_delta,
+                now - previous_announced_at,
+                || Swarm::of_other_users(&torrent.peers, user_id, previous_announced_at),
+            )
+        });
+
         // Has to be adjusted before the peer list is generated
         torrent.seeders = torrent.seeders.saturating_add_signed(seeder_delta);
         torrent.leechers = torrent.leechers.saturating_add_signed(leecher_delta);
@@ -619,6 +783,7 @@ pub async fn announce(
 
         let mut peers_ipv4: Vec<u8> = Vec::new();
         let mut peers_ipv6: Vec<u8> = Vec::new();
+        let mut peers_dictionary: Option<Vec<DictionaryPeer>> = None;
 
         let mut has_requested_seed_list = false;
         let mut has_requested_leech_list = false;
@@ -629,15 +794,18 @@ pub async fn announce(
         // - it is not a stopped event,
         // - there exist leechers (we have to remember to update the torrent leecher count before this check)
         // - there is no warning in the response
-        if queries.event != Event::Stopped && torrent.leechers > 0 && warnings.is_empty() {
+        if queries.event != Event::Stopped && torrent.leechers > 0 && !warnings.withholds_peers() {
             let mut peers: Vec<(&peer::Index, &Peer)> = Vec::with_capacity(std::cmp::min(
                 queries.numwant,
                 torrent.seeders as usize + torrent.leechers as usize,
             ));
 
             // Don't return peers with the same user id or those that are marked as inactive
+            // WebRTC peers and bittorrent peers can't connect to each other
             let valid_peers = torrent.peers.iter().filter(|(index, peer)| {
-                index.user_id != user_id && peer.is_included_in_peer_list(&config)
+                index.user_id != user_id
+                    && peer.is_included_in_peer_list(&config)
+                    && peer.is_webrtc == queries.is_webrtc
             });
 
             // Make sure leech peer lists are filled with seeds
@@ -674,82 +842,71 @@ pub async fn announce(
                 }
             }
 
-            // Split peers into ipv4 and ipv6 variants and serialize their socket
-            // to bytes according to the bittorrent spec
-            for (_, peer) in peers.iter() {
-                match peer.ip_address {
-                    IpAddr::V4(ip) => {
-                        peers_ipv4.extend(&ip.octets());
-                        peers_ipv4.extend(&peer.port.to_be_bytes());
-                    }
-                    IpAddr::V6(ip) => {
-                        peers_ipv6.extend(&ip.octets());
-                        peers_ipv6.extend(&peer.port.to_be_bytes());
+            if queries.compact {
+                // Split peers into ipv4 and ipv6 variants and serialize their socket
+                // to bytes according to the bittorrent spec. Dual-stack peers are
+                // included in both lists.
+                for (_, peer) in peers.iter() {
+                    for ip_address in
+                        std::iter::once(peer.ip_address).chain(peer.alternate_ip_address)
+                    {
+                        match ip_address {
+                            IpAddr::V4(ip) => {
+                                peers_ipv4.extend(&ip.octets());
+                                peers_ipv4.extend(&peer.port.to_be_bytes());
+                            }
+                            IpAddr::V6(ip) => {
+                                peers_ipv6.extend(&ip.octets());
+                                peers_ipv6.extend(&peer.port.to_be_bytes());
+                            }
+                        }
                     }
                 }
+            } else {
+                // Dual-stack peers are included once per ip address.
+                peers_dictionary = Some(
+                    peers
+                        .iter()
+                        .flat_map(|(index, peer)| {
+                            std::iter::once(peer.ip_address)
+                                .chain(peer.alternate_ip_address)
+                                .map(|ip_address| DictionaryPeer {
+                                    ip_address,
+                                    port: peer.port,
+                                    peer_id: (!queries.no_peer_id).then_some(index.peer_id),
+                                })
+                        })
+                        .collect(),
+                );
             }
         }
 
-        // Generate bencoded response to return to client
-
-        let interval = rng().random_range(config.announce_min..=config.announce_max);
-
-        // Write out bencoded response (keys must be sorted to be within spec)
-        let mut response: Vec<u8> = Vec::with_capacity(
-            82 // literal characters
-                + 5 * 5 // numbers with estimated digit quantity for each
-                + peers_ipv4.len() * 6 + 5 // bytes per ipv4 plus estimated length prefix
-                + peers_ipv6.len() * 18 + 5 // bytes per ipv6 plus estimated length prefix
-                + warnings.max_byte_length(), // max bytes per warning message plus separator
-        );
-
-        response.extend(b"d8:completei");
-
-        if is_over_seed_list_rate_limit || !warnings.is_empty() {
-            response.extend(b"0")
-        } else {
-            response.extend(torrent.seeders.to_string().as_bytes());
-        }
-
-        response.extend(b"e10:downloadedi");
-        response.extend(torrent.times_completed.to_string().as_bytes());
-        response.extend(b"e10:incompletei");
-
-        if is_over_leech_list_rate_limit || !warnings.is_empty() {
-            response.extend(b"0");
-        } else {
-            response.extend(torrent.leechers.to_string().as_bytes());
-        }
-
-        response.extend(b"e8:intervali");
-        response.extend(interval.to_string().as_bytes());
-        response.extend(b"e12:min intervali");
-        response.extend(config.announce_min.to_string().as_bytes());
-        response.extend(b"e5:peers");
-
-        if peers_ipv4.is_empty() {
-            response.extend(b"0:")
-        } else {
-            response.extend(peers_ipv4.len().to_string().as_bytes());
-            response.extend(b":");
-            response.extend(&peers_ipv4);
-        }
-
-        if !peers_ipv6.is_empty() {
-            response.extend(b"6:peers6");
-            response.extend(peers_ipv6.len().to_string().as_bytes());
-            response.extend(b":");
-            response.extend(peers_ipv6);
-        }
-
-        if let Some(warning_message) = warnings.into_message() {
-            response.extend(b"15:warning message");
-            response.extend(warning_message.len().to_string().as_bytes());
-            response.extend(b":");
-            response.extend(warning_message);
+        for &warning in warnings.iter() {
+            state.metrics.record_warning(warning);
         }
 
-        response.extend(b"e");
+        let response = AnnounceResponse {
+            complete: if is_over_seed_list_rate_limit || warnings.withholds_peers() {
+                0
+            } else {
+                torrent.seeders
+            },
+            downloaded: torrent.times_completed,
+            external_ip: config
+                .is_external_ip_enabled
+                .then_some(client_ip.to_canonical()),
+            incomplete: if is_over_leech_list_rate_limit || warnings.withholds_peers() {
+                0
+            } else {
+                torrent.leechers
+            },
+            interval: rng().random_range(config.announce_min..=config.announce_max),
+            min_interval: config.announce_min,
+            peers_ipv4,
+            peers_ipv6,
+            peers_dictionary,
+            warning_message: warnings.into_message(),
+        };
 
         let mut upload_factor = std::cmp::max(
             config.upload_factor,
@@ -796,6 +953,7 @@ pub async fn announce(
             download_factor,
             uploaded_delta,
             downloaded_delta,
+            upload_anomaly,
             seeder_delta,
             leecher_delta,
             times_completed_delta,
@@ -845,9 +1003,36 @@ pub async fn announce(
         upload_factor
     };
 
-    let credited_uploaded_delta = upload_factor as u64 * uploaded_delta / 100;
+    let credited_uploaded_delta = if upload_anomaly
+        .is_some_and(|upload_anomaly| upload_anomaly.exceeded_max_upload_speed)
+        && config.withhold_anomalous_upload_credit
+    {
+        0
+    } else {
+        upload_factor as u64 * uploaded_delta / 100
+    };
     let credited_downloaded_delta = download_factor as u64 * downloaded_delta / 100;
 
+    if let Some(upload_anomaly) = upload_anomaly {
+        state.queues.upload_anomalies.lock().upsert(
+            upload_anomaly_update::Index {
+                user_id,
+                torrent_id,
+                peer_id: queries.peer_id,
+            },
+            UploadAnomalyUpdate {
+                uploaded: uploaded_delta,
+                max_upload_speed: upload_anomaly.upload_speed,
+                flagged_announces: 1,
+                exceeded_max_upload_speed: upload_anomaly.exceeded_max_upload_speed,
+                uploaded_without_leechers: upload_anomaly.uploaded_without_leechers,
+                exceeded_swarm_download: upload_anomaly.exceeded_swarm_download,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+    }
+
     let completed_at = if queries.event == Event::Completed {
         Some(now)
     } else {
@@ -878,6 +1063,31 @@ pub async fn announce(
             });
     }
 
+    // Has to be queued after the peer is inserted, so that the probe finds
+    // the peer to update
+    if connectivity.is_outdated && queries.event != Event::Stopped {
+        state.connectivity_checker.enqueue(
+            state,
+            socket,
+            queries.info_hash,
+            (
+                torrent_id,
+                peer::Index {
+                    user_id,
+                    peer_id: queries.peer_id,
+                },
+            ),
+        );
+    }
+
+    // Browser user agents are too long to be stored, so a placeholder is
+    // recorded for WebRTC peers instead
+    let user_agent = user_agent.unwrap_or(if queries.is_webrtc {
+        websocket::USER_AGENT
+    } else {
+        ""
+    });
+
     state.queues.peers.lock().upsert(
         peer_update::Index {
             peer_id: queries.peer_id,
@@ -897,6 +1107,7 @@ pub async fn announce(
             created_at: now,
             updated_at: now,
             connectable: is_connectable,
+            is_webrtc: queries.is_webrtc,
         },
     );
 
@@ -980,49 +1191,192 @@ pub async fn announce(
     Ok(response)
 }
 
-async fn check_connectivity(state: &Arc<AppState>, ip: IpAddr, port: u16) -> bool {
-    if state.config.load().is_connectivity_check_enabled {
-        let now = Utc::now();
-        let socket = SocketAddr::from((ip, port));
-        let connectable_port_opt = state.stores.connectable_ports.read().get(&socket).cloned();
+/// Transport agnostic announce response.
+pub struct AnnounceResponse {
+    /// Amount of seeders in the swarm.
+    pub complete: u32,
+    /// Amount of times the torrent has been completed.
+    pub downloaded: u32,
+    /// Public address of the client as seen by the tracker (BEP 24).
+    pub external_ip: Option<IpAddr>,
+    /// Amount of leechers in the swarm.
+    pub incomplete: u32,
+    /// Amount of seconds the client should wait before its next announce.
+    pub interval: u32,
+    /// Minimum amount of seconds the client must wait before its next
+    /// announce.
+    pub min_interval: u32,
+    /// Compact ipv4 peer list (4 byte ip followed by 2 byte port per peer).
+    pub peers_ipv4: Vec<u8>,
+    /// Compact ipv6 peer list (16 byte ip followed by 2 byte port per peer).
+    pub peers_ipv6: Vec<u8>,
+    /// Peer list sent in place of the compact peer lists to clients that
+    /// requested a non-compact response.
+    pub peers_dictionary: Option<Vec<DictionaryPeer>>,
+    /// Combined announce warnings that should be shown to the user.
+    pub warning_message: Option<Vec<u8>>,
+}
 
-        if let Some(connectable_port) = connectable_port_opt {
-            let ttl = Duration::seconds(state.config.load().connectivity_check_interval);
+/// Peer of a non-compact peer list (BEP 3).
+pub struct DictionaryPeer {
+    pub ip_address: IpAddr,
+    pub port: u16,
+    /// Omitted if the client requested `no_peer_id`.
+    pub peer_id: Option<PeerId>,
+}
 
-            if let Some(cached_until) = connectable_port.updated_at.checked_add_signed(ttl) {
-                if cached_until > now {
-                    return connectable_port.connectable;
+impl AnnounceResponse {
+    /// Generate bencoded response to return to client
+    pub fn into_bencode(self) -> Vec<u8> {
+        let mut response: Vec<u8> = Vec::with_capacity(
+            82 // literal characters
+                + 5 * 5 // numbers with estimated digit quantity for each
+                + self.peers_ipv4.len() + 5 // ipv4 peers plus estimated length prefix
+                + self.peers_ipv6.len() + 5 // ipv6 peers plus estimated length prefix
+                + self.peers_dictionary.as_ref().map_or(0, |peers| peers.len() * 97) // estimated length of each peer
+                + self.external_ip.map_or(0, |_| 33) // key and ip with length prefix
+                + self.warning_message.as_ref().map_or(0, |message| message.len() + 5),
+        );
+
+        bencode::dictionary(&mut response, |response| {
+            response
+                .integer(b"complete", self.complete)
+                .integer(b"downloaded", self.downloaded);
+
+            match self.external_ip {
+                Some(IpAddr::V4(ip)) => response.bytes(b"external ip", &ip.octets()),
+                Some(IpAddr::V6(ip)) => response.bytes(b"external ip", &ip.octets()),
+                None => response,
+            };
+
+            response
+                .integer(b"incomplete", self.incomplete)
+                .integer(b"interval", self.interval)
+                .integer(b"min interval", self.min_interval);
+
+            if let Some(peers) = &self.peers_dictionary {
+                response.list(b"peers", |list| {
+                    for peer in peers {
+                        list.dictionary(|dictionary| {
+                            dictionary.bytes(b"ip", peer.ip_address.to_string().as_bytes());
+
+                            if let Some(peer_id) = peer.peer_id {
+                                dictionary.bytes(b"peer id", peer_id.as_slice());
+                            }
+
+                            dictionary.integer(b"port", peer.port);
+                        });
+                    }
+                });
+            } else {
+                response.bytes(b"peers", &self.peers_ipv4);
+
+                if !self.peers_ipv6.is_empty() {
+                    response.bytes(b"peers6", &self.peers_ipv6);
                 }
             }
-        }
 
-        let connectable = tokio::spawn(async move {
-            tokio::time::timeout(
-                std::time::Duration::from_millis(500),
-                TcpStream::connect(socket),
-            )
-            .await
-            .is_ok_and(|connection_result| connection_result.is_ok())
-        })
-        .await
-        .unwrap_or(false);
+            if let Some(warning_message) = &self.warning_message {
+                response.bytes(b"warning message", warning_message);
+            }
+        });
 
-        state
-            .stores
-            .connectable_ports
-            .write()
-            .entry(socket)
-            .and_modify(|connectable_port| {
-                connectable_port.connectable = connectable;
-                connectable_port.updated_at = now;
-            })
-            .or_insert(ConnectablePort {
-                connectable,
-                updated_at: now,
-            });
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_response() {
+        let response = AnnounceResponse {
+            complete: 1,
+            downloaded: 2,
+            external_ip: Some(IpAddr::from([192, 0, 2, 1])),
+            incomplete: 3,
+            interval: 4,
+            min_interval: 5,
+            peers_ipv4: vec![192, 0, 2, 2, 0x1A, 0xE1],
+            peers_ipv6: vec![
+                0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1A, 0xE1,
+            ],
+            peers_dictionary: None,
+            warning_message: Some(b"warning".to_vec()),
+        };
+
+        assert_eq!(
+            response.into_bencode(),
+            [
+                b"d8:completei1e10:downloadedi2e11:external ip4:\xC0\x00\x02\x01".as_slice(),
+                b"10:incompletei3e8:intervali4e12:min intervali5e",
+                b"5:peers6:\xC0\x00\x02\x02\x1A\xE1",
+                b"6:peers618:\x20\x01\x0D\xB8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1A\xE1",
+                b"15:warning message7:warninge",
+            ]
+            .concat()
+        );
+    }
 
-        return connectable;
+    #[test]
+    fn empty_compact_response() {
+        let response = AnnounceResponse {
+            complete: 0,
+            downloaded: 0,
+            external_ip: None,
+            incomplete: 0,
+            interval: 4,
+            min_interval: 5,
+            peers_ipv4: Vec::new(),
+            peers_ipv6: Vec::new(),
+            peers_dictionary: None,
+            warning_message: None,
+        };
+
+        assert_eq!(
+            response.into_bencode(),
+            b"d8:completei0e10:downloadedi0e10:incompletei0e8:intervali4e12:min intervali5e5:peers0:e"
+        );
     }
 
-    false
+    #[test]
+    fn non_compact_response() {
+        let response = AnnounceResponse {
+            complete: 1,
+            downloaded: 2,
+            external_ip: Some(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1])),
+            incomplete: 3,
+            interval: 4,
+            min_interval: 5,
+            peers_ipv4: Vec::new(),
+            peers_ipv6: Vec::new(),
+            peers_dictionary: Some(vec![
+                DictionaryPeer {
+                    ip_address: IpAddr::from([192, 0, 2, 2]),
+                    port: 6881,
+                    peer_id: Some(PeerId(*b"-UT0001-000000000000")),
+                },
+                DictionaryPeer {
+                    ip_address: IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 2]),
+                    port: 6882,
+                    peer_id: None,
+                },
+            ]),
+            warning_message: Some(b"warning".to_vec()),
+        };
+
+        assert_eq!(
+            response.into_bencode(),
+            [
+                b"d8:completei1e10:downloadedi2e".as_slice(),
+                b"11:external ip16:\x20\x01\x0D\xB8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01",
+                b"10:incompletei3e8:intervali4e12:min intervali5e5:peersl",
+                b"d2:ip9:192.0.2.27:peer id20:-UT0001-0000000000004:porti6881ee",
+                b"d2:ip11:2001:db8::24:porti6882ee",
+                b"e15:warning message7:warninge",
+            ]
+            .concat()
+        );
+    }
 }
@@ -1,4 +1,8 @@
+pub mod banned_ip_range;
 pub mod blacklisted_agent;
+pub mod blacklisted_port;
+pub mod client_rule;
+pub mod connectable_port;
 pub mod featured_torrent;
 pub mod freeleech_token;
 pub mod group;
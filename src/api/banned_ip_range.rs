@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+use ipnet::IpNet;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::state::AppState;
+
+#[derive(Clone, Deserialize)]
+pub struct APIBannedIpRange {
+    /// Range in CIDR notation, such as `192.0.2.0/24` or `2001:db8::/32`.
+    pub ip_range: IpNet,
+}
+
+pub async fn upsert(
+    State(state): State<Arc<AppState>>,
+    Json(banned_ip_range): Json<APIBannedIpRange>,
+) {
+    info!("Inserting banned ip range {}.", banned_ip_range.ip_range);
+
+    state
+        .stores
+        .banned_ip_ranges
+        .write()
+        .insert(banned_ip_range.ip_range);
+}
+
+pub async fn destroy(
+    State(state): State<Arc<AppState>>,
+    Json(banned_ip_range): Json<APIBannedIpRange>,
+) -> StatusCode {
+    if state
+        .stores
+        .banned_ip_ranges
+        .write()
+        .remove(&banned_ip_range.ip_range)
+    {
+        info!("Removing banned ip range {}.", banned_ip_range.ip_range);
+
+        return StatusCode::OK;
+    }
+
+    StatusCode::NOT_FOUND
+}
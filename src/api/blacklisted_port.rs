@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+use tracing::info;
+
+use crate::{state::AppState, store::blacklisted_port::PortRange};
+
+pub async fn upsert(
+    State(state): State<Arc<AppState>>,
+    Json(port_range): Json<PortRange>,
+) -> StatusCode {
+    if !port_range.is_valid() {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!("Inserting blacklisted port range {port_range}.");
+
+    state.stores.port_blacklist.write().insert(port_range);
+
+    StatusCode::OK
+}
+
+pub async fn destroy(
+    State(state): State<Arc<AppState>>,
+    Json(port_range): Json<PortRange>,
+) -> StatusCode {
+    if state
+        .stores
+        .port_blacklist
+        .write()
+        .shift_remove(&port_range)
+    {
+        info!("Removing blacklisted port range {port_range}.");
+
+        return StatusCode::OK;
+    }
+
+    StatusCode::NOT_FOUND
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    state::AppState,
+    store::client_rule::{ClientRule, DBImportClientRule},
+};
+
+pub async fn index(State(state): State<Arc<AppState>>) -> Json<IndexMap<u32, ClientRule>> {
+    Json(state.stores.client_rules.read().clone())
+}
+
+pub async fn upsert(
+    State(state): State<Arc<AppState>>,
+    Json(rule): Json<DBImportClientRule>,
+) -> StatusCode {
+    let id = rule.id;
+
+    match ClientRule::new(rule) {
+        Ok(rule) => {
+            info!("Inserting client rule with id {id}.");
+
+            state.stores.client_rules.write().insert(id, rule);
+
+            StatusCode::OK
+        }
+        Err(e) => {
+            error!("Ignoring client rule with id {id}: {e:#}");
+
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct APIRemoveClientRule {
+    pub id: u32,
+}
+
+pub async fn destroy(
+    State(state): State<Arc<AppState>>,
+    Json(rule): Json<APIRemoveClientRule>,
+) -> StatusCode {
+    if state
+        .stores
+        .client_rules
+        .write()
+        .shift_remove(&rule.id)
+        .is_some()
+    {
+        info!("Removing client rule with id {}.", rule.id);
+
+        return StatusCode::OK;
+    }
+
+    StatusCode::BAD_REQUEST
+}
@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use tracing::info;
+
+use crate::state::AppState;
+use crate::store::connectable_port::ConnectablePort;
+
+/// Shows the cached connectivity of a socket, such as `192.0.2.1:6881` or
+/// `[2001:db8::1]:6881`.
+pub async fn show(
+    State(state): State<Arc<AppState>>,
+    Path(socket): Path<String>,
+) -> Result<Json<ConnectablePort>, StatusCode> {
+    let socket: SocketAddr = socket.parse().or(Err(StatusCode::BAD_REQUEST))?;
+
+    state
+        .stores
+        .connectable_ports
+        .read()
+        .get(&socket)
+        .map(|connectable_port| Json(*connectable_port))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Removes the cached connectivity of a socket, so that it's checked again on
+/// the next announce from it.
+pub async fn destroy(State(state): State<Arc<AppState>>, Path(socket): Path<String>) -> StatusCode {
+    let Ok(socket) = socket.parse::<SocketAddr>() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    if state
+        .stores
+        .connectable_ports
+        .write()
+        .swap_remove(&socket)
+        .is_some()
+    {
+        info!("Removing cached connectivity of socket {socket}.");
+
+        return StatusCode::OK;
+    }
+
+    StatusCode::NOT_FOUND
+}
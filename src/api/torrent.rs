@@ -8,15 +8,20 @@ use tracing::info;
 
 use anyhow::Result;
 
-use crate::model::{info_hash::InfoHash, torrent_status::TorrentStatus};
+use crate::model::{
+    info_hash::InfoHash, info_hash_v2::InfoHashV2, info_hash_version::InfoHashVersion,
+    torrent_status::TorrentStatus,
+};
 use crate::state::AppState;
-use crate::store::torrent::Torrent;
+use crate::store::{infohash2id::Mapping, torrent::Torrent};
 
 #[derive(Clone, Deserialize)]
 pub struct APIInsertTorrent {
     pub id: u32,
     pub status: TorrentStatus,
     pub info_hash: String,
+    #[serde(default)]
+    pub info_hash_v2: Option<String>,
     pub is_deleted: bool,
     pub seeders: u32,
     pub leechers: u32,
@@ -29,12 +34,19 @@ pub async fn upsert(
     State(state): State<Arc<AppState>>,
     Json(torrent): Json<APIInsertTorrent>,
 ) -> StatusCode {
+    let info_hash_v2 = match torrent.info_hash_v2.as_deref().map(InfoHashV2::from_str) {
+        Some(Ok(info_hash_v2)) => Some(info_hash_v2),
+        Some(Err(_)) => return StatusCode::BAD_REQUEST,
+        None => None,
+    };
+
     if let Ok(info_hash) = InfoHash::from_str(&torrent.info_hash) {
         info!("Inserting torrent with id {}.", torrent.id);
-        let old_torrent = state.stores.torrents.lock().swap_remove(&torrent.id);
+        let mut torrent_guard = state.stores.torrents.lock(torrent.id);
+        let old_torrent = torrent_guard.swap_remove(&torrent.id);
         let peers = old_torrent.unwrap_or_default().peers;
 
-        state.stores.torrents.lock().insert(
+        torrent_guard.insert(
             torrent.id,
             Torrent {
                 id: torrent.id,
@@ -49,11 +61,22 @@ pub async fn upsert(
             },
         );
 
-        state
-            .stores
-            .infohash2id
-            .write()
-            .insert(info_hash, torrent.id);
+        drop(torrent_guard);
+
+        let mut infohash2id_guard = state.stores.infohash2id.write();
+
+        infohash2id_guard.insert(
+            info_hash,
+            Mapping {
+                torrent_id: torrent.id,
+                version: InfoHashVersion::V1,
+            },
+        );
+
+        infohash2id_guard.set_v2(
+            torrent.id,
+            info_hash_v2.map(|info_hash_v2| info_hash_v2.truncate()),
+        );
 
         return StatusCode::OK;
     }
@@ -70,7 +93,7 @@ pub async fn destroy(
     State(state): State<Arc<AppState>>,
     Json(torrent): Json<APIRemoveTorrent>,
 ) -> StatusCode {
-    let mut torrent_guard = state.stores.torrents.lock();
+    let mut torrent_guard = state.stores.torrents.lock(torrent.id);
 
     if let Some(torrent) = torrent_guard.get_mut(&torrent.id) {
         info!("Removing torrent with id {}.", torrent.id);
@@ -89,7 +112,7 @@ pub async fn show(
     state
         .stores
         .torrents
-        .lock()
+        .lock(id)
         .get(&id)
         .map(|torrent| Json(torrent.clone()))
         .ok_or(StatusCode::NOT_FOUND)
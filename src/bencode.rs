@@ -0,0 +1,144 @@
+use std::{io::Write, ops::Range};
+
+/// Writes a bencoded dictionary into the buffer.
+///
+/// Keys have to be written in ascending order without duplicates, as
+/// required by the bittorrent spec. The order is only checked in debug
+/// builds, and the responses are covered by tests comparing their bytes.
+pub fn dictionary(buffer: &mut Vec<u8>, write_entries: impl FnOnce(&mut Dictionary)) {
+    buffer.push(b'd');
+    write_entries(&mut Dictionary {
+        buffer,
+        last_key: None,
+    });
+    buffer.push(b'e');
+}
+
+/// Writes a bencoded list into the buffer.
+fn list(buffer: &mut Vec<u8>, write_items: impl FnOnce(&mut List)) {
+    buffer.push(b'l');
+    write_items(&mut List { buffer });
+    buffer.push(b'e');
+}
+
+/// Writes a bencoded byte string into the buffer.
+fn bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    // Writing into a vec can't fail
+    let _ = write!(buffer, "{}:", value.len());
+    buffer.extend(value);
+}
+
+/// Writes a bencoded integer into the buffer.
+fn integer(buffer: &mut Vec<u8>, value: i64) {
+    // Writing into a vec can't fail
+    let _ = write!(buffer, "i{value}e");
+}
+
+pub struct Dictionary<'a> {
+    buffer: &'a mut Vec<u8>,
+    /// Position of the previously written key within the buffer.
+    last_key: Option<Range<usize>>,
+}
+
+impl Dictionary<'_> {
+    pub fn bytes(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.key(key);
+        bytes(self.buffer, value);
+
+        self
+    }
+
+    pub fn integer(&mut self, key: &[u8], value: impl Into<i64>) -> &mut Self {
+        self.key(key);
+        integer(self.buffer, value.into());
+
+        self
+    }
+
+    pub fn dictionary(
+        &mut self,
+        key: &[u8],
+        write_entries: impl FnOnce(&mut Dictionary),
+    ) -> &mut Self {
+        self.key(key);
+        dictionary(self.buffer, write_entries);
+
+        self
+    }
+
+    pub fn list(&mut self, key: &[u8], write_items: impl FnOnce(&mut List)) -> &mut Self {
+        self.key(key);
+        list(self.buffer, write_items);
+
+        self
+    }
+
+    fn key(&mut self, key: &[u8]) {
+        if let Some(last_key) = self.last_key.clone() {
+            debug_assert!(
+                self.buffer[last_key] < *key,
+                "Bencoded dictionary keys must be sorted and unique."
+            );
+        }
+
+        bytes(self.buffer, key);
+
+        self.last_key = Some(self.buffer.len() - key.len()..self.buffer.len());
+    }
+}
+
+pub struct List<'a> {
+    buffer: &'a mut Vec<u8>,
+}
+
+impl List<'_> {
+    pub fn dictionary(&mut self, write_entries: impl FnOnce(&mut Dictionary)) -> &mut Self {
+        dictionary(self.buffer, write_entries);
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_values() {
+        let mut buffer = Vec::new();
+
+        dictionary(&mut buffer, |dictionary| {
+            dictionary
+                .dictionary(b"a", |nested| {
+                    nested.integer(b"x", -1);
+                })
+                .list(b"b", |list| {
+                    list.dictionary(|_| ()).dictionary(|nested| {
+                        nested.bytes(b"y", b"");
+                    });
+                })
+                .bytes(b"c", b"spam")
+                .integer(b"d", 0u32);
+        });
+
+        assert_eq!(buffer, b"d1:ad1:xi-1ee1:blded1:y0:ee1:c4:spam1:di0ee");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted and unique")]
+    fn unsorted_keys() {
+        dictionary(&mut Vec::new(), |dictionary| {
+            dictionary.integer(b"peers", 0).integer(b"interval", 0);
+        });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted and unique")]
+    fn duplicate_keys() {
+        dictionary(&mut Vec::new(), |dictionary| {
+            dictionary.integer(b"peers", 0).integer(b"peers", 0);
+        });
+    }
+}
@@ -1,4 +1,10 @@
-use std::{env, net::IpAddr, num::NonZeroU64, path::PathBuf, sync::Arc};
+use std::{
+    env,
+    net::IpAddr,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result, bail, ensure};
 use axum::{
@@ -7,15 +13,28 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use dotenvy::dotenv_override;
+use tokio::time::MissedTickBehavior;
 use tracing::{error, info};
 
-use crate::{rate::RateCollection, state::AppState};
+use crate::{
+    connectivity, fingerprint,
+    queue::{FullPolicy, MaxLengths},
+    rate::RateCollection,
+    state::AppState,
+    upload_anomaly,
+};
 
 #[derive(Clone)]
 pub struct Config {
     /// The interval (in milliseconds) between when history, peers, torrents and
     /// users are flushed to the main mysql database.
     pub flush_interval_milliseconds: u64,
+    /// What scheduled jobs (flushes, peer expiry and snapshots) do after a
+    /// run took longer than their interval. `burst` catches up on the missed
+    /// runs back to back, `delay` restarts the interval from when the run
+    /// finished, and `skip` drops the missed runs and keeps the original
+    /// schedule.
+    pub missed_tick_behavior: MissedTickBehavior,
     /// Amount of concurrent SQL queries per table flush. Use 1 and decrease
     /// the flush interval as necessary unless handling more than 10 000
     /// announces per second.
@@ -24,6 +43,27 @@ pub struct Config {
     /// Can be used to distribute increased load across multiple SQL queries.
     /// If unspecified, uses the max bindings allowed per SQL query.
     pub max_records_per_batch: Option<usize>,
+    /// Max amount of records held by each queue of database updates, so that
+    /// memory doesn't grow without bound while the database is unavailable.
+    /// Once full, updates of queued records are still merged, but updates of
+    /// other records are handled according to `queue_full_policy` and
+    /// announces receive a warning. The oldest records of the announce log are
+    /// dropped instead.
+    pub max_queue_lengths: MaxLengths,
+    /// What happens to updates of records that aren't queued yet once their
+    /// queue is full. Histories and users are always dead-lettered, since
+    /// they carry upload and download credit.
+    pub queue_full_policy: FullPolicy,
+    /// Amount of consecutive failed flushes of a table after which the
+    /// tracker is reported as not ready.
+    pub readiness_max_failed_flushes: u64,
+    /// Amount of updates queued for a table above which the tracker is
+    /// reported as not ready.
+    pub readiness_max_queue_length: usize,
+    /// Path of the file that updates rejected by the database or overflowing
+    /// a full queue are appended to, along with the error they were rejected
+    /// with. Rejected updates are logged instead unless configured.
+    pub dead_letter_path: Option<PathBuf>,
     /// The amount of peers that should be sent back if the peer does not
     /// include a numwant.
     pub numwant_default: usize,
@@ -48,7 +88,8 @@ pub struct Config {
     /// download column. A download_factor of 0 means global freeleech.
     pub download_factor: u8,
     /// Amount of seconds between scheduled batches where peers are marked as
-    /// inactive or erased from memory.
+    /// inactive or erased from memory. Each batch is spread out evenly over the
+    /// interval, a small slice of the torrents at a time.
     pub peer_expiry_interval: u64,
     /// Amount of seconds since the last announce before a peer is considered
     /// inactive.
@@ -59,6 +100,27 @@ pub struct Config {
     /// comes back online and the peer has been erased, then their new stats
     /// will be recorded incorrectly.
     pub inactive_peer_ttl: u64,
+    /// Path of the snapshot file the torrents, users and connectable ports
+    /// are persisted to, so that restarts keep the state that isn't stored
+    /// in the database. Torrents and users are still reloaded from the
+    /// database, keeping only their peers and rate limits from the snapshot.
+    /// Snapshots are disabled unless configured.
+    pub snapshot_path: Option<PathBuf>,
+    /// Amount of seconds between scheduled snapshots. A snapshot is also
+    /// written on graceful shutdown.
+    pub snapshot_interval: u64,
+    /// Max age in seconds of a snapshot for it to be loaded at startup. Older
+    /// snapshots are ignored and the stores are loaded from the database
+    /// instead, since they miss changes made while the tracker was offline.
+    pub snapshot_max_age: u64,
+    /// Directory of the write-ahead logs that queued database updates are
+    /// appended to until they're flushed, so that they're replayed at startup
+    /// instead of lost if the tracker is killed between flushes. The logs are
+    /// synced to disk on every flush. Updates of a flush interrupted by the
+    /// tracker being killed may already be in the database, so they're
+    /// dead-lettered instead of replayed. Announces aren't logged.
+    /// Write-ahead logs are disabled unless configured.
+    pub write_ahead_log_directory: Option<PathBuf>,
     /// Site password used by UNIT3D to send api requests to the tracker.
     /// Must be at least 32 characters long and should be properly randomized.
     pub apikey: String,
@@ -69,6 +131,16 @@ pub struct Config {
     /// Path to unix domain socket to listen from to receive announces from
     /// reverse proxy.
     pub listening_unix_socket: Option<PathBuf>,
+    /// IP address for the tracker to listen from to receive UDP announces
+    /// (BEP 15). The UDP tracker is disabled unless configured.
+    pub udp_listening_ip_address: Option<IpAddr>,
+    /// Port for the tracker to listen from to receive UDP announces
+    /// (BEP 15). The UDP tracker is disabled unless configured.
+    pub udp_listening_port: Option<u16>,
+    /// Max amount of UDP requests handled at the same time. Packets received
+    /// once reached are dropped, and retransmitted by clients. Only read
+    /// when the UDP tracker starts.
+    pub max_concurrent_udp_requests: usize,
     /// Max amount of active peers a user is allowed to have on a torrent.
     /// Prevents abuse from malicious users causing the server to run out of ram,
     /// as well as keeps the peer lists from being filled with too many clients
@@ -80,10 +152,56 @@ pub struct Config {
     /// The minimum number of seconds a socket's connectivity status is cached for
     /// before rechecking the peer's connectivity. Use `-1` for no caching.
     pub connectivity_check_interval: i64,
+    /// How peers are checked for connectivity. `connect` considers peers
+    /// accepting a TCP connection as connectable, and `handshake` only
+    /// considers peers answering a BitTorrent handshake for the torrent they
+    /// announced as connectable, so that other services listening on the
+    /// port aren't mistaken for the client.
+    pub connectivity_check_mode: connectivity::Mode,
+    /// Amount of seconds since a socket's connectivity was last checked
+    /// before it's removed from the cache. Sockets still announced from are
+    /// checked again every `CONNECTIVITY_CHECK_INTERVAL` seconds, so this
+    /// only removes sockets no longer in use.
+    pub connectable_port_ttl: u64,
     /// When enabled, restrict peers to those with open ports. Peers with closed
     /// ports will receive empty peer lists and are not included in other returned
-    /// peer lists. Requires `IS_CONNECTIVITY_CHECK_ENABLED` to be `true`.
+    /// peer lists. Ports that weren't checked yet are treated as open until
+    /// their check completes. Requires `IS_CONNECTIVITY_CHECK_ENABLED` to be
+    /// `true`.
     pub require_peer_connectivity: bool,
+    /// Max amount of connectivity checks run at the same time. Peers are
+    /// checked in the background, and announces are answered with the last
+    /// known connectivity of the peer in the meantime.
+    pub max_concurrent_connectivity_checks: usize,
+    /// Max amount of connectivity checks waiting to be run. Once full, peers
+    /// are checked on a later announce instead.
+    pub max_queued_connectivity_checks: usize,
+    /// When enabled, clients announcing with `compact=0` receive the peer list
+    /// as a list of dictionaries instead of being refused. Only needed for old
+    /// clients that don't support compact peer lists.
+    pub is_non_compact_peer_list_enabled: bool,
+    /// When enabled, only clients matching an `allow` client rule may
+    /// announce. Clients matching a `block` client rule are refused either
+    /// way.
+    pub is_client_allow_list_enabled: bool,
+    /// How announces are handled whose peer id identifies a different client
+    /// than their user agent.
+    pub client_fingerprint_mode: fingerprint::Mode,
+    /// Limits above which the amount uploaded since a peer's previous announce
+    /// is recorded as an upload anomaly for staff review.
+    pub upload_anomaly_thresholds: upload_anomaly::Thresholds,
+    /// When enabled, uploads exceeding the max upload speed aren't credited to
+    /// the user.
+    pub withhold_anomalous_upload_credit: bool,
+    /// When enabled, announce responses include the client's public ip address
+    /// as seen by the tracker (BEP 24), letting clients behind NAT learn their
+    /// external address.
+    pub is_external_ip_enabled: bool,
+    /// When enabled, browser based WebTorrent clients can announce through the
+    /// websocket tracker at `/ws/{passkey}`. WebRTC peers only receive offers
+    /// from other WebRTC peers. Requires the `webrtc` column of the `peers`
+    /// table.
+    pub is_websocket_tracker_enabled: bool,
     /// Enable logging of all successful announces to the `announces` table for
     /// debugging. This will generate significant amounts of data. Do not
     /// enable if you do not know what you are doing.
@@ -133,6 +251,15 @@ impl Config {
             .parse()
             .context("FLUSH_INTERVAL_MILLISECONDS must be a number between 1 and 2^64 - 1")?;
 
+        let missed_tick_behavior = match env::var("MISSED_TICK_BEHAVIOR").ok().as_deref() {
+            Some("burst") => MissedTickBehavior::Burst,
+            Some("delay") => MissedTickBehavior::Delay,
+            Some("skip") | None => MissedTickBehavior::Skip,
+            Some(_) => {
+                bail!("MISSED_TICK_BEHAVIOR must be either `burst`, `delay` or `skip`, if provided")
+            }
+        };
+
         let max_batches_per_flush = env::var("MAX_BATCHES_PER_FLUSH")
             .context("MAX_BATCHES_PER_FLUSH not found in .env file.")?
             .parse()
@@ -146,6 +273,49 @@ impl Config {
                 "MAX_RECORDS_PER_BATCH must be a number between 0 and 2^64 - 1, if provided",
             )?;
 
+        let max_queue_length = |name: &str| -> Result<usize> {
+            env::var(name)
+                .with_context(|| format!("{name} not found in .env file."))?
+                .parse()
+                .with_context(|| format!("{name} must be a number between 0 and 2^64 - 1"))
+        };
+
+        let max_queue_lengths = MaxLengths {
+            announces: max_queue_length("MAX_ANNOUNCE_QUEUE_LENGTH")?,
+            client_mismatches: max_queue_length("MAX_CLIENT_MISMATCH_QUEUE_LENGTH")?,
+            histories: max_queue_length("MAX_HISTORY_QUEUE_LENGTH")?,
+            peers: max_queue_length("MAX_PEER_QUEUE_LENGTH")?,
+            torrents: max_queue_length("MAX_TORRENT_QUEUE_LENGTH")?,
+            unregistered_info_hashes: max_queue_length("MAX_UNREGISTERED_INFO_HASH_QUEUE_LENGTH")?,
+            upload_anomalies: max_queue_length("MAX_UPLOAD_ANOMALY_QUEUE_LENGTH")?,
+            users: max_queue_length("MAX_USER_QUEUE_LENGTH")?,
+        };
+
+        let queue_full_policy = match env::var("QUEUE_FULL_POLICY")
+            .context("QUEUE_FULL_POLICY not found in .env file.")?
+            .as_str()
+        {
+            "drop" => FullPolicy::Drop,
+            "dead_letter" => FullPolicy::DeadLetter,
+            _ => bail!("QUEUE_FULL_POLICY must be either `drop` or `dead_letter`"),
+        };
+
+        let readiness_max_failed_flushes: NonZeroU64 = env::var("READINESS_MAX_FAILED_FLUSHES")
+            .context("READINESS_MAX_FAILED_FLUSHES not found in .env file.")?
+            .parse()
+            .context("READINESS_MAX_FAILED_FLUSHES must be a number between 1 and 2^64 - 1")?;
+
+        let readiness_max_queue_length = env::var("READINESS_MAX_QUEUE_LENGTH")
+            .context("READINESS_MAX_QUEUE_LENGTH not found in .env file.")?
+            .parse()
+            .context("READINESS_MAX_QUEUE_LENGTH must be a number between 0 and 2^64 - 1")?;
+
+        let dead_letter_path = env::var("DEAD_LETTER_PATH")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("DEAD_LETTER_PATH could not be parsed into a file path.")?;
+
         let numwant_default = env::var("NUMWANT_DEFAULT")
             .context("NUMWANT_DEFAULT not found in .env file.")?
             .parse()
@@ -211,6 +381,28 @@ impl Config {
             .parse()
             .context("INACTIVE_PEER_TTL must be a number between 0 and 2^64 - 1")?;
 
+        let snapshot_path = env::var("SNAPSHOT_PATH")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("SNAPSHOT_PATH could not be parsed into a file path.")?;
+
+        let snapshot_interval: NonZeroU64 = env::var("SNAPSHOT_INTERVAL")
+            .context("SNAPSHOT_INTERVAL not found in .env file.")?
+            .parse()
+            .context("SNAPSHOT_INTERVAL must be a number between 1 and 2^64 - 1")?;
+
+        let snapshot_max_age = env::var("SNAPSHOT_MAX_AGE")
+            .context("SNAPSHOT_MAX_AGE not found in .env file.")?
+            .parse()
+            .context("SNAPSHOT_MAX_AGE must be a number between 0 and 2^64 - 1")?;
+
+        let write_ahead_log_directory = env::var("WRITE_AHEAD_LOG_DIRECTORY")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("WRITE_AHEAD_LOG_DIRECTORY could not be parsed into a directory path.")?;
+
         let listening_ip_address = env::var("LISTENING_IP_ADDRESS")
             .ok()
             .map(|s| s.parse())
@@ -244,6 +436,28 @@ impl Config {
             "(LISTENING_IP_ADDRESS and LISTENING_PORT) AND LISTENING_UNIX_SOCKET are mutually exclusive"
         );
 
+        let udp_listening_ip_address = env::var("UDP_LISTENING_IP_ADDRESS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("UDP_LISTENING_IP_ADDRESS in .env file could not be parsed.")?;
+
+        let udp_listening_port = env::var("UDP_LISTENING_PORT")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("UDP_LISTENING_PORT must be a number between 0 and 2^16 - 1")?;
+
+        ensure!(
+            udp_listening_ip_address.is_some() == udp_listening_port.is_some(),
+            "UDP_LISTENING_IP_ADDRESS and UDP_LISTENING_PORT must be configured together."
+        );
+
+        let max_concurrent_udp_requests: NonZeroUsize = env::var("MAX_CONCURRENT_UDP_REQUESTS")
+            .context("MAX_CONCURRENT_UDP_REQUESTS not found in .env file.")?
+            .parse()
+            .context("MAX_CONCURRENT_UDP_REQUESTS must be a number between 1 and 2^64 - 1")?;
+
         let max_peers_per_torrent_per_user = env::var("MAX_PEERS_PER_TORRENT_PER_USER")
             .context("MAX_PEERS_PER_TORRENT_PER_USER not found in .env file.")?
             .parse()
@@ -259,11 +473,97 @@ impl Config {
             .parse()
             .context("CONNECTIVITY_CHECK_INTERVAL must be a number between -(2^63) and 2^63 - 1")?;
 
+        let connectable_port_ttl = env::var("CONNECTABLE_PORT_TTL")
+            .context("CONNECTABLE_PORT_TTL not found in .env file.")?
+            .parse()
+            .context("CONNECTABLE_PORT_TTL must be a number between 0 and 2^64 - 1")?;
+
+        let connectivity_check_mode = match env::var("CONNECTIVITY_CHECK_MODE").ok().as_deref() {
+            Some("connect") | None => connectivity::Mode::Connect,
+            Some("handshake") => connectivity::Mode::Handshake,
+            Some(_) => {
+                bail!(
+                    "CONNECTIVITY_CHECK_MODE must be either `connect` or `handshake`, if provided"
+                )
+            }
+        };
+
         let require_peer_connectivity = env::var("REQUIRE_PEER_CONNECTIVITY")
             .context("REQUIRE_PEER_CONNECTIVITY not found in .env file.")?
             .parse()
             .context("REQUIRE_PEER_CONNECTIVITY must be either `true` or `false`")?;
 
+        let max_concurrent_connectivity_checks: NonZeroUsize =
+            env::var("MAX_CONCURRENT_CONNECTIVITY_CHECKS")
+                .context("MAX_CONCURRENT_CONNECTIVITY_CHECKS not found in .env file.")?
+                .parse()
+                .context(
+                    "MAX_CONCURRENT_CONNECTIVITY_CHECKS must be a number between 1 and 2^64 - 1",
+                )?;
+
+        let max_queued_connectivity_checks = env::var("MAX_QUEUED_CONNECTIVITY_CHECKS")
+            .context("MAX_QUEUED_CONNECTIVITY_CHECKS not found in .env file.")?
+            .parse()
+            .context("MAX_QUEUED_CONNECTIVITY_CHECKS must be a number between 0 and 2^64 - 1")?;
+
+        let is_non_compact_peer_list_enabled = env::var("IS_NON_COMPACT_PEER_LIST_ENABLED")
+            .context("IS_NON_COMPACT_PEER_LIST_ENABLED not found in .env file.")?
+            .parse()
+            .context("IS_NON_COMPACT_PEER_LIST_ENABLED must be either `true` or `false`")?;
+
+        let is_client_allow_list_enabled = env::var("IS_CLIENT_ALLOW_LIST_ENABLED")
+            .context("IS_CLIENT_ALLOW_LIST_ENABLED not found in .env file.")?
+            .parse()
+            .context("IS_CLIENT_ALLOW_LIST_ENABLED must be either `true` or `false`")?;
+
+        let client_fingerprint_mode = match env::var("CLIENT_FINGERPRINT_MODE").ok().as_deref() {
+            Some("off") | None => fingerprint::Mode::Off,
+            Some("flag") => fingerprint::Mode::Flag,
+            Some("reject") => fingerprint::Mode::Reject,
+            Some(_) => {
+                bail!(
+                    "CLIENT_FINGERPRINT_MODE must be either `off`, `flag` or `reject`, if provided"
+                )
+            }
+        };
+
+        let max_upload_speed = env::var("MAX_UPLOAD_SPEED")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context("MAX_UPLOAD_SPEED must be a number between 0 and 2^64 - 1, if provided")?;
+
+        let max_upload_without_leechers = env::var("MAX_UPLOAD_WITHOUT_LEECHERS")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context(
+                "MAX_UPLOAD_WITHOUT_LEECHERS must be a number between 0 and 2^64 - 1, if provided",
+            )?;
+
+        let max_upload_over_swarm_download = env::var("MAX_UPLOAD_OVER_SWARM_DOWNLOAD")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()
+            .context(
+                "MAX_UPLOAD_OVER_SWARM_DOWNLOAD must be a number between 0 and 2^64 - 1, if provided",
+            )?;
+
+        let withhold_anomalous_upload_credit = env::var("WITHHOLD_ANOMALOUS_UPLOAD_CREDIT")
+            .context("WITHHOLD_ANOMALOUS_UPLOAD_CREDIT not found in .env file.")?
+            .parse()
+            .context("WITHHOLD_ANOMALOUS_UPLOAD_CREDIT must be either `true` or `false`")?;
+
+        let is_external_ip_enabled = env::var("IS_EXTERNAL_IP_ENABLED")
+            .context("IS_EXTERNAL_IP_ENABLED not found in .env file.")?
+            .parse()
+            .context("IS_EXTERNAL_IP_ENABLED must be either `true` or `false`")?;
+
+        let is_websocket_tracker_enabled = env::var("IS_WEBSOCKET_TRACKER_ENABLED")
+            .context("IS_WEBSOCKET_TRACKER_ENABLED not found in .env file.")?
+            .parse()
+            .context("IS_WEBSOCKET_TRACKER_ENABLED must be either `true` or `false`")?;
+
         let is_announce_logging_enabled = env::var("IS_ANNOUNCE_LOGGING_ENABLED")
             .context("IS_ANNOUNCE_LOGGING_ENABLED not found in .env file.")?
             .parse()
@@ -349,8 +649,14 @@ impl Config {
 
         Ok(Config {
             flush_interval_milliseconds: flush_interval_milliseconds.into(),
+            missed_tick_behavior,
             max_batches_per_flush,
             max_records_per_batch,
+            max_queue_lengths,
+            queue_full_policy,
+            readiness_max_failed_flushes: readiness_max_failed_flushes.into(),
+            readiness_max_queue_length,
+            dead_letter_path,
             numwant_default,
             numwant_max,
             announce_min,
@@ -361,14 +667,36 @@ impl Config {
             peer_expiry_interval: peer_expiry_interval.into(),
             active_peer_ttl,
             inactive_peer_ttl,
+            snapshot_path,
+            snapshot_interval: snapshot_interval.into(),
+            snapshot_max_age,
+            write_ahead_log_directory,
             apikey,
             listening_ip_address,
             listening_port,
             listening_unix_socket,
+            udp_listening_ip_address,
+            udp_listening_port,
+            max_concurrent_udp_requests: max_concurrent_udp_requests.into(),
             max_peers_per_torrent_per_user,
             is_connectivity_check_enabled,
             connectivity_check_interval,
+            connectivity_check_mode,
+            connectable_port_ttl,
             require_peer_connectivity,
+            max_concurrent_connectivity_checks: max_concurrent_connectivity_checks.into(),
+            max_queued_connectivity_checks,
+            is_non_compact_peer_list_enabled,
+            is_client_allow_list_enabled,
+            client_fingerprint_mode,
+            upload_anomaly_thresholds: upload_anomaly::Thresholds {
+                max_upload_speed,
+                max_upload_without_leechers,
+                max_upload_over_swarm_download,
+            },
+            withhold_anomalous_upload_credit,
+            is_external_ip_enabled,
+            is_websocket_tracker_enabled,
             is_announce_logging_enabled,
             reverse_proxy_client_ip_header_name,
             user_receive_seed_list_rate_limits,
@@ -388,7 +716,11 @@ impl Config {
         if dotenv_override().is_ok() {
             match Config::from_env() {
                 Ok(new_config) => {
+                    state
+                        .queues
+                        .set_limits(&new_config.max_queue_lengths, new_config.queue_full_policy);
                     state.config.store(Arc::new(new_config));
+                    state.scheduler.reload();
 
                     info!("Successfully reloaded config.");
 
@@ -0,0 +1,357 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use chrono::{Duration, Utc};
+use indexmap::{IndexMap, IndexSet};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Notify,
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    model::info_hash::InfoHash,
+    queue::torrent_update::{Index, TorrentUpdate},
+    state::AppState,
+    store::{connectable_port::ConnectablePort, peer},
+};
+
+/// Max time to wait for a peer to accept the connection before it's
+/// considered unconnectable.
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Protocol string starting each BitTorrent handshake (BEP 3).
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Peer id the tracker identifies itself with in handshakes.
+const PEER_ID: &[u8; 20] = b"-UA0000-connectivity";
+
+/// Peer announcing from a socket, identified by its torrent id and index.
+type PeerLocation = (u32, peer::Index);
+
+/// How peers are checked for connectivity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Peers accepting a TCP connection are connectable.
+    Connect,
+    /// Peers answering a BitTorrent handshake for the torrent they announced
+    /// are connectable, so that other services listening on the port aren't
+    /// mistaken for the client.
+    Handshake,
+}
+
+/// Connectivity of a socket as last recorded.
+pub struct Connectivity {
+    pub is_connectable: bool,
+    /// The connectivity is unknown or older than the check interval, and the
+    /// socket should be probed again.
+    pub is_outdated: bool,
+}
+
+/// Looks up the last recorded connectivity of a socket.
+pub fn lookup(state: &AppState, socket: SocketAddr) -> Connectivity {
+    let config = state.config.load();
+
+    if !config.is_connectivity_check_enabled {
+        return Connectivity {
+            is_connectable: false,
+            is_outdated: false,
+        };
+    }
+
+    match state.stores.connectable_ports.read().get(&socket) {
+        Some(connectable_port) => Connectivity {
+            is_connectable: connectable_port.connectable,
+            is_outdated: connectable_port
+                .updated_at
+                .checked_add_signed(Duration::seconds(config.connectivity_check_interval))
+                .is_none_or(|cached_until| cached_until <= Utc::now()),
+        },
+        // Sockets that weren't probed yet are assumed to be connectable until
+        // the probe answers, so that new peers don't receive empty peer lists
+        // when connectivity is required.
+        None => Connectivity {
+            is_connectable: true,
+            is_outdated: true,
+        },
+    }
+}
+
+/// Probes the connectivity of peers in the background, so that announces
+/// don't wait on peers accepting the connection.
+#[derive(Default)]
+pub struct ConnectivityChecker {
+    probes: Mutex<Probes>,
+    queued: Notify,
+}
+
+#[derive(Default)]
+struct Probes {
+    /// Sockets waiting to be probed, along with the info hash of the torrent
+    /// to handshake with and the peers announcing from them.
+    queued: IndexMap<SocketAddr, (InfoHash, IndexSet<PeerLocation>)>,
+    /// Sockets currently being probed, along with the peers announcing from
+    /// them.
+    running: IndexMap<SocketAddr, IndexSet<PeerLocation>>,
+}
+
+impl ConnectivityChecker {
+    /// Queues a probe of the socket a peer announced from. The peer is
+    /// updated once the probe completes. Sockets already queued or being
+    /// probed aren't probed twice, and the probe is dropped if the queue is
+    /// full, to be retried on the next announce.
+    pub fn enqueue(
+        &self,
+        state: &AppState,
+        socket: SocketAddr,
+        info_hash: InfoHash,
+        peer: PeerLocation,
+    ) {
+        let mut probes = self.probes.lock();
+
+        if let Some(peers) = probes.running.get_mut(&socket) {
+            peers.insert(peer);
+        } else if let Some((_, peers)) = probes.queued.get_mut(&socket) {
+            peers.insert(peer);
+        } else if probes.queued.len() < state.config.load().max_queued_connectivity_checks {
+            probes
+                .queued
+                .insert(socket, (info_hash, IndexSet::from([peer])));
+
+            self.queued.notify_one();
+        }
+    }
+
+    /// Moves the oldest queued probe to the running probes.
+    fn start_next(&self) -> Option<(SocketAddr, InfoHash)> {
+        let mut probes = self.probes.lock();
+        let (socket, (info_hash, peers)) = probes.queued.shift_remove_index(0)?;
+
+        probes.running.insert(socket, peers);
+
+        Some((socket, info_hash))
+    }
+
+    /// Removes a finished probe from the running probes, returning the peers
+    /// announcing from its socket.
+    fn finish(&self, socket: SocketAddr) -> IndexSet<PeerLocation> {
+        self.probes
+            .lock()
+            .running
+            .swap_remove(&socket)
+            .unwrap_or_default()
+    }
+}
+
+/// Runs the queued probes, at most `MAX_CONCURRENT_CONNECTIVITY_CHECKS` at a
+/// time.
+pub async fn run(state: Arc<AppState>) {
+    let mut probes = JoinSet::new();
+
+    loop {
+        while probes.len() >= state.config.load().max_concurrent_connectivity_checks {
+            probes.join_next().await;
+        }
+
+        match state.connectivity_checker.start_next() {
+            Some((socket, info_hash)) => {
+                probes.spawn(probe(state.clone(), socket, info_hash));
+            }
+            None => {
+                tokio::select! {
+                    _ = state.connectivity_checker.queued.notified() => (),
+                    Some(_) = probes.join_next() => (),
+                }
+            }
+        }
+    }
+}
+
+/// Records whether the socket accepts connections, and updates the peers
+/// announcing from it along with the seeder and leecher counts they're
+/// included in.
+async fn probe(state: Arc<AppState>, socket: SocketAddr, info_hash: InfoHash) {
+    let mode = state.config.load().connectivity_check_mode;
+    let result = timeout(CONNECT_TIMEOUT, async {
+        let mut stream = TcpStream::connect(socket).await?;
+
+        match mode {
+            Mode::Connect => Ok(true),
+            Mode::Handshake => handshake(&mut stream, info_hash).await,
+        }
+    })
+    .await;
+    let is_connectable = matches!(result, Ok(Ok(true)));
+
+    state.stores.connectable_ports.write().insert(
+        socket,
+        ConnectablePort {
+            connectable: is_connectable,
+            updated_at: Utc::now(),
+        },
+    );
+
+    let config = state.config.load();
+    let mut user_deltas: IndexMap<u32, (i32, i32)> = IndexMap::new();
+    let mut torrent_updates = Vec::new();
+
+    for (torrent_id, index) in state.connectivity_checker.finish(socket) {
+        let mut torrent_guard = state.stores.torrents.lock(torrent_id);
+
+        let Some(torrent) = torrent_guard.get_mut(&torrent_id) else {
+            continue;
+        };
+
+        let Some(peer) = torrent.peers.get_mut(&index) else {
+            continue;
+        };
+
+        // The peer may have announced from another socket since
+        if peer.is_webrtc
+            || peer.ip_address != socket.ip()
+            || peer.port != socket.port()
+            || peer.is_connectable == is_connectable
+        {
+            continue;
+        }
+
+        let old_peer = *peer;
+
+        peer.is_connectable = is_connectable;
+
+        let seeder_delta = peer.is_included_in_seed_list(&config) as i32
+            - old_peer.is_included_in_seed_list(&config) as i32;
+        let leecher_delta = peer.is_included_in_leech_list(&config) as i32
+            - old_peer.is_included_in_leech_list(&config) as i32;
+
+        if seeder_delta != 0 || leecher_delta != 0 {
+            torrent.seeders = torrent.seeders.saturating_add_signed(seeder_delta);
+            torrent.leechers = torrent.leechers.saturating_add_signed(leecher_delta);
+
+            let (user_seeder_delta, user_leecher_delta) =
+                user_deltas.entry(index.user_id).or_default();
+
+            *user_seeder_delta += seeder_delta;
+            *user_leecher_delta += leecher_delta;
+
+            torrent_updates.push((
+                Index { torrent_id },
+                TorrentUpdate {
+                    seeder_delta,
+                    leecher_delta,
+                    times_completed_delta: 0,
+                    balance_delta: 0,
+                },
+            ));
+        }
+    }
+
+    if !user_deltas.is_empty() {
+        let mut user_guard = state.stores.users.write();
+
+        for (user_id, (seeder_delta, leecher_delta)) in user_deltas {
+            if let Some(user) = user_guard.get_mut(&user_id) {
+                user.num_seeding = user.num_seeding.saturating_add_signed(seeder_delta);
+                user.num_leeching = user.num_leeching.saturating_add_signed(leecher_delta);
+            }
+        }
+    }
+
+    if !torrent_updates.is_empty() {
+        let mut torrent_queue = state.queues.torrents.lock();
+
+        for (index, update) in torrent_updates {
+            torrent_queue.upsert(index, update);
+        }
+    }
+}
+
+/// Sends a BitTorrent handshake for the info hash, and checks whether the
+/// peer answers with a handshake for the same info hash.
+async fn handshake(stream: &mut TcpStream, info_hash: InfoHash) -> std::io::Result<bool> {
+    let mut request = Vec::with_capacity(68);
+
+    request.push(PROTOCOL.len() as u8);
+    request.extend(PROTOCOL);
+    request.extend([0; 8]);
+    request.extend(info_hash.0);
+    request.extend(PEER_ID);
+
+    stream.write_all(&request).await?;
+
+    // The peer id of the reply is ignored, since some clients only send it
+    // after receiving the handshake of the other side.
+    let mut reply = [0; 48];
+
+    stream.read_exact(&mut reply).await?;
+
+    Ok(reply[0] as usize == PROTOCOL.len()
+        && reply[1..20] == PROTOCOL[..]
+        && reply[28..48] == info_hash.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Starts a peer answering the first connection with the reply, and
+    /// returns its socket.
+    async fn stub_peer(reply: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 68];
+
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&reply).await.unwrap();
+        });
+
+        socket
+    }
+
+    fn handshake_reply(info_hash: InfoHash) -> Vec<u8> {
+        let mut reply = vec![19];
+
+        reply.extend(PROTOCOL);
+        reply.extend([0; 8]);
+        reply.extend(info_hash.0);
+        reply.extend(b"-qB5000-000000000000");
+
+        reply
+    }
+
+    async fn is_connectable(socket: SocketAddr, info_hash: InfoHash) -> bool {
+        let mut stream = TcpStream::connect(socket).await.unwrap();
+
+        handshake(&mut stream, info_hash).await.unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn handshake_with_client() {
+        let info_hash = InfoHash([1; 20]);
+        let socket = stub_peer(handshake_reply(info_hash)).await;
+
+        assert!(is_connectable(socket, info_hash).await);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_other_torrent() {
+        let socket = stub_peer(handshake_reply(InfoHash([2; 20]))).await;
+
+        assert!(!is_connectable(socket, InfoHash([1; 20])).await);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_other_service() {
+        let reply = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let socket = stub_peer(reply).await;
+
+        assert!(!is_connectable(socket, InfoHash([1; 20])).await);
+    }
+}
@@ -3,9 +3,12 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
+use strum::IntoStaticStr;
 use thiserror::Error;
 
-#[derive(Error, Debug, Clone)]
+use crate::bencode;
+
+#[derive(Error, Debug, Clone, IntoStaticStr)]
 pub enum AnnounceError {
     #[error("Internal tracker error.")]
     InternalTrackerError,
@@ -33,6 +36,11 @@ pub enum AnnounceError {
     InvalidNumwant,
     #[error("Query parameter 'info_hash' is missing.")]
     MissingInfoHash,
+    #[error(
+        "Too many 'info_hash' parameters (max {}).",
+        crate::scrape::MAX_INFO_HASHES
+    )]
+    TooManyInfoHashes,
     #[error("Query parameter 'peer_id' is missing.")]
     MissingPeerId,
     #[error("Query parameter 'port' is missing.")]
@@ -51,8 +59,14 @@ pub enum AnnounceError {
     UserAgentTooLong,
     #[error("Client is not acceptable. Please check our blacklist.")]
     BlacklistedClient,
-    #[error("Browser, crawler or cheater is not allowed.")]
-    NotAClient,
+    #[error("{0}")]
+    BlockedClient(String),
+    #[error("Client is not on the allow list.")]
+    ClientNotAllowed,
+    #[error("Client does not match its peer id.")]
+    ClientMismatch,
+    #[error("Your IP address is banned.")]
+    BannedIpAddress,
     #[error("Invalid passkey.")]
     InvalidPasskey,
     #[error("Passkey does not exist. Please re-download the .torrent file.")]
@@ -61,8 +75,8 @@ pub enum AnnounceError {
     UserNotFound,
     #[error("Your downloading privileges have been disabled.")]
     DownloadPrivilegesRevoked,
-    #[error("Illegal port: {0}. Port should be between 6881-64999.")]
-    BlacklistedPort(u16),
+    #[error("Illegal port: {0}. Ports {1} are blacklisted.")]
+    BlacklistedPort(u16, String),
     #[error("InfoHash not found.")]
     InfoHashNotFound,
     #[error("Torrent not found.")]
@@ -85,55 +99,68 @@ pub enum AnnounceError {
     PeersPerTorrentPerUserLimit(u16),
     #[error("Stopped peer doesn't exist.")]
     StoppedPeerDoesNotExist,
+    #[error("Peer id is already in use by another user.")]
+    PeerIdInUse,
+    #[error("Invalid connection id.")]
+    InvalidConnectionId,
+    #[error("Malformed request.")]
+    MalformedRequest,
 }
 
 impl IntoResponse for AnnounceError {
     fn into_response(self) -> Response {
-        let mut response: Vec<u8> = vec![];
+        (StatusCode::OK, self.into_bencode()).into_response()
+    }
+}
 
-        if self.is_critical_warning() {
-            response.extend(b"d8:completei0e10:downloadedi0e10:incompletei0e");
+impl AnnounceError {
+    /// Generate bencoded response to return to client
+    pub fn into_bencode(self) -> Vec<u8> {
+        let message = self.to_string();
+        let mut response: Vec<u8> = Vec::with_capacity(
+            100 // literal characters
+                + 2 * 5 // numbers with estimated digit quantity for each
+                + message.len() + 5, // message plus estimated length prefix
+        );
 
-            match self {
-                // If the torrent status is pending, postponed, or rejected,
-                // reduce the interval to 30 seconds. This allows the uploader
-                // to start seeding sooner when the torrent is approved.
-                Self::TorrentIsPendingModeration
-                | Self::TorrentIsPostponed
-                | Self::TorrentIsRejected => response.extend(b"8:intervali30e12:min intervali30e"),
-                _ => response.extend(b"8:intervali5400e12:min intervali5400e"),
-            };
+        bencode::dictionary(&mut response, |response| {
+            if self.is_critical_warning() {
+                response
+                    .integer(b"complete", 0)
+                    .integer(b"downloaded", 0)
+                    .integer(b"incomplete", 0)
+                    .integer(b"interval", self.interval())
+                    .integer(b"min interval", self.interval())
+                    .bytes(b"peers", b"")
+                    .bytes(b"warning message", message.as_bytes());
+            } else {
+                response
+                    .bytes(b"failure reason", message.as_bytes())
+                    .integer(b"interval", self.interval())
+                    .integer(b"min interval", self.interval());
+            }
+        });
 
-            response.extend(b"5:peers0:15:warning message");
-            response.extend(self.to_string().len().to_string().as_bytes());
-            response.extend(b":");
-            response.extend(self.to_string().as_bytes());
-            response.extend(b"e");
-        } else {
-            response.extend(b"d14:failure reason");
-            response.extend(self.to_string().len().to_string().as_bytes());
-            response.extend(b":");
-            response.extend(self.to_string().as_bytes());
+        response
+    }
 
-            // If the torrent status is pending, postponed, or rejected, reduce the interval to 30 seconds.
-            // This allows the uploader to start seeding sooner when the torrent is approved.
-            match self {
-                Self::TorrentIsPendingModeration
-                | Self::TorrentIsPostponed
-                | Self::TorrentIsRejected => response.extend(b"8:intervali30e12:min intervali30ee"),
-                _ => response.extend(b"8:intervali5400e12:min intervali5400ee"),
-            };
+    /// The amount of seconds the client should wait before announcing again.
+    pub fn interval(&self) -> u32 {
+        match self {
+            // If the torrent status is pending, postponed, or rejected,
+            // reduce the interval to 30 seconds. This allows the uploader
+            // to start seeding sooner when the torrent is approved.
+            Self::TorrentIsPendingModeration
+            | Self::TorrentIsPostponed
+            | Self::TorrentIsRejected => 30,
+            _ => 5400,
         }
-
-        (StatusCode::OK, response).into_response()
     }
-}
 
-impl AnnounceError {
     /// Announce warnings that act as an error by immediately returning
     /// an empty peer list but are not explicit errors due to undesired
     /// side effects.
-    fn is_critical_warning(&self) -> bool {
+    pub fn is_critical_warning(&self) -> bool {
         match self {
             // Some clients (namely transmission) will keep sending
             // `stopped` events until a successful announce is received.
@@ -161,3 +188,32 @@ pub enum DecodeError {
     #[error("Invalid infohash.")]
     InfoHash,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_response() {
+        assert_eq!(
+            AnnounceError::InvalidPasskey.into_bencode(),
+            b"d14:failure reason16:Invalid passkey.8:intervali5400e12:min intervali5400ee"
+        );
+    }
+
+    #[test]
+    fn critical_warning_response() {
+        let error = AnnounceError::TorrentIsPendingModeration;
+        let message = error.to_string();
+
+        assert_eq!(
+            error.into_bencode(),
+            [
+                b"d8:completei0e10:downloadedi0e10:incompletei0e".as_slice(),
+                b"8:intervali30e12:min intervali30e5:peers0:",
+                format!("15:warning message{}:{message}e", message.len()).as_bytes(),
+            ]
+            .concat()
+        );
+    }
+}
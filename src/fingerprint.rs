@@ -0,0 +1,195 @@
+/// How announces are handled whose peer id and user agent identify different
+/// clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Peer ids and user agents aren't compared.
+    Off,
+    /// Mismatches are queued for staff review, and the announce is accepted.
+    Flag,
+    /// Mismatches are queued for staff review, and the announce is refused.
+    Reject,
+}
+
+/// How a client formats the version in its user agent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VersionFormat {
+    /// Components separated by dots, such as `4.6.5`.
+    Dotted,
+    /// Single digit components without separators, such as `2210` for
+    /// 2.2.1.
+    Compact,
+}
+
+/// Clients with Azureus-style peer ids (`-qB4650-`), by their two character
+/// code, along with the products their user agents start with and the
+/// format of their user agent version.
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str, &[&str], VersionFormat)] = &[
+    (b"AZ", "Vuze", &["Azureus", "Vuze"], VersionFormat::Dotted),
+    (b"BI", "BiglyBT", &["BiglyBT"], VersionFormat::Dotted),
+    (b"BT", "BitTorrent", &["BitTorrent"], VersionFormat::Compact),
+    (b"DE", "Deluge", &["Deluge"], VersionFormat::Dotted),
+    (b"KT", "KTorrent", &["KTorrent"], VersionFormat::Dotted),
+    (
+        b"lt",
+        "rTorrent",
+        &["rtorrent", "libTorrent"],
+        VersionFormat::Dotted,
+    ),
+    (
+        b"qB",
+        "qBittorrent",
+        &["qBittorrent"],
+        VersionFormat::Dotted,
+    ),
+    (
+        b"TR",
+        "Transmission",
+        &["Transmission"],
+        VersionFormat::Dotted,
+    ),
+    (
+        b"UM",
+        "µTorrent Mac",
+        &["uTorrentMac"],
+        VersionFormat::Compact,
+    ),
+    (b"UT", "µTorrent", &["uTorrent"], VersionFormat::Compact),
+];
+
+/// Clients with Shadow-style peer ids (`T03I-----`), by their one character
+/// code, along with the products their user agents start with.
+const SHADOW_CLIENTS: &[(u8, &str, &[&str])] = &[
+    (b'A', "ABC", &["ABC"]),
+    (b'R', "Tribler", &["Tribler"]),
+    (b'T', "BitTornado", &["BitTornado"]),
+];
+
+/// Client identified by its peer id.
+#[derive(Debug, PartialEq)]
+pub struct Client {
+    pub name: &'static str,
+    /// Products the user agent of the client starts with.
+    pub user_agent_products: &'static [&'static str],
+    pub version_format: VersionFormat,
+    pub major_version: Option<u32>,
+}
+
+impl Client {
+    /// Identifies the client from an Azureus-style or Shadow-style peer id.
+    /// Returns `None` for unknown clients.
+    pub fn from_peer_id(peer_id: &[u8]) -> Option<Client> {
+        if let [b'-', a, b, version, ..] = peer_id
+            && peer_id.get(7) == Some(&b'-')
+        {
+            let &(_, name, user_agent_products, version_format) = AZUREUS_CLIENTS
+                .iter()
+                .find(|(code, _, _, _)| code == &&[*a, *b])?;
+
+            return Some(Client {
+                name,
+                user_agent_products,
+                version_format,
+                major_version: decode_version(*version),
+            });
+        }
+
+        if let [code, ..] = peer_id
+            && peer_id.get(6..9) == Some(b"---")
+        {
+            let &(_, name, user_agent_products) =
+                SHADOW_CLIENTS.iter().find(|(c, _, _)| c == code)?;
+
+            // Shadow-style clients prefix the version of their user agent
+            // inconsistently, such as `BitTornado/T-0.3.18`
+            return Some(Client {
+                name,
+                user_agent_products,
+                version_format: VersionFormat::Dotted,
+                major_version: None,
+            });
+        }
+
+        None
+    }
+
+    /// Whether the user agent, such as `qBittorrent/4.6.5` or
+    /// `BiglyBT 3.5.0.0;Windows 10;Java 17`, belongs to the client. Only the
+    /// product and the major version are compared, since clients format the
+    /// rest of their user agent and peer id differently.
+    pub fn matches_user_agent(&self, user_agent: &str) -> bool {
+        let (product, version) = user_agent
+            .split_once(['/', ' '])
+            .unwrap_or((user_agent, ""));
+
+        self.user_agent_products
+            .iter()
+            .any(|expected| product.eq_ignore_ascii_case(expected))
+            && self
+                .major_version
+                .is_none_or(|major| self.parse_major_version(version) == Some(major))
+    }
+
+    /// Parses the major version from the start of a user agent version, such
+    /// as `4` from `4.6.5` or `2` from `2210(25130)`.
+    fn parse_major_version(&self, version: &str) -> Option<u32> {
+        let digits = version
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(version, |end| &version[..end]);
+        let digits = match self.version_format {
+            VersionFormat::Dotted => digits,
+            VersionFormat::Compact => digits.get(..1)?,
+        };
+
+        digits.parse().ok()
+    }
+}
+
+/// Returns the client identified by the peer id if the user agent belongs to
+/// another client. Peer ids of unknown clients are never mismatches.
+pub fn mismatch(peer_id: &[u8], user_agent: &str) -> Option<Client> {
+    Client::from_peer_id(peer_id).filter(|client| !client.matches_user_agent(user_agent))
+}
+
+/// Decodes a version character, where `A` to `Z` follow `9`.
+fn decode_version(version: u8) -> Option<u32> {
+    match version {
+        b'0'..=b'9' => Some((version - b'0').into()),
+        b'A'..=b'Z' => Some((version - b'A' + 10).into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_clients() {
+        for (peer_id, user_agent) in [
+            (b"-qB4650-000000000000", "qBittorrent/4.6.5"),
+            (b"-TR3000-000000000000", "Transmission/3.00"),
+            (b"-DE211s-000000000000", "Deluge/2.1.1 libtorrent/2.0.9.0"),
+            (b"-UT2210-000000000000", "uTorrent/2210(25130)"),
+            (
+                b"-BI3500-000000000000",
+                "BiglyBT 3.5.0.0;Windows 10;Java 17",
+            ),
+            (b"-lt0D80-000000000000", "rtorrent/0.9.8/0.13.8"),
+            (b"T03I-----00000000000", "BitTornado/T-0.3.18"),
+            (b"-XX1000-000000000000", "Anything/1.0"),
+        ] {
+            assert!(mismatch(peer_id, user_agent).is_none(), "{user_agent}");
+        }
+    }
+
+    #[test]
+    fn spoofed_peer_id() {
+        let client = mismatch(b"-qB4650-000000000000", "Transmission/4.0.6").unwrap();
+
+        assert_eq!(client.name, "qBittorrent");
+        assert!(mismatch(b"-qB4650-000000000000", "qBittorrent/3.3.16").is_some());
+        assert!(mismatch(b"-qB1000-000000000000", "qBittorrent/10.0.0").is_some());
+        assert!(mismatch(b"-TR4060-000000000000", "Transmission/40.6").is_some());
+        assert!(mismatch(b"-UT2210-000000000000", "").is_some());
+    }
+}
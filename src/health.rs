@@ -0,0 +1,74 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode};
+use tokio::{task, time::timeout};
+
+use crate::state::AppState;
+
+/// Max time to wait for a database connection before the tracker is reported
+/// as not ready.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Max time to wait for the stores to be unlocked before the tracker is
+/// reported as not alive.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports whether the tracker is still able to handle announces, so that it
+/// can be restarted otherwise. Fails if the stores stay locked, such as after
+/// a deadlock.
+pub async fn live(State(state): State<Arc<AppState>>) -> (StatusCode, &'static str) {
+    let is_alive = task::spawn_blocking(move || {
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+
+        state
+            .stores
+            .torrents
+            .shards()
+            .iter()
+            .all(|shard| shard.try_lock_until(deadline).is_some())
+            && state.stores.users.try_read_until(deadline).is_some()
+    })
+    .await
+    .unwrap_or(false);
+
+    if is_alive {
+        (StatusCode::OK, "LIVE")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Stores are locked.")
+    }
+}
+
+/// Reports whether the updates of announces are being persisted to the
+/// database, so that load balancers can drain the tracker otherwise. Lists
+/// each problem found on a separate line.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, String) {
+    let config = state.config.load();
+    let mut problems: Vec<String> = Vec::new();
+
+    for (record_type, failures) in state.metrics.consecutive_flush_failures() {
+        if failures >= config.readiness_max_failed_flushes {
+            problems.push(format!("Last {failures} flushes of {record_type} failed."));
+        }
+    }
+
+    for (record_type, len) in state.queues.lengths() {
+        if len > config.readiness_max_queue_length {
+            problems.push(format!("{len} {record_type} are queued."));
+        }
+    }
+
+    match timeout(DATABASE_TIMEOUT, state.pool.acquire()).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => problems.push(format!("Failed to connect to database: {e}")),
+        Err(_) => problems.push("Timed out connecting to database.".to_string()),
+    }
+
+    if problems.is_empty() {
+        (StatusCode::OK, "READY".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n"))
+    }
+}
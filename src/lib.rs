@@ -0,0 +1,27 @@
+mod announce;
+mod api;
+mod bencode;
+mod config;
+pub mod connectivity;
+mod error;
+mod fingerprint;
+mod health;
+mod metrics;
+mod model;
+mod queue;
+mod rate;
+pub mod routes;
+pub mod scheduler;
+mod scrape;
+pub mod snapshot;
+pub mod state;
+mod stats;
+mod store;
+pub mod udp;
+mod upload_anomaly;
+mod utils;
+mod warning;
+mod websocket;
+
+// Used by the benchmarks
+pub use store::torrent::{Shard, Torrent, TorrentStore};
@@ -1,10 +1,12 @@
 use anyhow::{Context, Result, bail};
 use axum::Router;
 use dotenvy::dotenv;
+use futures_util::future::join_all;
 use std::net::SocketAddr;
 use tokio::{
-    net::{TcpListener, UnixListener},
+    net::{TcpListener, UdpSocket, UnixListener},
     signal,
+    sync::oneshot,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -15,20 +17,7 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-mod announce;
-mod api;
-mod config;
-mod error;
-mod model;
-mod queue;
-mod rate;
-mod routes;
-mod scheduler;
-mod state;
-mod stats;
-mod store;
-mod utils;
-mod warning;
+use unit3d_announce::{connectivity, routes, scheduler, snapshot, state, udp};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -50,15 +39,12 @@ async fn main() -> Result<()> {
     // The state struct keeps track of all state within the application.
     let state = state::AppState::default().await?;
 
-    // Starts scheduler to automate flushing updates
-    // to database and inactive peer removal.
-    let _handle = tokio::spawn({
-        let state = state.clone();
+    // Starts scheduled jobs to automate flushing updates
+    // to database, inactive peer removal and snapshots.
+    let handles = scheduler::spawn(&state);
 
-        async move {
-            scheduler::handle(&state).await;
-        }
-    });
+    // Starts probing the connectivity of peers in the background.
+    let _connectivity_handle = tokio::spawn(connectivity::run(state.clone()));
 
     // Create router.
     let app = Router::new()
@@ -69,6 +55,21 @@ async fn main() -> Result<()> {
     // reloading config triggers a deadlock
     let config = state.config.load().clone();
 
+    // Starts the UDP tracker if configured.
+    let udp_listener = if let Some(ip) = config.udp_listening_ip_address
+        && let Some(port) = config.udp_listening_port
+    {
+        let socket = UdpSocket::bind(SocketAddr::from((ip, port))).await?;
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+
+        Some((
+            shutdown_sender,
+            tokio::spawn(udp::listen(state.clone(), socket, shutdown_receiver)),
+        ))
+    } else {
+        None
+    };
+
     if let Some(path) = config.listening_unix_socket.to_owned() {
         // Create unix domain socket.
         let _ = tokio::fs::remove_file(&path).await;
@@ -102,6 +103,18 @@ async fn main() -> Result<()> {
         bail!("Listener not configured.");
     }
 
+    // Stop receiving UDP announces and wait for the ones in progress so
+    // that they aren't lost after the final flush.
+    if let Some((shutdown_sender, udp_handle)) = udp_listener {
+        let _ = shutdown_sender.send(());
+        let _ = udp_handle.await;
+    }
+
+    // Stop the scheduled jobs so that they don't run concurrently with the
+    // final flush and snapshot.
+    state.scheduler.stop();
+    join_all(handles).await;
+
     // Flush all remaining updates before shutting down.
     let max_flushes = 1000;
     let mut flushes = 0;
@@ -111,6 +124,16 @@ async fn main() -> Result<()> {
         flushes += 1;
     }
 
+    // Persist the stores after the final flush so that the next start
+    // doesn't have to load them from the database.
+    let snapshot_path = state.config.load().snapshot_path.clone();
+
+    if let Some(path) = snapshot_path
+        && let Err(e) = snapshot::save(&state, &path).await
+    {
+        println!("{e:#}");
+    }
+
     if flushes == max_flushes {
         println!("Graceful shutdown failed");
     } else {
@@ -0,0 +1,449 @@
+use std::{
+    fmt::{Display, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+
+use crate::{error::AnnounceError, state::AppState, stats::AtomicF64, warning::AnnounceWarning};
+
+/// Upper bounds in seconds of the announce duration histogram buckets.
+const ANNOUNCE_DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Upper bounds in seconds of the flush duration histogram buckets.
+const FLUSH_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Upper bounds of the flushed rows histogram buckets.
+const FLUSH_ROWS_BUCKETS: &[f64] = &[0.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0];
+
+/// Record types of the queued database updates, as passed to the flushes.
+const RECORD_TYPES: [&str; 8] = [
+    "announces",
+    "client mismatches",
+    "histories",
+    "peers",
+    "torrents",
+    "unregistered info hashes",
+    "upload anomalies",
+    "users",
+];
+
+/// Counters and histograms exposed to Prometheus.
+pub struct Metrics {
+    announce_durations: Histogram,
+    announce_errors: Mutex<IndexMap<&'static str, u64>>,
+    announce_warnings: Mutex<IndexMap<&'static str, u64>>,
+    flushes: IndexMap<&'static str, FlushMetrics>,
+}
+
+struct FlushMetrics {
+    durations: Histogram,
+    rows: Histogram,
+    failures: AtomicU64,
+    /// Failures since the last successful flush.
+    consecutive_failures: AtomicU64,
+    /// Updates dropped because the queue was full.
+    dropped_updates: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            announce_durations: Histogram::new(ANNOUNCE_DURATION_BUCKETS),
+            announce_errors: Mutex::new(IndexMap::new()),
+            announce_warnings: Mutex::new(IndexMap::new()),
+            flushes: RECORD_TYPES
+                .into_iter()
+                .map(|record_type| {
+                    (
+                        record_type,
+                        FlushMetrics {
+                            durations: Histogram::new(FLUSH_DURATION_BUCKETS),
+                            rows: Histogram::new(FLUSH_ROWS_BUCKETS),
+                            failures: AtomicU64::new(0),
+                            consecutive_failures: AtomicU64::new(0),
+                            dropped_updates: AtomicU64::new(0),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Records a handled announce along with the error it failed with, if
+    /// any.
+    pub fn record_announce(&self, duration: Duration, error: Option<&AnnounceError>) {
+        self.announce_durations.observe(duration.as_secs_f64());
+
+        if let Some(error) = error {
+            *self.announce_errors.lock().entry(error.into()).or_default() += 1;
+        }
+    }
+
+    /// Records a warning added to an announce response.
+    pub fn record_warning(&self, warning: AnnounceWarning) {
+        *self
+            .announce_warnings
+            .lock()
+            .entry(warning.into())
+            .or_default() += 1;
+    }
+
+    /// Records a batch flushed to the database along with the amount of rows
+    /// affected, or `None` if the flush failed.
+    pub fn record_flush(&self, record_type: &str, duration: Duration, rows: Option<u64>) {
+        let Some(flush) = self.flushes.get(record_type) else {
+            return;
+        };
+
+        flush.durations.observe(duration.as_secs_f64());
+
+        match rows {
+            Some(rows) => {
+                flush.rows.observe(rows as f64);
+                flush.consecutive_failures.store(0, Ordering::Relaxed);
+            }
+            None => {
+                flush.failures.fetch_add(1, Ordering::Relaxed);
+                flush.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Records updates dropped because their queue was full.
+    pub fn record_dropped_updates(&self, record_type: &str, count: u64) {
+        if let Some(flush) = self.flushes.get(record_type) {
+            flush.dropped_updates.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    /// Amount of batches of each record type that failed to flush since the
+    /// last successful flush.
+    pub fn consecutive_flush_failures(&self) -> impl Iterator<Item = (&'static str, u64)> {
+        self.flushes.iter().map(|(&record_type, flush)| {
+            (
+                record_type,
+                flush.consecutive_failures.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Encodes the metrics in the Prometheus text exposition format.
+    fn encode(&self, state: &AppState) -> String {
+        let mut buffer = String::new();
+
+        header(
+            &mut buffer,
+            "unit3d_announce_announce_duration_seconds",
+            "histogram",
+            "Time taken to handle an announce.",
+        );
+        self.announce_durations.encode(
+            &mut buffer,
+            "unit3d_announce_announce_duration_seconds",
+            "",
+        );
+
+        header(
+            &mut buffer,
+            "unit3d_announce_announce_errors_total",
+            "counter",
+            "Announces that failed, by error.",
+        );
+
+        for (error, count) in self.announce_errors.lock().iter() {
+            sample(
+                &mut buffer,
+                "unit3d_announce_announce_errors_total",
+                &format!("error=\"{error}\""),
+                count,
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_announce_warnings_total",
+            "counter",
+            "Warnings added to announce responses, by warning.",
+        );
+
+        for (warning, count) in self.announce_warnings.lock().iter() {
+            sample(
+                &mut buffer,
+                "unit3d_announce_announce_warnings_total",
+                &format!("warning=\"{warning}\""),
+                count,
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_queue_length",
+            "gauge",
+            "Database updates waiting to be flushed, by record type.",
+        );
+
+        for (record_type, len) in state.queues.lengths() {
+            sample(
+                &mut buffer,
+                "unit3d_announce_queue_length",
+                &record_type_label(record_type),
+                len,
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_dropped_updates_total",
+            "counter",
+            "Updates dropped because their queue was full, by record type.",
+        );
+
+        for (record_type, flush) in &self.flushes {
+            sample(
+                &mut buffer,
+                "unit3d_announce_dropped_updates_total",
+                &record_type_label(record_type),
+                flush.dropped_updates.load(Ordering::Relaxed),
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_flush_duration_seconds",
+            "histogram",
+            "Time taken to flush a batch of updates to the database, by record type.",
+        );
+
+        for (record_type, flush) in &self.flushes {
+            flush.durations.encode(
+                &mut buffer,
+                "unit3d_announce_flush_duration_seconds",
+                &record_type_label(record_type),
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_flush_rows_affected",
+            "histogram",
+            "Rows affected by a successfully flushed batch of updates, by record type.",
+        );
+
+        for (record_type, flush) in &self.flushes {
+            flush.rows.encode(
+                &mut buffer,
+                "unit3d_announce_flush_rows_affected",
+                &record_type_label(record_type),
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_flush_failures_total",
+            "counter",
+            "Batches of updates that failed to flush, by record type.",
+        );
+
+        for (record_type, flush) in &self.flushes {
+            sample(
+                &mut buffer,
+                "unit3d_announce_flush_failures_total",
+                &record_type_label(record_type),
+                flush.failures.load(Ordering::Relaxed),
+            );
+        }
+
+        header(
+            &mut buffer,
+            "unit3d_announce_store_size",
+            "gauge",
+            "Records held in memory, by store.",
+        );
+
+        let (torrents, peers) =
+            state
+                .stores
+                .torrents
+                .shards()
+                .iter()
+                .fold((0, 0), |(torrents, peers), shard| {
+                    let shard = shard.lock();
+
+                    (
+                        torrents + shard.len(),
+                        peers
+                            + shard
+                                .values()
+                                .map(|torrent| torrent.peers.len())
+                                .sum::<usize>(),
+                    )
+                });
+
+        let store_sizes = [
+            ("torrents", torrents),
+            ("peers", peers),
+            ("users", state.stores.users.read().len()),
+            ("groups", state.stores.groups.read().len()),
+            ("infohash2id", state.stores.infohash2id.read().len()),
+            ("passkey2id", state.stores.passkey2id.read().len()),
+            (
+                "connectable_ports",
+                state.stores.connectable_ports.read().len(),
+            ),
+            (
+                "freeleech_tokens",
+                state.stores.freeleech_tokens.read().len(),
+            ),
+            (
+                "personal_freeleeches",
+                state.stores.personal_freeleeches.read().len(),
+            ),
+            (
+                "featured_torrents",
+                state.stores.featured_torrents.read().len(),
+            ),
+        ];
+
+        for (store, len) in store_sizes {
+            sample(
+                &mut buffer,
+                "unit3d_announce_store_size",
+                &format!("store=\"{store}\""),
+                len,
+            );
+        }
+
+        buffer
+    }
+}
+
+pub async fn show(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.encode(&state),
+    )
+}
+
+/// Histogram with fixed bucket upper bounds. Observations are counted in the
+/// first bucket they fit in, and summed up into cumulative buckets when
+/// encoded.
+struct Histogram {
+    bounds: &'static [f64],
+    /// One more bucket than bounds, for observations greater than all bounds.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicF64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicF64::default(),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| sum + value);
+    }
+
+    fn encode(&self, buffer: &mut String, name: &str, labels: &str) {
+        let bucket_name = format!("{name}_bucket");
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+
+            sample(
+                buffer,
+                &bucket_name,
+                &format!("{labels}{separator}le=\"{bound}\""),
+                count,
+            );
+        }
+
+        // The count is summed up from the buckets, so that it's consistent
+        // with them even if observations were added while encoding.
+        if let Some(overflow_bucket) = self.buckets.last() {
+            count += overflow_bucket.load(Ordering::Relaxed);
+        }
+
+        sample(
+            buffer,
+            &bucket_name,
+            &format!("{labels}{separator}le=\"+Inf\""),
+            count,
+        );
+        sample(
+            buffer,
+            &format!("{name}_sum"),
+            labels,
+            self.sum.load(Ordering::Relaxed),
+        );
+        sample(buffer, &format!("{name}_count"), labels, count);
+    }
+}
+
+/// Writes the lines describing a metric, which precede its samples.
+fn header(buffer: &mut String, name: &str, kind: &str, help: &str) {
+    // Writing into a string can't fail
+    let _ = writeln!(buffer, "# HELP {name} {help}");
+    let _ = writeln!(buffer, "# TYPE {name} {kind}");
+}
+
+/// Writes a single sample of a metric.
+fn sample(buffer: &mut String, name: &str, labels: &str, value: impl Display) {
+    // Writing into a string can't fail
+    let _ = if labels.is_empty() {
+        writeln!(buffer, "{name} {value}")
+    } else {
+        writeln!(buffer, "{name}{{{labels}}} {value}")
+    };
+}
+
+fn record_type_label(record_type: &str) -> String {
+    format!("record_type=\"{}\"", record_type.replace(' ', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_encoding() {
+        let histogram = Histogram::new(&[1.0, 2.5]);
+
+        histogram.observe(0.5);
+        histogram.observe(1.0);
+        histogram.observe(2.0);
+        histogram.observe(3.0);
+
+        let mut buffer = String::new();
+        histogram.encode(&mut buffer, "duration", "record_type=\"peers\"");
+
+        assert_eq!(
+            buffer,
+            "duration_bucket{record_type=\"peers\",le=\"1\"} 2\n\
+             duration_bucket{record_type=\"peers\",le=\"2.5\"} 3\n\
+             duration_bucket{record_type=\"peers\",le=\"+Inf\"} 4\n\
+             duration_sum{record_type=\"peers\"} 6.5\n\
+             duration_count{record_type=\"peers\"} 4\n"
+        );
+    }
+}
@@ -1,4 +1,6 @@
 pub mod info_hash;
+pub mod info_hash_v2;
+pub mod info_hash_version;
 pub mod passkey;
 pub mod peer_id;
 pub mod torrent_status;
@@ -1,6 +1,6 @@
 use std::{fmt, ops::Deref, str::FromStr};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 use sqlx::{Database, Decode};
 
 use crate::utils::{hex_decode, hex_encode};
@@ -70,6 +70,15 @@ where
     }
 }
 
+impl Serialize for InfoHash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 impl Deref for InfoHash {
     type Target = [u8; 20];
 
@@ -0,0 +1,79 @@
+use std::{fmt, ops::Deref, str::FromStr};
+
+use crate::model::info_hash::InfoHash;
+use crate::utils::{hex_decode, hex_encode};
+
+use anyhow::{Context, Result, bail};
+
+/// SHA-256 info hash of a v2 (BEP 52) or hybrid torrent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub struct InfoHashV2(pub [u8; 32]);
+
+impl InfoHashV2 {
+    /// The first 20 bytes of the info hash, which clients use in place of
+    /// the full info hash when announcing.
+    pub fn truncate(&self) -> InfoHash {
+        let mut truncated = [0u8; 20];
+        truncated.copy_from_slice(&self.0[0..20]);
+
+        InfoHash(truncated)
+    }
+}
+
+impl FromStr for InfoHashV2 {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = s.as_bytes();
+        let mut out = [0u8; 32];
+
+        if bytes.len() != 64 {
+            bail!("`{s}` is not a valid v2 infohash.");
+        }
+
+        for pos in 0..32 {
+            out[pos] = hex_decode([bytes[pos * 2], bytes[pos * 2 + 1]])
+                .with_context(|| format!("`{s}` is not a valid v2 infohash."))?;
+        }
+
+        Ok(InfoHashV2(out))
+    }
+}
+
+impl From<[u8; 32]> for InfoHashV2 {
+    fn from(array: [u8; 32]) -> Self {
+        InfoHashV2(array)
+    }
+}
+
+impl TryFrom<&[u8]> for InfoHashV2 {
+    type Error = crate::error::DecodeError;
+
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Error> {
+        Ok(InfoHashV2(
+            slice
+                .try_into()
+                .or(Err(crate::error::DecodeError::InfoHash))?,
+        ))
+    }
+}
+
+impl fmt::Display for InfoHashV2 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes: Vec<u8> = vec![];
+
+        for pos in 0..32 {
+            bytes.extend(hex_encode(self.0[pos]));
+        }
+
+        fmt.write_str(&String::from_utf8_lossy(&bytes))
+    }
+}
+
+impl Deref for InfoHashV2 {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
@@ -0,0 +1,11 @@
+use serde::Serialize;
+
+/// Which hash of a torrent's info dictionary an info hash was derived from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[repr(u8)]
+pub enum InfoHashVersion {
+    /// SHA-1 info hash of a v1 or hybrid torrent.
+    V1 = 1,
+    /// SHA-256 info hash of a v2 or hybrid torrent, truncated to 20 bytes.
+    V2 = 2,
+}
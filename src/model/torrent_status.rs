@@ -34,7 +34,7 @@ pub enum TorrentStatus {
 }
 
 impl TorrentStatus {
-    fn from_i16(status: i16) -> Self {
+    pub fn from_i16(status: i16) -> Self {
         match status {
             0 => Self::Pending,
             1 => Self::Approved,
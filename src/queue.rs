@@ -1,33 +1,62 @@
-use std::{cmp::min, collections::VecDeque, hash::Hash, slice::Iter, sync::Arc, vec::IntoIter};
+use std::{
+    cmp::min,
+    collections::VecDeque,
+    hash::Hash,
+    mem,
+    path::Path,
+    slice::Iter,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    vec::IntoIter,
+};
 
 pub mod announce_update;
+pub mod client_mismatch_update;
+pub mod dead_letter;
 pub mod history_update;
 pub mod peer_update;
 pub mod torrent_update;
 pub mod unregistered_info_hash_update;
+pub mod upload_anomaly_update;
 pub mod user_update;
+pub mod wal;
 
-use crate::state::AppState;
+use crate::{snapshot::Encode, state::AppState};
+use anyhow::Result;
+use client_mismatch_update::ClientMismatchUpdate;
 use futures_util::future::join_all;
 use history_update::HistoryUpdate;
 use parking_lot::Mutex;
 use peer_update::PeerUpdate;
 use ringmap::RingMap;
+use serde::Serialize;
+use sqlx::mysql::MySqlDatabaseError;
 use tokio::{join, time::Instant};
 use torrent_update::TorrentUpdate;
-use tracing::info;
+use tracing::{error, info};
 use unregistered_info_hash_update::UnregisteredInfoHashUpdate;
+use upload_anomaly_update::UploadAnomalyUpdate;
 use user_update::UserUpdate;
+use wal::{Checkpoint, WriteAheadLog};
 
 /// Holds queued database updates
 pub struct Queues {
+    /// Announces aren't written to a write-ahead log. They're only an audit
+    /// trail that is dropped anyway once the queue is full, and logging
+    /// every announce would double the disk writes of the other queues.
     pub announces: Mutex<announce_update::Queue>,
+    pub client_mismatches: Mutex<Queue<client_mismatch_update::Index, ClientMismatchUpdate>>,
     pub histories: Mutex<Queue<history_update::Index, HistoryUpdate>>,
     pub peers: Mutex<Queue<peer_update::Index, PeerUpdate>>,
     pub torrents: Mutex<Queue<torrent_update::Index, TorrentUpdate>>,
     pub unregistered_info_hashes:
         Mutex<Queue<unregistered_info_hash_update::Index, UnregisteredInfoHashUpdate>>,
+    pub upload_anomalies: Mutex<Queue<upload_anomaly_update::Index, UploadAnomalyUpdate>>,
     pub users: Mutex<Queue<user_update::Index, UserUpdate>>,
+    /// Whether any queue was full as of the last flush.
+    is_full: AtomicBool,
 }
 
 impl Queues {
@@ -35,6 +64,14 @@ impl Queues {
     pub fn new() -> Queues {
         Queues {
             announces: Mutex::new(announce_update::Queue::new()),
+            client_mismatches: Mutex::new(Queue::<
+                client_mismatch_update::Index,
+                ClientMismatchUpdate,
+            >::new(QueueConfig {
+                max_bindings_per_flush: 65_535,
+                bindings_per_record: 6,
+                extra_bindings_per_flush: 0,
+            })),
             histories: Mutex::new(Queue::<history_update::Index, HistoryUpdate>::new(
                 QueueConfig {
                     max_bindings_per_flush: 65_535,
@@ -45,7 +82,7 @@ impl Queues {
             )),
             peers: Mutex::new(Queue::<peer_update::Index, PeerUpdate>::new(QueueConfig {
                 max_bindings_per_flush: 65_535,
-                bindings_per_record: 15,
+                bindings_per_record: 16,
                 extra_bindings_per_flush: 0,
             })),
             torrents: Mutex::new(Queue::<torrent_update::Index, TorrentUpdate>::new(
@@ -60,37 +97,164 @@ impl Queues {
                 UnregisteredInfoHashUpdate,
             >::new(QueueConfig {
                 max_bindings_per_flush: 65_535,
-                bindings_per_record: 4,
+                bindings_per_record: 5,
                 extra_bindings_per_flush: 0,
             })),
+            upload_anomalies: Mutex::new(
+                Queue::<upload_anomaly_update::Index, UploadAnomalyUpdate>::new(QueueConfig {
+                    max_bindings_per_flush: 65_535,
+                    bindings_per_record: 11,
+                    extra_bindings_per_flush: 0,
+                }),
+            ),
             users: Mutex::new(Queue::<user_update::Index, UserUpdate>::new(QueueConfig {
                 max_bindings_per_flush: 65_535,
                 bindings_per_record: 9,
                 extra_bindings_per_flush: 0,
             })),
+            is_full: AtomicBool::new(false),
         }
     }
 
+    /// Limits the amount of records each queue can hold. Histories and users
+    /// carry upload and download credit, so their updates are always
+    /// dead-lettered instead of dropped once their queue is full.
+    pub fn set_limits(&self, max_lengths: &MaxLengths, full_policy: FullPolicy) {
+        self.announces.lock().set_max_len(max_lengths.announces);
+        self.client_mismatches
+            .lock()
+            .set_limit(max_lengths.client_mismatches, full_policy);
+        self.histories
+            .lock()
+            .set_limit(max_lengths.histories, FullPolicy::DeadLetter);
+        self.peers.lock().set_limit(max_lengths.peers, full_policy);
+        self.torrents
+            .lock()
+            .set_limit(max_lengths.torrents, full_policy);
+        self.unregistered_info_hashes
+            .lock()
+            .set_limit(max_lengths.unregistered_info_hashes, full_policy);
+        self.upload_anomalies
+            .lock()
+            .set_limit(max_lengths.upload_anomalies, full_policy);
+        self.users
+            .lock()
+            .set_limit(max_lengths.users, FullPolicy::DeadLetter);
+    }
+
+    /// Returns true if updates of new records weren't queued during the last
+    /// flush interval because a queue was full.
+    pub fn is_full(&self) -> bool {
+        self.is_full.load(Ordering::Relaxed)
+    }
+
+    /// Replays the write-ahead logs in the directory into the queues and
+    /// starts logging every upserted update to them. Returns the amount of
+    /// replayed updates.
+    pub async fn replay_logs(
+        &mut self,
+        directory: &Path,
+        dead_letter_path: Option<&Path>,
+    ) -> Result<usize> {
+        std::fs::create_dir_all(directory)?;
+
+        Ok(self
+            .client_mismatches
+            .get_mut()
+            .replay_log(directory, "client_mismatches", dead_letter_path)
+            .await?
+            + self
+                .histories
+                .get_mut()
+                .replay_log(directory, "histories", dead_letter_path)
+                .await?
+            + self
+                .peers
+                .get_mut()
+                .replay_log(directory, "peers", dead_letter_path)
+                .await?
+            + self
+                .torrents
+                .get_mut()
+                .replay_log(directory, "torrents", dead_letter_path)
+                .await?
+            + self
+                .unregistered_info_hashes
+                .get_mut()
+                .replay_log(directory, "unregistered_info_hashes", dead_letter_path)
+                .await?
+            + self
+                .upload_anomalies
+                .get_mut()
+                .replay_log(directory, "upload_anomalies", dead_letter_path)
+                .await?
+            + self
+                .users
+                .get_mut()
+                .replay_log(directory, "users", dead_letter_path)
+                .await?)
+    }
+
     /// Send queued updates to mysql database
     pub async fn flush(&self, state: &Arc<AppState>) {
-        join!(
+        let (
+            _,
+            client_mismatches,
+            histories,
+            peers,
+            torrents,
+            users,
+            unregistered_info_hashes,
+            upload_anomalies,
+        ) = join!(
             self.flush_announce_updates(state),
+            self.client_mismatches.flush(state, "client mismatches"),
             self.histories.flush(state, "histories"),
             self.peers.flush(state, "peers"),
             self.torrents.flush(state, "torrents"),
             self.users.flush(state, "users"),
             self.unregistered_info_hashes
                 .flush(state, "unregistered info hashes"),
+            self.upload_anomalies.flush(state, "upload anomalies"),
+        );
+
+        self.is_full.store(
+            client_mismatches
+                || histories
+                || peers
+                || torrents
+                || users
+                || unregistered_info_hashes
+                || upload_anomalies,
+            Ordering::Relaxed,
         );
     }
 
     /// Send announce updates to mysql database
     async fn flush_announce_updates(&self, state: &Arc<AppState>) {
-        let announce_update_batch = self.announces.lock().take_batch();
+        let announce_update_batch = {
+            let mut queue = self.announces.lock();
+            let dropped_updates = queue.take_dropped_updates();
+
+            if dropped_updates > 0 {
+                error!("Dropped the {dropped_updates} oldest announces since the queue is full.");
+                state
+                    .metrics
+                    .record_dropped_updates("announces", dropped_updates);
+            }
+
+            queue.take_batch()
+        };
         let start = Instant::now();
         let len = announce_update_batch.len();
         let result = announce_update_batch.flush_to_db(state).await;
-        let elapsed = start.elapsed().as_millis();
+        let elapsed = start.elapsed();
+
+        state
+            .metrics
+            .record_flush("announces", elapsed, result.as_ref().ok().copied());
+
+        let elapsed = elapsed.as_millis();
 
         match result {
             Ok(_) => {
@@ -107,19 +271,79 @@ impl Queues {
         }
     }
 
+    /// Amount of queued updates of each record type.
+    pub fn lengths(&self) -> [(&'static str, usize); 8] {
+        [
+            ("announces", self.announces.lock().len()),
+            ("client mismatches", self.client_mismatches.lock().len()),
+            ("histories", self.histories.lock().len()),
+            ("peers", self.peers.lock().len()),
+            ("torrents", self.torrents.lock().len()),
+            (
+                "unregistered info hashes",
+                self.unregistered_info_hashes.lock().len(),
+            ),
+            ("upload anomalies", self.upload_anomalies.lock().len()),
+            ("users", self.users.lock().len()),
+        ]
+    }
+
     pub fn are_not_empty(&self) -> bool {
         !self.announces.lock().is_empty()
+            || self.client_mismatches.lock().is_not_empty()
             || self.histories.lock().is_not_empty()
             || self.peers.lock().is_not_empty()
             || self.torrents.lock().is_not_empty()
             || self.users.lock().is_not_empty()
             || self.unregistered_info_hashes.lock().is_not_empty()
+            || self.upload_anomalies.lock().is_not_empty()
     }
 }
 
+/// Max amount of records held by each queue.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxLengths {
+    pub announces: usize,
+    pub client_mismatches: usize,
+    pub histories: usize,
+    pub peers: usize,
+    pub torrents: usize,
+    pub unregistered_info_hashes: usize,
+    pub upload_anomalies: usize,
+    pub users: usize,
+}
+
+/// What happens to updates of records that aren't queued yet once a queue is
+/// full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullPolicy {
+    /// The updates are dropped.
+    Drop,
+    /// The updates are written to the dead letter file during the next flush,
+    /// so that they can be applied manually.
+    DeadLetter,
+}
+
+/// Max exponent of the amount of flushes skipped after consecutive failed
+/// flushes, so that retries happen at least every 64 flushes.
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
 pub struct Queue<K, V> {
     records: RingMap<K, V>,
     config: QueueConfig,
+    log: Option<WriteAheadLog>,
+    /// Consecutive flushes that failed to update the database.
+    failed_flushes: u32,
+    /// Flushes left to skip before retrying after a failed flush.
+    skipped_flushes: u32,
+    /// Max amount of queued records, after which updates of records that
+    /// aren't queued yet are handled according to the full policy.
+    max_len: usize,
+    full_policy: FullPolicy,
+    /// Updates dropped since the queue was last flushed.
+    dropped_updates: u64,
+    /// Updates to be written to the dead letter file during the next flush.
+    overflow: Vec<(K, V)>,
 }
 
 pub struct QueueConfig {
@@ -143,19 +367,58 @@ impl QueueConfig {
 
 impl<K, V> Queue<K, V>
 where
-    K: Hash + Eq + Ord,
-    V: Clone + Mergeable,
+    K: Hash + Eq + Ord + Encode,
+    V: Clone + Mergeable + Encode,
 {
     /// Initialize a new queue
     pub fn new(config: QueueConfig) -> Queue<K, V> {
         Self {
             records: RingMap::new(),
             config,
+            log: None,
+            failed_flushes: 0,
+            skipped_flushes: 0,
+            max_len: usize::MAX,
+            full_policy: FullPolicy::DeadLetter,
+            dropped_updates: 0,
+            overflow: Vec::new(),
         }
     }
 
+    fn set_limit(&mut self, max_len: usize, full_policy: FullPolicy) {
+        self.max_len = max_len;
+        self.full_policy = full_policy;
+    }
+
     /// Upsert a single update into the queue
     pub fn upsert(&mut self, key: K, value: V) {
+        // Updates of queued records are still merged in place once full,
+        // since they don't take up more memory.
+        let is_full = self.records.len() >= self.max_len && !self.records.contains_key(&key);
+
+        if is_full && self.full_policy == FullPolicy::Drop {
+            self.dropped_updates += 1;
+
+            return;
+        }
+
+        // Overflowing updates are logged too, so that they aren't lost if
+        // the tracker stops before they're dead-lettered.
+        if let Some(log) = &mut self.log
+            && let Err(e) = log.append(&key, &value)
+        {
+            error!("{e:#}");
+        }
+
+        if is_full {
+            self.overflow.push((key, value));
+        } else {
+            self.insert(key, value);
+        }
+    }
+
+    /// Upsert a single update into the queue without logging it
+    fn insert(&mut self, key: K, value: V) {
         self.records
             .entry(key)
             .and_modify(|update| update.merge(&value))
@@ -185,14 +448,123 @@ where
         batches
     }
 
-    /// Bulk upsert a batch into the end of the queue
+    /// Bulk upsert a batch that failed to flush into the end of the queue.
+    /// The batch was truncated from the log before it was flushed, so it's
+    /// logged again.
     fn upsert_batch(&mut self, batch: Batch<K, V>) {
-        batch.into_iter().for_each(|(k, v)| self.upsert(k, v));
+        for (key, value) in batch.into_iter() {
+            if let Some(log) = &mut self.log
+                && let Err(e) = log.append(&key, &value)
+            {
+                error!("{e:#}");
+            }
+
+            self.insert(key, value);
+        }
+    }
+
+    /// Replays the write-ahead log with the name in the directory into the
+    /// queue and logs all further upserts to it. Updates of an interrupted
+    /// flush may already be in the database, so they're dead-lettered
+    /// instead of being replayed. Returns the amount of replayed updates.
+    async fn replay_log(
+        &mut self,
+        directory: &Path,
+        name: &'static str,
+        dead_letter_path: Option<&Path>,
+    ) -> Result<usize>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let replay = WriteAheadLog::read(directory, name)?;
+        let len = replay.queued.len();
+
+        replay
+            .queued
+            .into_iter()
+            .for_each(|(k, v)| self.insert(k, v));
+
+        if !replay.interrupted.is_empty() {
+            let record_type = name.replace('_', " ");
+
+            error!(
+                "Dead-lettering {} {record_type} of a flush interrupted by the tracker stopping.",
+                replay.interrupted.len()
+            );
+
+            let interrupted = replay
+                .interrupted
+                .into_iter()
+                .map(|(index, update)| {
+                    let error = "Flush was interrupted, so the update may already be applied.";
+
+                    (index, update, error.to_string())
+                })
+                .collect::<Vec<_>>();
+
+            dead_letter::write(dead_letter_path, &record_type, &interrupted).await;
+        }
+
+        // Supersedes the flush segment, so that it's dead-lettered only once.
+        self.log = Some(WriteAheadLog::create(
+            directory.to_path_buf(),
+            name,
+            self.records.iter(),
+        )?);
+
+        Ok(len)
+    }
+
+    /// Returns true if the flush has to be skipped to back off after failed
+    /// flushes.
+    fn is_backing_off(&mut self) -> bool {
+        if self.skipped_flushes == 0 {
+            return false;
+        }
+
+        self.skipped_flushes -= 1;
+
+        true
+    }
+
+    /// Exponentially increases the amount of flushes skipped after each
+    /// consecutive failed flush, and resets it after a successful flush.
+    fn back_off(&mut self, has_failed: bool) {
+        if has_failed {
+            self.skipped_flushes = (1 << self.failed_flushes.min(MAX_BACKOFF_EXPONENT)) - 1;
+            self.failed_flushes = self.failed_flushes.saturating_add(1);
+        } else {
+            self.failed_flushes = 0;
+        }
+    }
+
+    /// Rotates the write-ahead log before the batches taken from the queue
+    /// are flushed. The returned checkpoint of the updates still queued has
+    /// to be written to truncate the log down to them before the batches are
+    /// sent to the database.
+    fn rotate_log(&mut self, batches: &VecDeque<Batch<K, V>>) -> Result<Option<Checkpoint>> {
+        self.log
+            .as_mut()
+            .map(|log| {
+                log.rotate(
+                    self.records.iter(),
+                    batches
+                        .iter()
+                        .flat_map(Batch::iter)
+                        .map(|(key, value)| (key, value)),
+                )
+            })
+            .transpose()
     }
 
     pub fn is_not_empty(&self) -> bool {
         !self.records.is_empty()
     }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
 }
 
 pub trait Mergeable {
@@ -201,22 +573,103 @@ pub trait Mergeable {
 }
 
 pub trait MutexQueueExt {
-    async fn flush<'a>(&self, state: &Arc<AppState>, record_type: &'a str);
+    /// Flushes the queue and returns true if updates weren't queued because
+    /// the queue was full since the last flush.
+    async fn flush<'a>(&self, state: &Arc<AppState>, record_type: &'a str) -> bool;
 }
 
 impl<K, V> MutexQueueExt for Mutex<Queue<K, V>>
 where
-    K: Hash + Eq + Ord,
-    V: Clone + Mergeable,
+    K: Hash + Eq + Ord + Encode + Serialize,
+    V: Clone + Mergeable + Encode + Serialize,
     Batch<K, V>: Flushable<V>,
 {
-    async fn flush<'a>(&self, state: &Arc<AppState>, record_type: &'a str) {
-        let batches = self.lock().take_batches(state);
+    async fn flush<'a>(&self, state: &Arc<AppState>, record_type: &'a str) -> bool {
+        let (batches, dropped_updates, overflow, is_backing_off, checkpoint) = {
+            let mut queue = self.lock();
+            let dropped_updates = mem::take(&mut queue.dropped_updates);
+            let overflow = mem::take(&mut queue.overflow);
+
+            if dropped_updates > 0 {
+                error!("Dropped {dropped_updates} {record_type} since the queue is full.");
+                state
+                    .metrics
+                    .record_dropped_updates(record_type, dropped_updates);
+            }
+
+            let is_backing_off = queue.is_backing_off();
+            let batches = if is_backing_off {
+                VecDeque::new()
+            } else {
+                queue.take_batches(state)
+            };
+
+            // The log is truncated before the updates are sent to the
+            // database, so that they aren't replayed and applied twice if the
+            // tracker stops after the database applied them.
+            let checkpoint = if batches.is_empty() && overflow.is_empty() {
+                Ok(None)
+            } else {
+                queue.rotate_log(&batches)
+            };
+
+            (
+                batches,
+                dropped_updates,
+                overflow,
+                is_backing_off,
+                checkpoint,
+            )
+        };
+
+        let is_full = dropped_updates > 0 || !overflow.is_empty();
+
+        if !overflow.is_empty() {
+            error!(
+                "Dead-lettering {} {record_type} since the queue is full.",
+                overflow.len()
+            );
+
+            let dead_letter_path = state.config.load().dead_letter_path.clone();
+            let overflow = overflow
+                .into_iter()
+                .map(|(index, update)| (index, update, "Queue is full.".to_string()))
+                .collect::<Vec<_>>();
+
+            dead_letter::write(dead_letter_path.as_deref(), record_type, &overflow).await;
+        }
+
+        // The checkpoint is written and synced to disk without holding the
+        // lock, so that announces aren't blocked by disk I/O.
+        let result = match checkpoint {
+            Ok(Some(checkpoint)) => tokio::task::spawn_blocking(|| checkpoint.write())
+                .await
+                .unwrap_or_else(|e| Err(e.into())),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("{e:#}");
+
+            // The updates are still in the previous segments of the log.
+            let mut queue = self.lock();
+
+            batches.into_iter().for_each(|batch| {
+                batch.into_iter().for_each(|(k, v)| queue.insert(k, v));
+            });
+
+            return is_full;
+        }
+
+        if is_backing_off {
+            return is_full;
+        }
 
         if batches.is_empty() {
             info!("Upserted 0 {record_type} in 0 ms.");
 
-            return;
+            return is_full;
         }
 
         let tasks = batches
@@ -224,26 +677,144 @@ where
             .map(|batch| async move {
                 let start = Instant::now();
                 let len = batch.len();
-                let result = batch.flush_to_db(state).await;
+                let outcome = flush_bisecting(state, record_type, batch).await;
                 let elapsed = start.elapsed().as_millis();
 
-                (len, elapsed, result, batch)
+                (len, elapsed, outcome)
             })
             .collect::<Vec<_>>();
 
         let results = join_all(tasks).await;
+        let mut has_failed = false;
+        let mut rejected = Vec::new();
 
-        for (len, elapsed, result, batch) in results {
-            match result {
-                Ok(_) => {
+        for (len, elapsed, outcome) in results {
+            match outcome.error {
+                None => {
                     info!("Upserted {len} {record_type} in {elapsed} ms.");
                 }
-                Err(e) => {
-                    info!("Failed to update {len} {record_type} after {elapsed} ms: {e}",);
-                    self.lock().upsert_batch(batch);
+                Some(e) => {
+                    let failed_len: usize = outcome.failed.iter().map(Batch::len).sum();
+
+                    info!("Failed to update {failed_len} {record_type} after {elapsed} ms: {e}",);
+                    has_failed = true;
+
+                    let mut queue = self.lock();
+
+                    outcome
+                        .failed
+                        .into_iter()
+                        .for_each(|batch| queue.upsert_batch(batch));
                 }
             }
+
+            rejected.extend(outcome.rejected);
+        }
+
+        if !rejected.is_empty() {
+            error!("Database rejected {} {record_type}.", rejected.len());
+
+            let dead_letter_path = state.config.load().dead_letter_path.clone();
+
+            dead_letter::write(dead_letter_path.as_deref(), record_type, &rejected).await;
         }
+
+        let mut queue = self.lock();
+
+        queue.back_off(has_failed);
+
+        if let Some(log) = &queue.log
+            && let Err(e) = log.complete_flush()
+        {
+            error!("{e:#}");
+        }
+
+        is_full
+    }
+}
+
+/// Result of flushing a batch of updates.
+struct FlushOutcome<K, V> {
+    /// Updates that couldn't be flushed and have to be retried.
+    failed: Vec<Batch<K, V>>,
+    /// Error that caused the updates to fail, if any.
+    error: Option<sqlx::Error>,
+    /// Updates rejected by the database, along with the error message.
+    rejected: Vec<(K, V, String)>,
+}
+
+/// Flushes the batch to the database. If the database rejects the batch, it's
+/// split in halves that are flushed separately, until the updates that caused
+/// the rejection are isolated, so that they don't block the rest of the queue
+/// indefinitely.
+async fn flush_bisecting<K, V>(
+    state: &Arc<AppState>,
+    record_type: &str,
+    batch: Batch<K, V>,
+) -> FlushOutcome<K, V>
+where
+    Batch<K, V>: Flushable<V>,
+{
+    let mut outcome = FlushOutcome {
+        failed: Vec::new(),
+        error: None,
+        rejected: Vec::new(),
+    };
+    let mut pending = vec![batch];
+
+    while let Some(batch) = pending.pop() {
+        let start = Instant::now();
+        let result = batch.flush_to_db(state).await;
+
+        state
+            .metrics
+            .record_flush(record_type, start.elapsed(), result.as_ref().ok().copied());
+
+        match result {
+            Ok(_) => (),
+            Err(e) if is_rejection(&e) && batch.len() > 1 => {
+                let (first_half, second_half) = batch.bisect();
+
+                pending.push(second_half);
+                pending.push(first_half);
+            }
+            Err(e) if is_rejection(&e) => {
+                let message = e.to_string();
+
+                outcome.rejected.extend(
+                    batch
+                        .into_iter()
+                        .map(|(index, update)| (index, update, message.clone())),
+                );
+            }
+            Err(e) => {
+                // The database is unavailable, so the remaining batches would
+                // fail too.
+                outcome.failed.push(batch);
+                outcome.failed.append(&mut pending);
+                outcome.error = Some(e);
+            }
+        }
+    }
+
+    outcome
+}
+
+/// Returns true if the database refused the updates themselves, such as when
+/// they violate a constraint. Any other error, such as a lost connection, a
+/// read-only server or a missing table, is treated as transient so that the
+/// whole batch is retried instead of dead-lettered.
+fn is_rejection(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => matches!(
+            e.try_downcast_ref::<MySqlDatabaseError>()
+                .map(MySqlDatabaseError::number),
+            // Column can't be null, duplicate entry, out of range value,
+            // incorrect value, data too long, and foreign key constraint
+            // failures
+            Some(1048 | 1062 | 1264 | 1366 | 1406 | 1451 | 1452)
+        ),
+        _ => false,
     }
 }
 
@@ -265,6 +836,13 @@ impl<'a, K, V> Batch<K, V> {
     fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// Splits the batch into two halves.
+    fn bisect(mut self) -> (Batch<K, V>, Batch<K, V>) {
+        let second_half = self.0.split_off(self.0.len() / 2);
+
+        (self, Batch(second_half))
+    }
 }
 
 pub trait Flushable<T> {
@@ -274,3 +852,69 @@ pub trait Flushable<T> {
     /// or doesn't use too many bindings
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let mut queue = Queue::<user_update::Index, UserUpdate>::new(QueueConfig {
+            max_bindings_per_flush: 65_535,
+            bindings_per_record: 9,
+            extra_bindings_per_flush: 0,
+        });
+        let mut skipped_flushes = Vec::new();
+
+        for _ in 0..9 {
+            queue.back_off(true);
+
+            let mut skipped = 0;
+
+            while queue.is_backing_off() {
+                skipped += 1;
+            }
+
+            skipped_flushes.push(skipped);
+        }
+
+        assert_eq!(skipped_flushes, [0, 1, 3, 7, 15, 31, 63, 63, 63]);
+
+        queue.back_off(false);
+        queue.back_off(true);
+
+        assert!(!queue.is_backing_off());
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut queue = Queue::<user_update::Index, UserUpdate>::new(QueueConfig {
+            max_bindings_per_flush: 65_535,
+            bindings_per_record: 9,
+            extra_bindings_per_flush: 0,
+        });
+        let update = UserUpdate {
+            uploaded_delta: 1,
+            downloaded_delta: 0,
+        };
+
+        queue.set_limit(1, FullPolicy::Drop);
+        queue.upsert(user_update::Index { user_id: 1 }, update.clone());
+        queue.upsert(user_update::Index { user_id: 2 }, update.clone());
+        queue.upsert(user_update::Index { user_id: 1 }, update.clone());
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.dropped_updates, 1);
+        assert_eq!(
+            queue.records[&user_update::Index { user_id: 1 }].uploaded_delta,
+            2
+        );
+
+        queue.set_limit(1, FullPolicy::DeadLetter);
+        queue.upsert(user_update::Index { user_id: 3 }, update);
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.dropped_updates, 1);
+        assert_eq!(queue.overflow.len(), 1);
+    }
+}
@@ -1,15 +1,19 @@
-use std::{
-    cmp::min,
-    ops::{Deref, DerefMut},
-    sync::Arc,
-};
+use std::{cmp::min, collections::VecDeque, ops::Deref, sync::Arc};
 
 use chrono::{DateTime, Utc};
 use sqlx::{MySql, QueryBuilder};
 
 use crate::{announce::Event, model::peer_id::PeerId, state::AppState};
 
-pub struct Queue(pub Vec<AnnounceUpdate>);
+pub struct Queue {
+    records: VecDeque<AnnounceUpdate>,
+    /// Max amount of queued announces, after which the oldest are dropped.
+    max_len: usize,
+    /// Announces dropped since the queue was last flushed.
+    dropped_updates: u64,
+}
+
+pub struct Batch(Vec<AnnounceUpdate>);
 
 #[derive(Clone)]
 pub struct AnnounceUpdate {
@@ -29,11 +33,16 @@ pub struct AnnounceUpdate {
 
 impl Queue {
     pub fn new() -> Queue {
-        Queue(Vec::new())
+        Queue {
+            records: VecDeque::new(),
+            max_len: usize::MAX,
+            dropped_updates: 0,
+        }
     }
 
     pub fn upsert(&mut self, new: AnnounceUpdate) {
-        self.push(new);
+        self.records.push_back(new);
+        self.drop_oldest();
     }
 
     /// Determine the max amount of announce records that can be inserted at
@@ -50,17 +59,55 @@ impl Queue {
 
     /// Take a portion of the announce updates small enough to be inserted into
     /// the database.
-    pub fn take_batch(&mut self) -> Queue {
-        let len = self.len();
+    pub fn take_batch(&mut self) -> Batch {
+        let len = self.records.len();
+
+        Batch(
+            self.records
+                .drain(0..min(Queue::announce_limit(), len))
+                .collect(),
+        )
+    }
+
+    /// Merge a announce update batch back into the start of the queue
+    pub fn upsert_batch(&mut self, batch: Batch) {
+        batch
+            .0
+            .into_iter()
+            .rev()
+            .for_each(|announce_update| self.records.push_front(announce_update));
+        self.drop_oldest();
+    }
+
+    /// Drops the oldest announces until the queue fits its max length, so
+    /// that it doesn't grow without bound while the database is unavailable.
+    fn drop_oldest(&mut self) {
+        while self.records.len() > self.max_len {
+            self.records.pop_front();
+            self.dropped_updates += 1;
+        }
+    }
+
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+        self.drop_oldest();
+    }
 
-        Queue(self.drain(0..min(Queue::announce_limit(), len)).collect())
+    /// Returns the amount of announces dropped since the last call.
+    pub fn take_dropped_updates(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped_updates)
     }
 
-    /// Merge a announce update batch into this announce update batch
-    pub fn upsert_batch(&mut self, batch: Queue) {
-        self.extend(batch.0);
+    pub fn len(&self) -> usize {
+        self.records.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl Batch {
     /// Flushes announce updates to the mysql db
     pub async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         let len = self.len();
@@ -120,16 +167,10 @@ impl Queue {
     }
 }
 
-impl Deref for Queue {
+impl Deref for Batch {
     type Target = Vec<AnnounceUpdate>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
-
-impl DerefMut for Queue {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use crate::{
+    model::peer_id::PeerId,
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{MySql, QueryBuilder};
+
+use super::{Flushable, Mergeable};
+
+// Fields must be in same order as database primary key
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct Index {
+    pub user_id: u32,
+    pub torrent_id: u32,
+    pub peer_id: PeerId,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ClientMismatchUpdate {
+    /// User agent disagreeing with the client identified by the peer id.
+    pub user_agent: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Mergeable for ClientMismatchUpdate {
+    fn merge(&mut self, new: &Self) {
+        if new.updated_at > self.updated_at {
+            self.user_agent = new.user_agent.clone();
+            self.updated_at = new.updated_at;
+        }
+
+        self.created_at = std::cmp::min(self.created_at, new.created_at);
+    }
+}
+
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_id.encode(buffer);
+        self.torrent_id.encode(buffer);
+        self.peer_id.0.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            user_id: Encode::decode(reader)?,
+            torrent_id: Encode::decode(reader)?,
+            peer_id: PeerId(Encode::decode(reader)?),
+        })
+    }
+}
+
+impl Encode for ClientMismatchUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_agent.encode(buffer);
+        self.created_at.encode(buffer);
+        self.updated_at.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(ClientMismatchUpdate {
+            user_agent: Encode::decode(reader)?,
+            created_at: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Flushable<ClientMismatchUpdate> for super::Batch<Index, ClientMismatchUpdate> {
+    async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+
+        let mut query_builder: QueryBuilder<MySql> = QueryBuilder::new(
+            r#"
+                INSERT INTO
+                    client_mismatches(
+                        user_id,
+                        torrent_id,
+                        peer_id,
+                        user_agent,
+                        created_at,
+                        updated_at
+                    )
+            "#,
+        );
+
+        query_builder
+            // Trailing space required before the push values function
+            // Leading space required after the push values function
+            .push_values(self.iter(), |mut bind, (index, client_mismatch_update)| {
+                bind.push_bind(index.user_id)
+                    .push_bind(index.torrent_id)
+                    .push_bind(index.peer_id.to_vec())
+                    .push_bind(client_mismatch_update.user_agent.as_str())
+                    .push_bind(client_mismatch_update.created_at)
+                    .push_bind(client_mismatch_update.updated_at);
+            })
+            // Mysql 8.0.20 deprecates use of VALUES() so will have to update it eventually to use aliases instead
+            // However, Mariadb doesn't yet support aliases
+            .push(
+                r#"
+                ON DUPLICATE KEY UPDATE
+                    user_agent = VALUES(user_agent),
+                    updated_at = VALUES(updated_at)
+            "#,
+            );
+
+        query_builder
+            .build()
+            .persistent(false)
+            .execute(&state.pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+}
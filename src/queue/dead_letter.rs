@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::json;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tracing::error;
+
+/// Writes updates that couldn't be applied, along with the reason they
+/// weren't, as JSON lines to the dead letter file for later inspection. The
+/// updates are logged instead if no file is configured or it can't be written
+/// to.
+pub async fn write<K: Serialize, V: Serialize>(
+    path: Option<&Path>,
+    record_type: &str,
+    records: &[(K, V, String)],
+) {
+    let rejected_at = Utc::now();
+    let mut lines = String::new();
+
+    for (index, update, error) in records {
+        lines.push_str(
+            &json!({
+                "rejected_at": rejected_at,
+                "record_type": record_type,
+                "error": error,
+                "index": index,
+                "update": update,
+            })
+            .to_string(),
+        );
+        lines.push('\n');
+    }
+
+    if let Some(path) = path {
+        match append(path, &lines).await {
+            Ok(()) => return,
+            Err(e) => error!("{e:#}"),
+        }
+    }
+
+    lines.lines().for_each(|line| error!("{line}"));
+}
+
+async fn append(path: &Path, lines: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed opening dead letter file {path:?}."))?;
+
+    file.write_all(lines.as_bytes())
+        .await
+        .with_context(|| format!("Failed writing to dead letter file {path:?}."))
+}
@@ -1,20 +1,25 @@
 use std::sync::Arc;
 
+use anyhow::Result;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{MySql, QueryBuilder};
 
-use crate::state::AppState;
+use crate::{
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
 
 use super::{Flushable, Mergeable};
 
 // Fields must be in same order as database primary key
-#[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct Index {
     pub user_id: u32,
     pub torrent_id: u32,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct HistoryUpdate {
     pub user_agent: String,
     pub is_active: bool,
@@ -48,6 +53,56 @@ impl Mergeable for HistoryUpdate {
     }
 }
 
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_id.encode(buffer);
+        self.torrent_id.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            user_id: Encode::decode(reader)?,
+            torrent_id: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for HistoryUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_agent.encode(buffer);
+        self.is_active.encode(buffer);
+        self.is_seeder.encode(buffer);
+        self.is_immune.encode(buffer);
+        self.uploaded.encode(buffer);
+        self.downloaded.encode(buffer);
+        self.uploaded_delta.encode(buffer);
+        self.downloaded_delta.encode(buffer);
+        self.credited_uploaded_delta.encode(buffer);
+        self.credited_downloaded_delta.encode(buffer);
+        self.completed_at.encode(buffer);
+        self.created_at.encode(buffer);
+        self.updated_at.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(HistoryUpdate {
+            user_agent: Encode::decode(reader)?,
+            is_active: Encode::decode(reader)?,
+            is_seeder: Encode::decode(reader)?,
+            is_immune: Encode::decode(reader)?,
+            uploaded: Encode::decode(reader)?,
+            downloaded: Encode::decode(reader)?,
+            uploaded_delta: Encode::decode(reader)?,
+            downloaded_delta: Encode::decode(reader)?,
+            credited_uploaded_delta: Encode::decode(reader)?,
+            credited_downloaded_delta: Encode::decode(reader)?,
+            completed_at: Encode::decode(reader)?,
+            created_at: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+        })
+    }
+}
+
 impl Flushable<HistoryUpdate> for super::Batch<Index, HistoryUpdate> {
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         if self.is_empty() {
@@ -1,20 +1,26 @@
 use std::{net::IpAddr, sync::Arc};
 
-use crate::{model::peer_id::PeerId, state::AppState};
+use crate::{
+    model::peer_id::PeerId,
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
+use anyhow::Result;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{MySql, QueryBuilder};
 
 use super::{Flushable, Mergeable};
 
 // Fields must be in same order as database primary key
-#[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct Index {
     pub user_id: u32,
     pub torrent_id: u32,
     pub peer_id: PeerId,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct PeerUpdate {
     pub ip: std::net::IpAddr,
     pub port: u16,
@@ -28,6 +34,7 @@ pub struct PeerUpdate {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub connectable: bool,
+    pub is_webrtc: bool,
 }
 
 impl Mergeable for PeerUpdate {
@@ -44,18 +51,75 @@ impl Mergeable for PeerUpdate {
             self.left = new.left;
             self.updated_at = new.updated_at;
             self.connectable = new.connectable;
+            self.is_webrtc = new.is_webrtc;
         }
 
         self.created_at = std::cmp::min(self.created_at, new.created_at);
     }
 }
 
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_id.encode(buffer);
+        self.torrent_id.encode(buffer);
+        self.peer_id.0.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            user_id: Encode::decode(reader)?,
+            torrent_id: Encode::decode(reader)?,
+            peer_id: PeerId(Encode::decode(reader)?),
+        })
+    }
+}
+
+impl Encode for PeerUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.ip.encode(buffer);
+        self.port.encode(buffer);
+        self.agent.encode(buffer);
+        self.uploaded.encode(buffer);
+        self.downloaded.encode(buffer);
+        self.is_active.encode(buffer);
+        self.is_seeder.encode(buffer);
+        self.is_visible.encode(buffer);
+        self.left.encode(buffer);
+        self.created_at.encode(buffer);
+        self.updated_at.encode(buffer);
+        self.connectable.encode(buffer);
+        self.is_webrtc.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(PeerUpdate {
+            ip: Encode::decode(reader)?,
+            port: Encode::decode(reader)?,
+            agent: Encode::decode(reader)?,
+            uploaded: Encode::decode(reader)?,
+            downloaded: Encode::decode(reader)?,
+            is_active: Encode::decode(reader)?,
+            is_seeder: Encode::decode(reader)?,
+            is_visible: Encode::decode(reader)?,
+            left: Encode::decode(reader)?,
+            created_at: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+            connectable: Encode::decode(reader)?,
+            is_webrtc: Encode::decode(reader)?,
+        })
+    }
+}
+
 impl Flushable<PeerUpdate> for super::Batch<Index, PeerUpdate> {
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         if self.is_empty() {
             return Ok(0);
         }
 
+        // The `webrtc` column is only required once the websocket tracker is
+        // enabled
+        let is_websocket_tracker_enabled = state.config.load().is_websocket_tracker_enabled;
+
         let mut query_builder: QueryBuilder<MySql> = QueryBuilder::new(
             r#"
                 INSERT INTO
@@ -75,10 +139,15 @@ impl Flushable<PeerUpdate> for super::Batch<Index, PeerUpdate> {
                         torrent_id,
                         user_id,
                         connectable
-                    )
             "#,
         );
 
+        if is_websocket_tracker_enabled {
+            query_builder.push(", webrtc");
+        }
+
+        query_builder.push(") ");
+
         query_builder
             // Trailing space required before the push values function
             // Leading space required after the push values function
@@ -101,6 +170,10 @@ impl Flushable<PeerUpdate> for super::Batch<Index, PeerUpdate> {
                     .push_bind(index.torrent_id)
                     .push_bind(index.user_id)
                     .push_bind(peer_update.connectable);
+
+                if is_websocket_tracker_enabled {
+                    bind.push_bind(peer_update.is_webrtc);
+                }
             })
             // Mysql 8.0.20 deprecates use of VALUES() so will have to update it eventually to use aliases instead
             // However, Mariadb doesn't yet support aliases
@@ -121,6 +194,10 @@ impl Flushable<PeerUpdate> for super::Batch<Index, PeerUpdate> {
             "#,
             );
 
+        if is_websocket_tracker_enabled {
+            query_builder.push(", webrtc = VALUES(webrtc)");
+        }
+
         query_builder
             .build()
             .persistent(false)
@@ -1,19 +1,24 @@
 use std::sync::Arc;
 
+use anyhow::Result;
 use chrono::Utc;
+use serde::Serialize;
 use sqlx::{MySql, QueryBuilder};
 
-use crate::state::AppState;
+use crate::{
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
 
 use super::{Flushable, Mergeable};
 
 // Fields must be in same order as database primary key
-#[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct Index {
     pub torrent_id: u32,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct TorrentUpdate {
     pub seeder_delta: i32,
     pub leecher_delta: i32,
@@ -32,6 +37,36 @@ impl Mergeable for TorrentUpdate {
     }
 }
 
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.torrent_id.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            torrent_id: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for TorrentUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.seeder_delta.encode(buffer);
+        self.leecher_delta.encode(buffer);
+        self.times_completed_delta.encode(buffer);
+        self.balance_delta.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(TorrentUpdate {
+            seeder_delta: Encode::decode(reader)?,
+            leecher_delta: Encode::decode(reader)?,
+            times_completed_delta: Encode::decode(reader)?,
+            balance_delta: Encode::decode(reader)?,
+        })
+    }
+}
+
 impl Flushable<TorrentUpdate> for super::Batch<Index, TorrentUpdate> {
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         if self.is_empty() {
@@ -1,26 +1,39 @@
 use std::sync::Arc;
 
-use crate::{model::info_hash::InfoHash, state::AppState};
+use crate::{
+    model::{info_hash::InfoHash, info_hash_version::InfoHashVersion},
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
+use anyhow::Result;
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{MySql, QueryBuilder};
 
 use super::{Flushable, Mergeable};
 
 // Fields must be in same order as database primary key
-#[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct Index {
     pub info_hash: InfoHash,
     pub user_id: u32,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct UnregisteredInfoHashUpdate {
+    /// Unknown if the info hash could be either a v1 info hash or a
+    /// truncated v2 info hash.
+    pub info_hash_version: Option<InfoHashVersion>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
 impl Mergeable for UnregisteredInfoHashUpdate {
     fn merge(&mut self, new: &Self) {
+        if new.info_hash_version.is_some() {
+            self.info_hash_version = new.info_hash_version;
+        }
+
         if new.updated_at > self.updated_at {
             self.updated_at = new.updated_at;
         }
@@ -29,6 +42,36 @@ impl Mergeable for UnregisteredInfoHashUpdate {
     }
 }
 
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.info_hash.0.encode(buffer);
+        self.user_id.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            info_hash: InfoHash(Encode::decode(reader)?),
+            user_id: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for UnregisteredInfoHashUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.info_hash_version.encode(buffer);
+        self.created_at.encode(buffer);
+        self.updated_at.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(UnregisteredInfoHashUpdate {
+            info_hash_version: Encode::decode(reader)?,
+            created_at: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+        })
+    }
+}
+
 impl Flushable<UnregisteredInfoHashUpdate> for super::Batch<Index, UnregisteredInfoHashUpdate> {
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         if self.is_empty() {
@@ -41,6 +84,7 @@ impl Flushable<UnregisteredInfoHashUpdate> for super::Batch<Index, UnregisteredI
                     unregistered_info_hashes(
                         user_id,
                         info_hash,
+                        info_hash_version,
                         created_at,
                         updated_at
                     )
@@ -55,6 +99,11 @@ impl Flushable<UnregisteredInfoHashUpdate> for super::Batch<Index, UnregisteredI
                 |mut bind, (index, unregistered_info_hash_update)| {
                     bind.push_bind(index.user_id)
                         .push_bind(index.info_hash.to_vec())
+                        .push_bind(
+                            unregistered_info_hash_update
+                                .info_hash_version
+                                .map(|version| version as u8),
+                        )
                         .push_bind(unregistered_info_hash_update.created_at)
                         .push_bind(unregistered_info_hash_update.updated_at);
                 },
@@ -64,6 +113,7 @@ impl Flushable<UnregisteredInfoHashUpdate> for super::Batch<Index, UnregisteredI
             .push(
                 r#"
                 ON DUPLICATE KEY UPDATE
+                    info_hash_version = COALESCE(VALUES(info_hash_version), info_hash_version),
                     updated_at = VALUES(updated_at)
             "#,
             );
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use crate::{
+    model::peer_id::PeerId,
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{MySql, QueryBuilder};
+
+use super::{Flushable, Mergeable};
+
+// Fields must be in same order as database primary key
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct Index {
+    pub user_id: u32,
+    pub torrent_id: u32,
+    pub peer_id: PeerId,
+}
+
+#[derive(Clone, Serialize)]
+pub struct UploadAnomalyUpdate {
+    /// Amount uploaded during the flagged announces.
+    pub uploaded: u64,
+    /// Highest average upload speed of the flagged announces, in bytes per
+    /// second.
+    pub max_upload_speed: u64,
+    pub flagged_announces: u32,
+    pub exceeded_max_upload_speed: bool,
+    pub uploaded_without_leechers: bool,
+    pub exceeded_swarm_download: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Mergeable for UploadAnomalyUpdate {
+    fn merge(&mut self, new: &Self) {
+        self.uploaded = self.uploaded.saturating_add(new.uploaded);
+        self.max_upload_speed = std::cmp::max(self.max_upload_speed, new.max_upload_speed);
+        self.flagged_announces = self.flagged_announces.saturating_add(new.flagged_announces);
+        self.exceeded_max_upload_speed |= new.exceeded_max_upload_speed;
+        self.uploaded_without_leechers |= new.uploaded_without_leechers;
+        self.exceeded_swarm_download |= new.exceeded_swarm_download;
+
+        if new.updated_at > self.updated_at {
+            self.updated_at = new.updated_at;
+        }
+
+        self.created_at = std::cmp::min(self.created_at, new.created_at);
+    }
+}
+
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_id.encode(buffer);
+        self.torrent_id.encode(buffer);
+        self.peer_id.0.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            user_id: Encode::decode(reader)?,
+            torrent_id: Encode::decode(reader)?,
+            peer_id: PeerId(Encode::decode(reader)?),
+        })
+    }
+}
+
+impl Encode for UploadAnomalyUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.uploaded.encode(buffer);
+        self.max_upload_speed.encode(buffer);
+        self.flagged_announces.encode(buffer);
+        self.exceeded_max_upload_speed.encode(buffer);
+        self.uploaded_without_leechers.encode(buffer);
+        self.exceeded_swarm_download.encode(buffer);
+        self.created_at.encode(buffer);
+        self.updated_at.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(UploadAnomalyUpdate {
+            uploaded: Encode::decode(reader)?,
+            max_upload_speed: Encode::decode(reader)?,
+            flagged_announces: Encode::decode(reader)?,
+            exceeded_max_upload_speed: Encode::decode(reader)?,
+            uploaded_without_leechers: Encode::decode(reader)?,
+            exceeded_swarm_download: Encode::decode(reader)?,
+            created_at: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Flushable<UploadAnomalyUpdate> for super::Batch<Index, UploadAnomalyUpdate> {
+    async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+
+        let mut query_builder: QueryBuilder<MySql> = QueryBuilder::new(
+            r#"
+                INSERT INTO
+                    upload_anomalies(
+                        user_id,
+                        torrent_id,
+                        peer_id,
+                        uploaded,
+                        max_upload_speed,
+                        flagged_announces,
+                        exceeded_max_upload_speed,
+                        uploaded_without_leechers,
+                        exceeded_swarm_download,
+                        created_at,
+                        updated_at
+                    )
+            "#,
+        );
+
+        query_builder
+            // Trailing space required before the push values function
+            // Leading space required after the push values function
+            .push_values(self.iter(), |mut bind, (index, upload_anomaly_update)| {
+                bind.push_bind(index.user_id)
+                    .push_bind(index.torrent_id)
+                    .push_bind(index.peer_id.to_vec())
+                    .push_bind(upload_anomaly_update.uploaded)
+                    .push_bind(upload_anomaly_update.max_upload_speed)
+                    .push_bind(upload_anomaly_update.flagged_announces)
+                    .push_bind(upload_anomaly_update.exceeded_max_upload_speed)
+                    .push_bind(upload_anomaly_update.uploaded_without_leechers)
+                    .push_bind(upload_anomaly_update.exceeded_swarm_download)
+                    .push_bind(upload_anomaly_update.created_at)
+                    .push_bind(upload_anomaly_update.updated_at);
+            })
+            // Mysql 8.0.20 deprecates use of VALUES() so will have to update it eventually to use aliases instead
+            // However, Mariadb doesn't yet support aliases
+            .push(
+                r#"
+                ON DUPLICATE KEY UPDATE
+                    uploaded = uploaded + VALUES(uploaded),
+                    max_upload_speed = GREATEST(max_upload_speed, VALUES(max_upload_speed)),
+                    flagged_announces = flagged_announces + VALUES(flagged_announces),
+                    exceeded_max_upload_speed = exceeded_max_upload_speed OR VALUES(exceeded_max_upload_speed),
+                    uploaded_without_leechers = uploaded_without_leechers OR VALUES(uploaded_without_leechers),
+                    exceeded_swarm_download = exceeded_swarm_download OR VALUES(exceeded_swarm_download),
+                    updated_at = VALUES(updated_at)
+            "#,
+            );
+
+        query_builder
+            .build()
+            .persistent(false)
+            .execute(&state.pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+}
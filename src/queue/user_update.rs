@@ -1,13 +1,18 @@
 use std::sync::Arc;
 
+use anyhow::Result;
+use serde::Serialize;
 use sqlx::{MySql, QueryBuilder};
 
-use crate::state::AppState;
+use crate::{
+    snapshot::{Encode, Reader},
+    state::AppState,
+};
 
 use super::{Flushable, Mergeable};
 
 // Fields must be in same order as database primary key
-#[derive(Eq, Hash, PartialEq, PartialOrd, Ord)]
+#[derive(Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct Index {
     pub user_id: u32,
 }
@@ -15,7 +20,7 @@ pub struct Index {
 // TODO: Ideally unit3d should have `num_seeding` and `num_leeching` columns
 // on the user table so that the navbar doesn't query the history table.
 // If those columns existed, they should be updated too.
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct UserUpdate {
     pub uploaded_delta: u64,
     pub downloaded_delta: u64,
@@ -28,6 +33,32 @@ impl Mergeable for UserUpdate {
     }
 }
 
+impl Encode for Index {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.user_id.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Index {
+            user_id: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for UserUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.uploaded_delta.encode(buffer);
+        self.downloaded_delta.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(UserUpdate {
+            uploaded_delta: Encode::decode(reader)?,
+            downloaded_delta: Encode::decode(reader)?,
+        })
+    }
+}
+
 impl Flushable<UserUpdate> for super::Batch<Index, UserUpdate> {
     async fn flush_to_db(&self, state: &Arc<AppState>) -> Result<u64, sqlx::Error> {
         if self.is_empty() {
@@ -0,0 +1,608 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use tracing::error;
+
+use crate::snapshot::{Encode, Reader};
+
+/// Identifies write-ahead log files.
+const MAGIC: [u8; 8] = *b"U3DAWAL\0";
+
+/// Has to be incremented on every change to the log format or to the
+/// encoding of the queued updates.
+const VERSION: u16 = 3;
+
+/// Append-only log of the updates upserted into a queue that haven't been
+/// flushed to the database yet, so that they survive the process being
+/// killed between flushes.
+///
+/// The log is made of numbered segment files in its directory. Checkpoint
+/// segments (`<name>.<sequence>.checkpoint`) hold every record queued at
+/// some point, and append segments (`<name>.<sequence>.wal`) hold the
+/// updates upserted afterwards. Only the latest checkpoint and the append
+/// segments following it are replayed, so older segments can be deleted
+/// lazily.
+///
+/// The updates taken out of the queue to be flushed are left out of the
+/// checkpoint written before they're sent to the database, so that updates
+/// that were already applied aren't replayed and applied twice, which would
+/// credit additive deltas twice. They're written to a flush segment
+/// (`<name>.<sequence>.flush`) alongside the checkpoint instead, which is
+/// deleted once the flush completes. If the process is killed during the
+/// flush, it isn't known whether they were applied, so they're returned
+/// separately instead of being replayed.
+///
+/// Each record is prefixed with its length, so that a record only partially
+/// written before a crash can be told apart from a corrupted log.
+pub struct WriteAheadLog {
+    directory: PathBuf,
+    name: &'static str,
+    /// Sequence number of the append segment.
+    sequence: u64,
+    writer: Option<Writer>,
+}
+
+/// Thread writing the appended records to the append segment, so that
+/// upserts don't make a write syscall per record while holding the queue's
+/// lock. Records appended while a write is in progress are batched into the
+/// next write.
+struct Writer {
+    commands: Sender<Command>,
+    thread: JoinHandle<()>,
+}
+
+enum Command {
+    /// Appends the encoded records to the current segment.
+    Append(Vec<u8>),
+    /// Appends further records to the given segment instead.
+    Open(File, PathBuf),
+    /// Deletes the given flush segments.
+    Remove(Vec<PathBuf>),
+}
+
+/// Records of a log read back after a restart.
+pub struct Replay<K, V> {
+    /// Records that were still queued.
+    pub queued: Vec<(K, V)>,
+    /// Records that were being flushed to the database, which may or may not
+    /// have been applied.
+    pub interrupted: Vec<(K, V)>,
+}
+
+impl WriteAheadLog {
+    /// Reads the records of the log with the name in the directory. A
+    /// missing log has no records.
+    pub fn read<K: Encode, V: Encode>(directory: &Path, name: &str) -> Result<Replay<K, V>> {
+        let segments = segments(directory, name)?;
+        let checkpoint = segments
+            .iter()
+            .filter(|segment| segment.kind == SegmentKind::Checkpoint)
+            .map(|segment| segment.sequence)
+            .max();
+        let mut replay = Replay {
+            queued: Vec::new(),
+            interrupted: Vec::new(),
+        };
+
+        for segment in segments {
+            // A flush segment only counts once its checkpoint was written,
+            // since its updates aren't sent to the database before then.
+            let records = match (segment.kind, checkpoint) {
+                (_, None) if segment.kind == SegmentKind::Append => &mut replay.queued,
+                (SegmentKind::Checkpoint, Some(checkpoint)) if segment.sequence == checkpoint => {
+                    &mut replay.queued
+                }
+                (SegmentKind::Append, Some(checkpoint)) if segment.sequence > checkpoint => {
+                    &mut replay.queued
+                }
+                (SegmentKind::Flush, Some(checkpoint)) if segment.sequence == checkpoint => {
+                    &mut replay.interrupted
+                }
+                _ => continue,
+            };
+
+            let path = segment.path;
+            let bytes =
+                fs::read(&path).with_context(|| format!("Failed reading log from {path:?}."))?;
+
+            records
+                .extend(decode(&bytes).with_context(|| format!("Failed decoding log {path:?}."))?);
+        }
+
+        Ok(replay)
+    }
+
+    /// Checkpoints the given records after the existing segments of the log
+    /// with the name in the directory, and opens a new segment for
+    /// appending.
+    pub fn create<'a, K: Encode + 'a, V: Encode + 'a>(
+        directory: PathBuf,
+        name: &'static str,
+        records: impl Iterator<Item = (&'a K, &'a V)>,
+    ) -> Result<WriteAheadLog> {
+        let sequence = segments(&directory, name)?
+            .iter()
+            .map(|segment| segment.sequence)
+            .max()
+            .unwrap_or(0);
+        let checkpoint = Checkpoint::new(&directory, name, sequence + 1, records, iter::empty());
+        let path = segment_path(&directory, name, sequence + 2, "wal");
+        let file = open_append_segment(&path)?;
+
+        checkpoint.write()?;
+
+        let (commands, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(format!("{name} log writer"))
+            .spawn(move || write_appended_records(file, path, receiver))
+            .context("Failed spawning log writer.")?;
+
+        Ok(WriteAheadLog {
+            directory,
+            name,
+            sequence: sequence + 2,
+            writer: Some(Writer { commands, thread }),
+        })
+    }
+
+    /// Appends a single record to the end of the log. The record is handed
+    /// to the writer thread, which hands it to the OS without waiting for it
+    /// to reach the disk, so that upserts don't stall on I/O. It survives the
+    /// process being killed once written, and is synced to disk with the
+    /// next checkpoint.
+    pub fn append<K: Encode, V: Encode>(&mut self, key: &K, value: &V) -> Result<()> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        encode_record(&mut buffer, key, value);
+
+        self.send(Command::Append(buffer))
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.writer
+            .as_ref()
+            .and_then(|writer| writer.commands.send(command).ok())
+            .ok_or_else(|| anyhow!("Writer of log {} stopped.", self.name))
+    }
+
+    /// Starts a new append segment and returns a checkpoint of the given
+    /// records, which must be every record in the queue, and of the records
+    /// taken out of the queue to be flushed. Only encodes the records in
+    /// memory, so that the checkpoint can be written without holding the
+    /// queue's lock. The flushed records must not be sent to the database
+    /// before the checkpoint is written.
+    pub fn rotate<'a, K: Encode + 'a, V: Encode + 'a>(
+        &mut self,
+        records: impl Iterator<Item = (&'a K, &'a V)>,
+        flushed: impl Iterator<Item = (&'a K, &'a V)>,
+    ) -> Result<Checkpoint> {
+        let checkpoint = Checkpoint::new(
+            &self.directory,
+            self.name,
+            self.sequence + 1,
+            records,
+            flushed,
+        );
+        let path = segment_path(&self.directory, self.name, self.sequence + 2, "wal");
+        let file = open_append_segment(&path)?;
+
+        self.send(Command::Open(file, path))?;
+        self.sequence += 2;
+
+        Ok(checkpoint)
+    }
+
+    /// Deletes the flush segments once their updates were applied or
+    /// appended to the log again. The segments are deleted by the writer
+    /// thread after writing the records appended so far.
+    pub fn complete_flush(&self) -> Result<()> {
+        let paths = segments(&self.directory, self.name)?
+            .into_iter()
+            .filter(|segment| segment.kind == SegmentKind::Flush)
+            .map(|segment| segment.path)
+            .collect::<Vec<_>>();
+
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        self.send(Command::Remove(paths))
+    }
+}
+
+/// Waits for the records appended so far to be written.
+impl Drop for WriteAheadLog {
+    fn drop(&mut self) {
+        if let Some(Writer { commands, thread }) = self.writer.take() {
+            drop(commands);
+
+            if thread.join().is_err() {
+                error!("Writer of log {} panicked.", self.name);
+            }
+        }
+    }
+}
+
+/// Writes the appended records until the log is dropped, batching the
+/// records received while the previous write was in progress.
+fn write_appended_records(mut file: File, mut path: PathBuf, commands: Receiver<Command>) {
+    let mut buffer = Vec::new();
+
+    while let Ok(command) = commands.recv() {
+        for command in iter::once(command).chain(commands.try_iter()) {
+            match command {
+                Command::Append(record) => buffer.extend_from_slice(&record),
+                Command::Open(next_file, next_path) => {
+                    write_buffer(&mut file, &path, &mut buffer);
+                    file = next_file;
+                    path = next_path;
+                }
+                Command::Remove(paths) => {
+                    write_buffer(&mut file, &path, &mut buffer);
+
+                    for path in paths {
+                        if let Err(e) = fs::remove_file(&path) {
+                            error!("Failed deleting log {path:?}: {e}");
+                        }
+                    }
+                }
+            }
+        }
+
+        write_buffer(&mut file, &path, &mut buffer);
+    }
+}
+
+fn write_buffer(file: &mut File, path: &Path, buffer: &mut Vec<u8>) {
+    if buffer.is_empty() {
+        return;
+    }
+
+    if let Err(e) = file.write_all(buffer) {
+        error!("Failed appending to log {path:?}: {e}");
+    }
+
+    buffer.clear();
+}
+
+/// Every record of a queue at the time the log was rotated, along with the
+/// records being flushed, encoded but not written yet.
+pub struct Checkpoint {
+    directory: PathBuf,
+    name: &'static str,
+    sequence: u64,
+    buffer: Vec<u8>,
+    flushed: Option<Vec<u8>>,
+}
+
+impl Checkpoint {
+    fn new<'a, K: Encode + 'a, V: Encode + 'a>(
+        directory: &Path,
+        name: &'static str,
+        sequence: u64,
+        records: impl Iterator<Item = (&'a K, &'a V)>,
+        flushed: impl Iterator<Item = (&'a K, &'a V)>,
+    ) -> Checkpoint {
+        let mut flushed = flushed.peekable();
+
+        Checkpoint {
+            directory: directory.to_path_buf(),
+            name,
+            sequence,
+            buffer: encode_segment(records),
+            flushed: flushed.peek().is_some().then(|| encode_segment(flushed)),
+        }
+    }
+
+    /// Durably writes the checkpoint and deletes the segments it supersedes.
+    pub fn write(self) -> Result<()> {
+        // The flush segment is only read once the checkpoint exists, so it
+        // doesn't have to be written atomically.
+        if let Some(flushed) = &self.flushed {
+            let path = segment_path(&self.directory, self.name, self.sequence, "flush");
+            let mut file =
+                File::create(&path).with_context(|| format!("Failed creating log {path:?}."))?;
+
+            file.write_all(flushed)
+                .and_then(|_| file.sync_all())
+                .with_context(|| format!("Failed writing log to {path:?}."))?;
+        }
+
+        let path = segment_path(&self.directory, self.name, self.sequence, "checkpoint");
+
+        // The checkpoint is written atomically, so that a crash while
+        // writing doesn't lose the records of the previous segments.
+        let temporary_path = segment_path(&self.directory, self.name, self.sequence, "tmp");
+        let mut file = File::create(&temporary_path)
+            .with_context(|| format!("Failed creating log {temporary_path:?}."))?;
+
+        file.write_all(&self.buffer)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed writing log to {temporary_path:?}."))?;
+        fs::rename(&temporary_path, &path)
+            .with_context(|| format!("Failed moving log to {path:?}."))?;
+        File::open(&self.directory)
+            .and_then(|directory| directory.sync_all())
+            .with_context(|| format!("Failed syncing log directory {:?}.", self.directory))?;
+
+        for segment in segments(&self.directory, self.name)? {
+            if segment.sequence < self.sequence {
+                fs::remove_file(&segment.path)
+                    .with_context(|| format!("Failed deleting log {:?}.", segment.path))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SegmentKind {
+    Checkpoint,
+    Append,
+    Flush,
+    /// Checkpoint that was still being written when the process was killed.
+    Temporary,
+}
+
+struct Segment {
+    path: PathBuf,
+    sequence: u64,
+    kind: SegmentKind,
+}
+
+fn segment_path(directory: &Path, name: &str, sequence: u64, extension: &str) -> PathBuf {
+    directory.join(format!("{name}.{sequence}.{extension}"))
+}
+
+/// Lists the segments of the log with the name in the directory in
+/// ascending order.
+fn segments(directory: &Path, name: &str) -> Result<Vec<Segment>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed listing logs in {directory:?}."));
+        }
+    };
+    let mut segments = Vec::new();
+
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed listing logs in {directory:?}."))?
+            .path();
+
+        let Some((sequence, extension)) = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| file_name.strip_prefix(name)?.strip_prefix('.'))
+            .and_then(|file_name| file_name.split_once('.'))
+        else {
+            continue;
+        };
+
+        let kind = match extension {
+            "checkpoint" => SegmentKind::Checkpoint,
+            "wal" => SegmentKind::Append,
+            "flush" => SegmentKind::Flush,
+            "tmp" => SegmentKind::Temporary,
+            _ => continue,
+        };
+
+        if let Ok(sequence) = sequence.parse() {
+            segments.push(Segment {
+                path,
+                sequence,
+                kind,
+            });
+        }
+    }
+
+    segments.sort_unstable_by_key(|segment| segment.sequence);
+
+    Ok(segments)
+}
+
+fn open_append_segment(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed opening log {path:?}."))?;
+    let mut buffer: Vec<u8> = Vec::new();
+
+    MAGIC.encode(&mut buffer);
+    VERSION.encode(&mut buffer);
+
+    file.write_all(&buffer)
+        .with_context(|| format!("Failed writing log to {path:?}."))?;
+
+    Ok(file)
+}
+
+fn encode_segment<'a, K: Encode + 'a, V: Encode + 'a>(
+    records: impl Iterator<Item = (&'a K, &'a V)>,
+) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::new();
+
+    MAGIC.encode(&mut buffer);
+    VERSION.encode(&mut buffer);
+
+    for (key, value) in records {
+        encode_record(&mut buffer, key, value);
+    }
+
+    buffer
+}
+
+fn encode_record<K: Encode, V: Encode>(buffer: &mut Vec<u8>, key: &K, value: &V) {
+    let start = buffer.len();
+
+    // Placeholder for the length, which is only known once encoded
+    0u32.encode(buffer);
+    key.encode(buffer);
+    value.encode(buffer);
+
+    let len = (buffer.len() - start - 4) as u32;
+
+    buffer[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn decode<K: Encode, V: Encode>(bytes: &[u8]) -> Result<Vec<(K, V)>> {
+    let mut reader = Reader { bytes };
+
+    ensure!(
+        <[u8; 8]>::decode(&mut reader)? == MAGIC,
+        "File is not a write-ahead log."
+    );
+
+    let version = u16::decode(&mut reader)?;
+
+    ensure!(
+        version == VERSION,
+        "Write-ahead log version {version} is not supported."
+    );
+
+    let mut records = Vec::new();
+    let mut bytes = reader.bytes;
+
+    // A partially written last record is dropped, since the process was
+    // killed while appending it.
+    while let Some((len, rest)) = bytes.split_first_chunk()
+        && let Some((record, rest)) = rest.split_at_checked(u32::from_le_bytes(*len) as usize)
+    {
+        let mut reader = Reader { bytes: record };
+
+        records.push((K::decode(&mut reader)?, V::decode(&mut reader)?));
+
+        ensure!(reader.bytes.is_empty(), "Record has trailing data.");
+
+        bytes = rest;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partially_written_record() -> Result<()> {
+        let mut buffer = Vec::new();
+
+        MAGIC.encode(&mut buffer);
+        VERSION.encode(&mut buffer);
+        encode_record(&mut buffer, &1u32, &String::from("a"));
+        encode_record(&mut buffer, &2u32, &String::from("b"));
+
+        let len = buffer.len();
+
+        for truncated_len in len - 3..len {
+            let records = decode::<u32, String>(&buffer[..truncated_len])?;
+
+            assert_eq!(records, [(1, String::from("a"))]);
+        }
+
+        assert_eq!(decode::<u32, String>(&buffer)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replays_latest_checkpoint() -> Result<()> {
+        let directory = std::env::temp_dir().join(format!("wal-test-{}", std::process::id()));
+
+        fs::create_dir_all(&directory)?;
+
+        let mut log =
+            WriteAheadLog::create(directory.clone(), "test", [(&1u32, &1u32)].into_iter())?;
+
+        log.append(&2u32, &2u32)?;
+
+        let checkpoint = log.rotate([(&1u32, &1u32), (&2u32, &2u32)].into_iter(), iter::empty())?;
+
+        log.append(&3u32, &3u32)?;
+
+        // Waits for the appended records to be written
+        drop(log);
+
+        // Until the checkpoint is written, the previous segments are replayed
+        assert_eq!(
+            WriteAheadLog::read::<u32, u32>(&directory, "test")?.queued,
+            [(1, 1), (2, 2), (3, 3)]
+        );
+
+        checkpoint.write()?;
+
+        assert_eq!(
+            WriteAheadLog::read::<u32, u32>(&directory, "test")?.queued,
+            [(1, 1), (2, 2), (3, 3)]
+        );
+        assert_eq!(segments(&directory, "test")?.len(), 2);
+
+        fs::remove_dir_all(&directory)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_flush() -> Result<()> {
+        let directory = std::env::temp_dir().join(format!("wal-flush-test-{}", std::process::id()));
+
+        fs::create_dir_all(&directory)?;
+
+        let mut log = WriteAheadLog::create(
+            directory.clone(),
+            "test",
+            [(&1u32, &1u32), (&2u32, &2u32)].into_iter(),
+        )?;
+        let checkpoint = log.rotate([(&2u32, &2u32)].into_iter(), [(&1u32, &1u32)].into_iter())?;
+
+        // Until the checkpoint is written, the flushed records are still
+        // replayed, since they weren't sent to the database yet
+        let replay = WriteAheadLog::read::<u32, u32>(&directory, "test")?;
+
+        assert_eq!(replay.queued, [(1, 1), (2, 2)]);
+        assert!(replay.interrupted.is_empty());
+
+        checkpoint.write()?;
+
+        let replay = WriteAheadLog::read::<u32, u32>(&directory, "test")?;
+
+        assert_eq!(replay.queued, [(2, 2)]);
+        assert_eq!(replay.interrupted, [(1, 1)]);
+
+        log.complete_flush()?;
+        drop(log);
+
+        let replay = WriteAheadLog::read::<u32, u32>(&directory, "test")?;
+
+        assert_eq!(replay.queued, [(2, 2)]);
+        assert!(replay.interrupted.is_empty());
+
+        fs::remove_dir_all(&directory)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_record() {
+        let mut buffer = Vec::new();
+
+        MAGIC.encode(&mut buffer);
+        VERSION.encode(&mut buffer);
+        encode_record(&mut buffer, &1u32, &true);
+
+        *buffer.last_mut().unwrap() = 2;
+
+        assert!(decode::<u32, bool>(&buffer).is_err());
+    }
+}
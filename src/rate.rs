@@ -1,15 +1,17 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::Context;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use tokio::time::Duration;
 
+use crate::snapshot::{Encode, Reader};
+
 /// Used to efficiently calculate rates of a recurring event.
 ///
 /// Each time the rate is ticked, its new rate is calculated using the
 /// following formula:
 ///
-/// ```
+/// ```text
 /// new_rate = old_rate * e^(-1 * (current_time - last_event_time) / window) + 1
 /// ```
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
@@ -130,3 +132,39 @@ impl RateCollection {
         !self.is_under_limit()
     }
 }
+
+impl Encode for Rate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.count.encode(buffer);
+        self.max_count.encode(buffer);
+        self.window.encode(buffer);
+        self.updated_at.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            count: Encode::decode(reader)?,
+            max_count: Encode::decode(reader)?,
+            window: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for RateCollection {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.rates.len().encode(buffer);
+
+        for rate in &self.rates {
+            rate.encode(buffer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            rates: (0..usize::decode(reader)?)
+                .map(|_| Rate::decode(reader))
+                .collect::<Result<Vec<Rate>>>()?,
+        })
+    }
+}
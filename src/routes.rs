@@ -6,7 +6,9 @@ use axum::{
     routing::{get, post, put},
 };
 
-use crate::{announce, api, config::Config, state::AppState, stats};
+use crate::{
+    announce, api, config::Config, health, metrics, scrape, state::AppState, stats, websocket,
+};
 
 pub fn routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
     Router::new()
@@ -16,7 +18,10 @@ pub fn routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
                 .route("/{passkey}", get(announce::announce))
                 .nest(
                     "/health",
-                    Router::new().route("/ping", get(|| async { "PONG" })),
+                    Router::new()
+                        .route("/ping", get(|| async { "PONG" }))
+                        .route("/live", get(health::live))
+                        .route("/ready", get(health::ready)),
                 )
                 .nest(
                     &("/".to_string() + &state.config.load().apikey),
@@ -32,11 +37,26 @@ pub fn routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
                             "/groups",
                             put(api::group::upsert).delete(api::group::destroy),
                         )
+                        .route(
+                            "/banned-ip-ranges",
+                            put(api::banned_ip_range::upsert).delete(api::banned_ip_range::destroy),
+                        )
                         .route(
                             "/blacklisted-agents",
                             put(api::blacklisted_agent::upsert)
                                 .delete(api::blacklisted_agent::destroy),
                         )
+                        .route(
+                            "/blacklisted-ports",
+                            put(api::blacklisted_port::upsert)
+                                .delete(api::blacklisted_port::destroy),
+                        )
+                        .route(
+                            "/client-rules",
+                            get(api::client_rule::index)
+                                .put(api::client_rule::upsert)
+                                .delete(api::client_rule::destroy),
+                        )
                         .route(
                             "/freeleech-tokens",
                             put(api::freeleech_token::upsert).delete(api::freeleech_token::destroy),
@@ -51,9 +71,16 @@ pub fn routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
                             put(api::featured_torrent::upsert)
                                 .delete(api::featured_torrent::destroy),
                         )
+                        .route(
+                            "/connectable-ports/{socket}",
+                            get(api::connectable_port::show).delete(api::connectable_port::destroy),
+                        )
                         .route("/stats", get(crate::stats::show))
+                        .route("/metrics", get(metrics::show))
                         .route("/config/reload", post(Config::reload)),
                 ),
         )
+        .route("/scrape/{passkey}", get(scrape::scrape))
+        .route("/ws/{passkey}", get(websocket::upgrade))
         .layer(from_fn_with_state(state.clone(), stats::record_request))
 }
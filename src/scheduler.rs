@@ -1,43 +1,193 @@
 use std::sync::Arc;
 
 use crate::queue::torrent_update::{Index, TorrentUpdate};
+use crate::snapshot;
 use crate::state::AppState;
+use crate::store::torrent::Shard;
 use chrono::{Duration, Utc};
-use tokio::time::Instant;
-use tracing::info;
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use strum::{EnumIter, IntoEnumIterator, IntoStaticStr};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{error, info};
 
-pub async fn handle(state: &Arc<AppState>) {
-    let mut interval = tokio::time::interval(std::time::Duration::from_millis(1));
-    let mut counter = 0_u64;
+/// Background jobs, each run periodically by its own task.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Job {
+    /// Flushes the queued updates to the database.
+    Flush,
+    /// Expires the peers of the next shard of the torrent store.
+    Reap,
+    /// Writes the stores to the snapshot file.
+    Snapshot,
+}
+
+impl Job {
+    /// Time between two runs of the job.
+    fn period(self, state: &AppState) -> std::time::Duration {
+        let config = state.config.load();
+
+        match self {
+            Job::Flush => std::time::Duration::from_millis(config.flush_interval_milliseconds),
+            // Each shard is reaped once per peer expiry interval, with the
+            // shards spread out evenly over the interval
+            Job::Reap => std::time::Duration::from_millis(
+                (config.peer_expiry_interval * 1000 / state.stores.torrents.shards().len() as u64)
+                    .max(1),
+            ),
+            Job::Snapshot => std::time::Duration::from_secs(config.snapshot_interval),
+        }
+    }
+
+    /// Runs the job once. Returns whether there was anything to do.
+    async fn run(self, state: &Arc<AppState>, reaper: &mut Reaper) -> bool {
+        match self {
+            Job::Flush => state.queues.flush(state).await,
+            Job::Reap => reaper.reap_next_shard(state),
+            Job::Snapshot => {
+                let snapshot_path = state.config.load().snapshot_path.clone();
+
+                let Some(path) = snapshot_path else {
+                    return false;
+                };
+
+                if let Err(e) = snapshot::save(state, &path).await {
+                    error!("{e:#}");
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Signals the job tasks. Watch channels are used instead of notifications,
+/// so that jobs that are running when signaled still see the signal once
+/// they're done.
+#[derive(Default)]
+pub struct Scheduler {
+    config_reloaded: watch::Sender<()>,
+    is_stopped: watch::Sender<bool>,
+}
+
+impl Scheduler {
+    /// Wakes up the jobs so that they pick up intervals changed by a config
+    /// reload without waiting for their next run.
+    pub fn reload(&self) {
+        self.config_reloaded.send_replace(());
+    }
+
+    /// Stops the jobs once their current run is done.
+    pub fn stop(&self) {
+        self.is_stopped.send_replace(true);
+    }
+}
+
+/// Spawns a task for each job, running it at its configured interval.
+pub fn spawn(state: &Arc<AppState>) -> Vec<JoinHandle<()>> {
+    Job::iter()
+        .map(|job| tokio::spawn(run(state.clone(), job)))
+        .collect()
+}
+
+async fn run(state: Arc<AppState>, job: Job) {
+    let mut reaper = Reaper::default();
+    let mut config_reloaded = state.scheduler.config_reloaded.subscribe();
+    let mut is_stopped = state.scheduler.is_stopped.subscribe();
 
     loop {
-        interval.tick().await;
-        counter += 1;
+        let period = job.period(&state);
+        let missed_tick_behavior = state.config.load().missed_tick_behavior;
+        let mut interval = time::interval_at(Instant::now() + period, period);
 
-        if counter % state.config.load().flush_interval_milliseconds == 0 {
-            state.queues.flush(state).await;
+        interval.set_missed_tick_behavior(missed_tick_behavior);
+
+        // Run the job until its interval is changed by a config reload
+        while period == job.period(&state)
+            && missed_tick_behavior == state.config.load().missed_tick_behavior
+        {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let start = Instant::now();
+
+                    if job.run(&state, &mut reaper).await {
+                        state.stats.record_job(job, start.elapsed());
+                    }
+                }
+                _ = config_reloaded.changed() => (),
+                _ = is_stopped.changed() => return,
+            }
         }
+    }
+}
+
+/// Expires peers one shard of the torrent store at a time, so that each tick
+/// only blocks the announces of a single shard for a short time instead of
+/// stalling every announce while all torrents are walked.
+#[derive(Default)]
+struct Reaper {
+    next_shard: usize,
+    expired_peers: usize,
+    elapsed: std::time::Duration,
+}
+
+impl Reaper {
+    /// Remove peers of the next shard that have not announced for some time
+    fn reap_next_shard(&mut self, state: &AppState) {
+        let start = Instant::now();
+        let shards = state.stores.torrents.shards();
 
-        if counter % (state.config.load().peer_expiry_interval * 1000) == 0 {
-            reap(state).await;
+        self.expired_peers += reap(state, &shards[self.next_shard]);
+        self.next_shard += 1;
+
+        // The connectable ports are expired once per sweep of all shards
+        if self.next_shard == shards.len() {
+            let expired_connectable_ports = expire_connectable_ports(state);
+
+            self.elapsed += start.elapsed();
+
+            info!(
+                "Expired {} stale peers and {expired_connectable_ports} cached connectable ports in {} ms.",
+                self.expired_peers,
+                self.elapsed.as_millis()
+            );
+
+            *self = Reaper::default();
+        } else {
+            self.elapsed += start.elapsed();
         }
     }
 }
 
-/// Remove peers that have not announced for some time
-pub async fn reap(state: &Arc<AppState>) {
-    use rayon::prelude::*;
+/// Remove cached connectable ports that haven't been checked for some time.
+/// Returns the amount of connectable ports removed.
+fn expire_connectable_ports(state: &AppState) -> usize {
+    let ttl = Duration::seconds(state.config.load().connectable_port_ttl.try_into().unwrap());
+    let cutoff = Utc::now().checked_sub_signed(ttl).unwrap();
+
+    state.stores.connectable_ports.write().expire(cutoff)
+}
 
-    let start = Instant::now();
+/// Remove peers of a shard that have not announced for some time. Returns
+/// the amount of peers marked as inactive.
+fn reap(state: &AppState, shard: &Mutex<Shard>) -> usize {
     let config = state.config.load();
     let ttl = Duration::seconds(config.active_peer_ttl.try_into().unwrap());
     let active_cutoff = Utc::now().checked_sub_signed(ttl).unwrap();
     let ttl = Duration::seconds(config.inactive_peer_ttl.try_into().unwrap());
     let inactive_cutoff = Utc::now().checked_sub_signed(ttl).unwrap();
 
-    let mut torrent_store = state.stores.torrents.lock();
+    // Amount of seeding and leeching peers to subtract from each user
+    let mut user_deltas: IndexMap<u32, (u32, u32)> = IndexMap::new();
+    let mut torrent_updates = Vec::new();
+    let mut expired_peers = 0;
 
-    torrent_store.par_values_mut().for_each(|torrent| {
+    for torrent in shard.lock().values_mut() {
         let mut seeder_delta: i32 = 0;
         let mut leecher_delta: i32 = 0;
 
@@ -53,34 +203,31 @@ pub async fn reap(state: &Arc<AppState>) {
             // count are updated to reflect.
             if peer.updated_at < active_cutoff && peer.is_active {
                 if peer.is_included_in_peer_list(&config) {
-                    state
-                        .stores
-                        .users
-                        .write()
-                        .entry(index.user_id)
-                        .and_modify(|user| {
-                            if peer.is_seeder {
-                                user.num_seeding = user.num_seeding.saturating_sub(1);
-                            } else {
-                                user.num_leeching = user.num_leeching.saturating_sub(1);
-                            }
-                        });
+                    let (seeding, leeching) = user_deltas.entry(index.user_id).or_default();
+
                     match peer.is_seeder {
-                        true => seeder_delta -= 1,
-                        false => leecher_delta -= 1,
+                        true => {
+                            *seeding += 1;
+                            seeder_delta -= 1;
+                        }
+                        false => {
+                            *leeching += 1;
+                            leecher_delta -= 1;
+                        }
                     }
                 }
 
                 peer.is_active = false;
+                expired_peers += 1;
             }
         }
 
-        // Update peer count of torrents and users
+        // Update peer count of torrents
         if seeder_delta != 0 || leecher_delta != 0 {
             torrent.seeders = torrent.seeders.saturating_add_signed(seeder_delta);
             torrent.leechers = torrent.leechers.saturating_add_signed(leecher_delta);
 
-            state.queues.torrents.lock().upsert(
+            torrent_updates.push((
                 Index {
                     torrent_id: torrent.id,
                 },
@@ -90,10 +237,30 @@ pub async fn reap(state: &Arc<AppState>) {
                     times_completed_delta: 0,
                     balance_delta: 0,
                 },
-            );
+            ));
         }
-    });
+    }
+
+    // Update peer count of users, taking each lock once per shard instead of
+    // once per peer
+    if !user_deltas.is_empty() {
+        let mut user_guard = state.stores.users.write();
+
+        for (user_id, (seeding, leeching)) in user_deltas {
+            if let Some(user) = user_guard.get_mut(&user_id) {
+                user.num_seeding = user.num_seeding.saturating_sub(seeding);
+                user.num_leeching = user.num_leeching.saturating_sub(leeching);
+            }
+        }
+    }
+
+    if !torrent_updates.is_empty() {
+        let mut torrent_queue = state.queues.torrents.lock();
+
+        for (index, update) in torrent_updates {
+            torrent_queue.upsert(index, update);
+        }
+    }
 
-    let elapsed = start.elapsed().as_millis();
-    info!("Expired stale peers in {elapsed} ms.")
+    expired_peers
 }
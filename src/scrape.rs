@@ -0,0 +1,363 @@
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::request::Parts,
+};
+use std::{cmp::Ordering, str::FromStr, sync::Arc};
+
+use crate::{
+    announce::Query,
+    bencode,
+    error::AnnounceError::{
+        self, GroupNotEnabled, GroupNotFound, InvalidInfoHash, InvalidPasskey,
+        InvalidQueryStringKey, InvalidQueryStringValue, MissingInfoHash, PasskeyNotFound,
+        TooManyInfoHashes, UserNotFound,
+    },
+    model::{
+        info_hash::InfoHash, info_hash_v2::InfoHashV2, passkey::Passkey,
+        torrent_status::TorrentStatus,
+    },
+    state::AppState,
+    utils,
+};
+
+/// Max amount of info hashes scraped per request, which is also the max
+/// amount that fits in a single UDP scrape request.
+pub const MAX_INFO_HASHES: usize = 74;
+
+pub struct Scrape {
+    info_hashes: Vec<RequestedInfoHash>,
+}
+
+/// Info hash as requested by the client. Clients scrape v2 torrents with
+/// either the SHA-256 info hash truncated to 20 bytes or the full info hash,
+/// and expect the response to use the same one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RequestedInfoHash {
+    V1(InfoHash),
+    V2(InfoHashV2),
+}
+
+impl RequestedInfoHash {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            RequestedInfoHash::V1(info_hash) => info_hash.as_slice(),
+            RequestedInfoHash::V2(info_hash_v2) => info_hash_v2.as_slice(),
+        }
+    }
+
+    /// The 20 byte info hash the torrent is looked up by.
+    fn truncate(&self) -> InfoHash {
+        match self {
+            RequestedInfoHash::V1(info_hash) => *info_hash,
+            RequestedInfoHash::V2(info_hash_v2) => info_hash_v2.truncate(),
+        }
+    }
+}
+
+/// Ordered by their bytes, the same as bencoded dictionary keys.
+impl Ord for RequestedInfoHash {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl PartialOrd for RequestedInfoHash {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Extracts the query parameters in the HTTP GET request.
+impl<S> FromRequestParts<S> for Query<Scrape>
+where
+    S: Send + Sync,
+{
+    type Rejection = AnnounceError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query_string = parts.uri.query().unwrap_or_default();
+        let query_bytes = query_string.as_bytes();
+        let query_length = query_bytes.len();
+        let mut pos = 0;
+        let mut ampersand_positions = memchr::memchr_iter(b'&', query_bytes);
+
+        let mut info_hashes: Vec<RequestedInfoHash> = Vec::new();
+
+        for equal_sign_pos in memchr::memchr_iter(b'=', query_bytes) {
+            let value_end_pos = ampersand_positions.next().unwrap_or(query_length);
+
+            let parameter = query_string
+                .get(pos..equal_sign_pos)
+                .ok_or(InvalidQueryStringKey)?;
+            let value = query_string
+                .get(equal_sign_pos + 1..value_end_pos)
+                .ok_or(InvalidQueryStringValue)?;
+
+            if parameter == "info_hash" {
+                if info_hashes.len() == MAX_INFO_HASHES {
+                    return Err(TooManyInfoHashes);
+                }
+
+                info_hashes.push(match utils::urlencoded_to_bytes(value) {
+                    Ok(bytes) => RequestedInfoHash::V1(InfoHash::from(bytes)),
+                    Err(_) => RequestedInfoHash::V2(InfoHashV2::from(
+                        utils::urlencoded_to_bytes(value).or(Err(InvalidInfoHash))?,
+                    )),
+                });
+            }
+
+            if value_end_pos == query_length {
+                break;
+            } else {
+                pos = value_end_pos + 1;
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return Err(MissingInfoHash);
+        }
+
+        // Bencoded dictionary keys must be sorted and unique
+        info_hashes.sort_unstable();
+        info_hashes.dedup();
+
+        Ok(Query(Scrape { info_hashes }))
+    }
+}
+
+/// Swarm statistics of a single torrent as reported in a scrape response.
+pub struct ScrapedTorrent {
+    pub complete: u32,
+    pub downloaded: u32,
+    pub incomplete: u32,
+}
+
+/// Looks up the swarm statistics of each info hash in the given order.
+/// Unregistered, deleted and unapproved torrents are returned as `None` as
+/// if they were never registered.
+fn scrape_torrents(state: &AppState, info_hashes: &[InfoHash]) -> Vec<Option<ScrapedTorrent>> {
+    let torrent_ids: Vec<Option<u32>> = {
+        let infohash2id_guard = state.stores.infohash2id.read();
+
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                infohash2id_guard
+                    .get(info_hash)
+                    .map(|mapping| mapping.torrent_id)
+            })
+            .collect()
+    };
+
+    torrent_ids
+        .into_iter()
+        .map(|torrent_id| {
+            let torrent_id = torrent_id?;
+            let torrent_guard = state.stores.torrents.lock(torrent_id);
+            let torrent = torrent_guard.get(&torrent_id)?;
+
+            if torrent.is_deleted || torrent.status != TorrentStatus::Approved {
+                return None;
+            }
+
+            Some(ScrapedTorrent {
+                complete: torrent.seeders,
+                downloaded: torrent.times_completed,
+                incomplete: torrent.leechers,
+            })
+        })
+        .collect()
+}
+
+/// Looks up the swarm statistics of each info hash in the given order on
+/// behalf of the user owning the passkey. Shared by the HTTP and UDP
+/// trackers so that both apply the same user, group and rate limit checks.
+pub fn scrape_torrents_as_user(
+    state: &AppState,
+    passkey: &str,
+    info_hashes: &[InfoHash],
+) -> Result<Vec<Option<ScrapedTorrent>>, AnnounceError> {
+    let passkey: Passkey = Passkey::from_str(passkey).or(Err(InvalidPasskey))?;
+
+    // Validate passkey
+    let user_id = state
+        .stores
+        .passkey2id
+        .read()
+        .get(&passkey)
+        .cloned()
+        .ok_or(PasskeyNotFound)?;
+
+    let user = state
+        .stores
+        .users
+        .read()
+        .get(&user_id)
+        .cloned()
+        .ok_or(UserNotFound)?;
+
+    let group = state
+        .stores
+        .groups
+        .read()
+        .get(&user.group_id)
+        .ok_or(GroupNotFound)?
+        .clone();
+
+    if ["banned", "validating", "disabled"].contains(&group.slug.as_str()) {
+        return Err(GroupNotEnabled(group.slug));
+    }
+
+    // Users that are over their peer list rate limits don't receive the
+    // seeder and leecher counts either, the same as in announce responses.
+    let is_over_seed_list_rate_limit = user.receive_seed_list_rates.is_over_limit();
+    let is_over_leech_list_rate_limit = user.receive_leech_list_rates.is_over_limit();
+
+    Ok(scrape_torrents(state, info_hashes)
+        .into_iter()
+        .map(|scraped_torrent| {
+            scraped_torrent.map(|scraped_torrent| ScrapedTorrent {
+                complete: if is_over_seed_list_rate_limit {
+                    0
+                } else {
+                    scraped_torrent.complete
+                },
+                downloaded: scraped_torrent.downloaded,
+                incomplete: if is_over_leech_list_rate_limit {
+                    0
+                } else {
+                    scraped_torrent.incomplete
+                },
+            })
+        })
+        .collect())
+}
+
+pub async fn scrape(
+    State(state): State<Arc<AppState>>,
+    Path(passkey): Path<String>,
+    Query(queries): Query<Scrape>,
+) -> Result<Vec<u8>, AnnounceError> {
+    let info_hashes: Vec<InfoHash> = queries
+        .info_hashes
+        .iter()
+        .map(RequestedInfoHash::truncate)
+        .collect();
+
+    let files = queries
+        .info_hashes
+        .into_iter()
+        .zip(scrape_torrents_as_user(&state, &passkey, &info_hashes)?)
+        .filter_map(|(info_hash, scraped_torrent)| {
+            scraped_torrent.map(|scraped_torrent| (info_hash, scraped_torrent))
+        })
+        .collect();
+
+    Ok(ScrapeResponse { files }.into_bencode())
+}
+
+pub struct ScrapeResponse {
+    /// Swarm statistics of each registered info hash, sorted by info hash.
+    pub files: Vec<(RequestedInfoHash, ScrapedTorrent)>,
+}
+
+impl ScrapeResponse {
+    /// Generate bencoded response to return to client
+    pub fn into_bencode(self) -> Vec<u8> {
+        let mut response: Vec<u8> = Vec::with_capacity(
+            10 // literal characters
+                + self.files.len() * (
+                    23 // info hash with length prefix
+                    + 40 // literal characters
+                    + 3 * 5 // numbers with estimated digit quantity for each
+                ),
+        );
+
+        bencode::dictionary(&mut response, |response| {
+            response.dictionary(b"files", |files| {
+                for (info_hash, scraped_torrent) in &self.files {
+                    files.dictionary(info_hash.as_slice(), |file| {
+                        file.integer(b"complete", scraped_torrent.complete)
+                            .integer(b"downloaded", scraped_torrent.downloaded)
+                            .integer(b"incomplete", scraped_torrent.incomplete);
+                    });
+                }
+            });
+        });
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_response() {
+        let response = ScrapeResponse {
+            files: vec![
+                (
+                    RequestedInfoHash::V1(InfoHash([b'a'; 20])),
+                    ScrapedTorrent {
+                        complete: 1,
+                        downloaded: 2,
+                        incomplete: 3,
+                    },
+                ),
+                (
+                    RequestedInfoHash::V2(InfoHashV2([b'b'; 32])),
+                    ScrapedTorrent {
+                        complete: 0,
+                        downloaded: 10,
+                        incomplete: 0,
+                    },
+                ),
+            ],
+        };
+
+        assert_eq!(
+            response.into_bencode(),
+            [
+                b"d5:filesd".as_slice(),
+                b"20:aaaaaaaaaaaaaaaaaaaad8:completei1e10:downloadedi2e10:incompletei3ee",
+                b"32:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbd8:completei0e10:downloadedi10e10:incompletei0ee",
+                b"ee",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn empty_scrape_response() {
+        assert_eq!(
+            ScrapeResponse { files: Vec::new() }.into_bencode(),
+            b"d5:filesdee"
+        );
+    }
+
+    async fn parse(info_hashes: usize) -> Result<Query<Scrape>, AnnounceError> {
+        let query = (0..info_hashes)
+            .map(|i| format!("info_hash={}%{i:02x}", "%00".repeat(19)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let (mut parts, _) = axum::http::Request::builder()
+            .uri(format!("/scrape?{query}"))
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        Query::<Scrape>::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn too_many_info_hashes() {
+        let Query(scrape) = parse(MAX_INFO_HASHES).await.unwrap();
+
+        assert_eq!(scrape.info_hashes.len(), MAX_INFO_HASHES);
+        assert!(matches!(
+            parse(MAX_INFO_HASHES + 1).await,
+            Err(TooManyInfoHashes)
+        ));
+    }
+}
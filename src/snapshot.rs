@@ -0,0 +1,588 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::{
+    model::{
+        info_hash_version::InfoHashVersion, passkey::Passkey, peer_id::PeerId,
+        torrent_status::TorrentStatus,
+    },
+    rate::RateCollection,
+    state::AppState,
+    store::{
+        connectable_port::{ConnectablePort, ConnectablePortStore},
+        peer::{Index, Peer, PeerStore},
+        torrent::{Torrent, TorrentStore},
+        user::{User, UserStore},
+    },
+};
+
+/// Identifies snapshot files.
+const MAGIC: [u8; 8] = *b"U3DASNAP";
+
+/// Has to be incremented on every change to the snapshot format. Snapshots
+/// of other versions are ignored.
+const VERSION: u16 = 1;
+
+/// The in-memory state that isn't stored in the database or is stored late.
+/// Torrents and users are still loaded from the database since they may have
+/// changed while the tracker was offline, so only the peers and the user
+/// rate limits are restored from the snapshot.
+pub struct Snapshot {
+    pub torrents: TorrentStore,
+    pub users: UserStore,
+    pub connectable_ports: ConnectablePortStore,
+}
+
+/// Writes the torrents, users and connectable ports to the snapshot file.
+///
+/// Each store (or shard of the torrent store) is only locked while it's
+/// copied. The copies are encoded and written on a blocking thread, so that
+/// announces don't wait on the encoding or on disk I/O.
+pub async fn save(state: &AppState, path: &Path) -> Result<()> {
+    let start = Instant::now();
+    let snapshot = Snapshot {
+        torrents: state.stores.torrents.clone(),
+        users: state.stores.users.read().clone(),
+        connectable_ports: state.stores.connectable_ports.read().clone(),
+    };
+    let path = path.to_path_buf();
+    let len = tokio::task::spawn_blocking(move || snapshot.write(&path))
+        .await
+        .context("Failed writing snapshot.")??;
+
+    let elapsed = start.elapsed().as_millis();
+    info!("Saved {len} byte snapshot in {elapsed} ms.");
+
+    Ok(())
+}
+
+impl Snapshot {
+    /// Encodes and writes the snapshot to the file, and returns its size.
+    ///
+    /// The file is replaced atomically, so that a crash while writing doesn't
+    /// leave a corrupted snapshot behind.
+    fn write(&self, path: &Path) -> Result<usize> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        MAGIC.encode(&mut buffer);
+        VERSION.encode(&mut buffer);
+        Utc::now().encode(&mut buffer);
+
+        self.torrents.encode(&mut buffer);
+        self.users.encode(&mut buffer);
+        self.connectable_ports.encode(&mut buffer);
+
+        let temporary_path = path.with_extension("tmp");
+        let mut file = File::create(&temporary_path)
+            .with_context(|| format!("Failed creating snapshot {temporary_path:?}."))?;
+
+        file.write_all(&buffer)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed writing snapshot to {temporary_path:?}."))?;
+        fs::rename(&temporary_path, path)
+            .with_context(|| format!("Failed moving snapshot to {path:?}."))?;
+
+        Ok(buffer.len())
+    }
+}
+
+/// Reads the snapshot file if it was written less than `max_age` seconds ago.
+pub async fn load(path: &Path, max_age: u64) -> Result<Snapshot> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed reading snapshot from {path:?}."))?;
+    let mut reader = Reader { bytes: &bytes };
+
+    ensure!(
+        <[u8; 8]>::decode(&mut reader)? == MAGIC,
+        "File is not a snapshot."
+    );
+
+    let version = u16::decode(&mut reader)?;
+
+    ensure!(
+        version == VERSION,
+        "Snapshot version {version} is not supported."
+    );
+
+    let age = Utc::now()
+        .signed_duration_since(DateTime::<Utc>::decode(&mut reader)?)
+        .num_seconds();
+
+    ensure!(
+        age <= i64::try_from(max_age).unwrap_or(i64::MAX),
+        "Snapshot is {age} seconds old."
+    );
+
+    let snapshot = Snapshot {
+        torrents: TorrentStore::decode(&mut reader)?,
+        users: UserStore::decode(&mut reader)?,
+        connectable_ports: ConnectablePortStore::decode(&mut reader)?,
+    };
+
+    ensure!(reader.bytes.is_empty(), "Snapshot has trailing data.");
+
+    Ok(snapshot)
+}
+
+/// Binary encoding of the values stored in a snapshot or a write-ahead log.
+/// Numbers are little endian and collections are prefixed with their length.
+pub trait Encode: Sized {
+    fn encode(&self, buffer: &mut Vec<u8>);
+
+    fn decode(reader: &mut Reader) -> Result<Self>;
+}
+
+pub struct Reader<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let (taken, rest) = self
+            .bytes
+            .split_first_chunk()
+            .context("Data is truncated.")?;
+
+        self.bytes = rest;
+
+        Ok(*taken)
+    }
+}
+
+macro_rules! impl_encode_for_number {
+    ($($number:ty),*) => {
+        $(
+            impl Encode for $number {
+                fn encode(&self, buffer: &mut Vec<u8>) {
+                    buffer.extend(self.to_le_bytes());
+                }
+
+                fn decode(reader: &mut Reader) -> Result<Self> {
+                    Ok(Self::from_le_bytes(reader.take()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_encode_for_number!(u8, u16, u32, u64, i16, i32, i64, f64);
+
+impl Encode for usize {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as u64).encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(u64::decode(reader)?.try_into()?)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as u8).encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => bail!("Invalid boolean {byte}."),
+        }
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        reader.take()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.is_some().encode(buffer);
+
+        if let Some(value) = self {
+            value.encode(buffer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        bool::decode(reader)?.then(|| T::decode(reader)).transpose()
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.len().encode(buffer);
+        buffer.extend(self.as_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let len = usize::decode(reader)?;
+        let (bytes, rest) = reader
+            .bytes
+            .split_at_checked(len)
+            .context("Data is truncated.")?;
+
+        reader.bytes = rest;
+
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+impl Encode for IpAddr {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            IpAddr::V4(ip) => {
+                4u8.encode(buffer);
+                ip.octets().encode(buffer);
+            }
+            IpAddr::V6(ip) => {
+                6u8.encode(buffer);
+                ip.octets().encode(buffer);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        match u8::decode(reader)? {
+            4 => Ok(IpAddr::from(<[u8; 4]>::decode(reader)?)),
+            6 => Ok(IpAddr::from(<[u8; 16]>::decode(reader)?)),
+            family => bail!("Invalid ip family {family}."),
+        }
+    }
+}
+
+impl Encode for SocketAddr {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.ip().encode(buffer);
+        self.port().encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(SocketAddr::from((
+            IpAddr::decode(reader)?,
+            u16::decode(reader)?,
+        )))
+    }
+}
+
+impl Encode for DateTime<Utc> {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.timestamp_micros().encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        DateTime::from_timestamp_micros(i64::decode(reader)?).context("Invalid timestamp.")
+    }
+}
+
+impl Encode for TorrentStatus {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as i16).encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(TorrentStatus::from_i16(i16::decode(reader)?))
+    }
+}
+
+impl Encode for InfoHashVersion {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as u8).encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        match u8::decode(reader)? {
+            1 => Ok(InfoHashVersion::V1),
+            2 => Ok(InfoHashVersion::V2),
+            version => bail!("Invalid info hash version {version}."),
+        }
+    }
+}
+
+impl Encode for Peer {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.ip_address.encode(buffer);
+        self.alternate_ip_address.encode(buffer);
+        self.port.encode(buffer);
+        self.is_seeder.encode(buffer);
+        self.is_active.encode(buffer);
+        self.is_visible.encode(buffer);
+        self.is_connectable.encode(buffer);
+        self.has_sent_completed.encode(buffer);
+        self.updated_at.encode(buffer);
+        self.uploaded.encode(buffer);
+        self.downloaded.encode(buffer);
+        self.is_webrtc.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Peer {
+            ip_address: Encode::decode(reader)?,
+            alternate_ip_address: Encode::decode(reader)?,
+            port: Encode::decode(reader)?,
+            is_seeder: Encode::decode(reader)?,
+            is_active: Encode::decode(reader)?,
+            is_visible: Encode::decode(reader)?,
+            is_connectable: Encode::decode(reader)?,
+            has_sent_completed: Encode::decode(reader)?,
+            updated_at: Encode::decode(reader)?,
+            uploaded: Encode::decode(reader)?,
+            downloaded: Encode::decode(reader)?,
+            is_webrtc: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for PeerStore {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.len().encode(buffer);
+
+        for (index, peer) in self.iter() {
+            index.user_id.encode(buffer);
+            index.peer_id.0.encode(buffer);
+            peer.encode(buffer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let mut peers = PeerStore::new();
+
+        for _ in 0..usize::decode(reader)? {
+            peers.insert(
+                Index {
+                    user_id: Encode::decode(reader)?,
+                    peer_id: PeerId(Encode::decode(reader)?),
+                },
+                Peer::decode(reader)?,
+            );
+        }
+
+        Ok(peers)
+    }
+}
+
+impl Encode for Torrent {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.id.encode(buffer);
+        self.status.encode(buffer);
+        self.is_deleted.encode(buffer);
+        self.peers.encode(buffer);
+        self.seeders.encode(buffer);
+        self.leechers.encode(buffer);
+        self.times_completed.encode(buffer);
+        self.download_factor.encode(buffer);
+        self.upload_factor.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(Torrent {
+            id: Encode::decode(reader)?,
+            status: Encode::decode(reader)?,
+            is_deleted: Encode::decode(reader)?,
+            peers: Encode::decode(reader)?,
+            seeders: Encode::decode(reader)?,
+            leechers: Encode::decode(reader)?,
+            times_completed: Encode::decode(reader)?,
+            download_factor: Encode::decode(reader)?,
+            upload_factor: Encode::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for TorrentStore {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        let start = buffer.len();
+        let mut len: usize = 0;
+
+        // Placeholder for the length, which is only known once every shard
+        // has been encoded
+        len.encode(buffer);
+
+        for shard in self.shards() {
+            let shard = shard.lock();
+
+            len += shard.len();
+
+            for torrent in shard.values() {
+                torrent.encode(buffer);
+            }
+        }
+
+        buffer[start..start + 8].copy_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let mut torrents = TorrentStore::new();
+
+        for _ in 0..usize::decode(reader)? {
+            let torrent = Torrent::decode(reader)?;
+
+            torrents.shard_mut(torrent.id).insert(torrent.id, torrent);
+        }
+
+        Ok(torrents)
+    }
+}
+
+impl Encode for User {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.id.encode(buffer);
+        self.group_id.encode(buffer);
+        self.passkey.0.encode(buffer);
+        self.can_download.encode(buffer);
+        self.num_seeding.encode(buffer);
+        self.num_leeching.encode(buffer);
+        self.is_donor.encode(buffer);
+        self.is_lifetime.encode(buffer);
+        self.receive_seed_list_rates.encode(buffer);
+        self.receive_leech_list_rates.encode(buffer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        Ok(User {
+            id: Encode::decode(reader)?,
+            group_id: Encode::decode(reader)?,
+            passkey: Passkey(Encode::decode(reader)?),
+            can_download: Encode::decode(reader)?,
+            num_seeding: Encode::decode(reader)?,
+            num_leeching: Encode::decode(reader)?,
+            is_donor: Encode::decode(reader)?,
+            is_lifetime: Encode::decode(reader)?,
+            receive_seed_list_rates: RateCollection::decode(reader)?,
+            receive_leech_list_rates: RateCollection::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for UserStore {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.len().encode(buffer);
+
+        for user in self.values() {
+            user.encode(buffer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let mut users = UserStore::new();
+
+        for _ in 0..usize::decode(reader)? {
+            let user = User::decode(reader)?;
+
+            users.insert(user.id, user);
+        }
+
+        Ok(users)
+    }
+}
+
+impl Encode for ConnectablePortStore {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.len().encode(buffer);
+
+        for (socket, connectable_port) in self.iter() {
+            socket.encode(buffer);
+            connectable_port.connectable.encode(buffer);
+            connectable_port.updated_at.encode(buffer);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let mut connectable_ports = ConnectablePortStore::new();
+
+        for _ in 0..usize::decode(reader)? {
+            connectable_ports.insert(
+                SocketAddr::decode(reader)?,
+                ConnectablePort {
+                    connectable: Encode::decode(reader)?,
+                    updated_at: Encode::decode(reader)?,
+                },
+            );
+        }
+
+        Ok(connectable_ports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrents() -> TorrentStore {
+        let mut torrents = TorrentStore::new();
+        let mut torrent = Torrent {
+            id: 1,
+            status: TorrentStatus::Approved,
+            seeders: 1,
+            times_completed: 2,
+            download_factor: 100,
+            upload_factor: 100,
+            ..Default::default()
+        };
+
+        torrent.peers.insert(
+            Index {
+                user_id: 2,
+                peer_id: PeerId(*b"-UT0001-000000000000"),
+            },
+            Peer {
+                ip_address: IpAddr::from([192, 0, 2, 1]),
+                alternate_ip_address: Some(IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1])),
+                port: 6881,
+                is_seeder: true,
+                is_active: true,
+                is_visible: true,
+                is_connectable: false,
+                has_sent_completed: true,
+                updated_at: DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+                uploaded: 1 << 40,
+                downloaded: 0,
+                is_webrtc: false,
+            },
+        );
+        torrents.shard_mut(torrent.id).insert(torrent.id, torrent);
+
+        torrents
+    }
+
+    #[test]
+    fn torrents_round_trip() -> Result<()> {
+        let mut buffer = Vec::new();
+        torrents().encode(&mut buffer);
+
+        let mut reader = Reader { bytes: &buffer };
+        let decoded = TorrentStore::decode(&mut reader)?;
+        assert!(reader.bytes.is_empty());
+
+        let mut reencoded = Vec::new();
+        decoded.encode(&mut reencoded);
+        assert_eq!(buffer, reencoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_torrents() {
+        let mut buffer = Vec::new();
+        torrents().encode(&mut buffer);
+        buffer.pop();
+
+        assert!(TorrentStore::decode(&mut Reader { bytes: &buffer }).is_err());
+    }
+}
@@ -5,9 +5,13 @@ use sqlx::MySqlPool;
 use anyhow::{Context, Result};
 
 use crate::config;
+use crate::connectivity::ConnectivityChecker;
+use crate::metrics::Metrics;
 use crate::queue::Queues;
+use crate::scheduler::Scheduler;
 use crate::stats::Stats;
 use crate::store::Stores;
+use crate::websocket::WebSocketPeers;
 
 use dotenvy::dotenv;
 use sqlx::mysql::MySqlPoolOptions;
@@ -16,10 +20,14 @@ use std::{env, sync::Arc, time::Duration};
 
 pub struct AppState {
     pub config: ArcSwap<config::Config>,
+    pub connectivity_checker: ConnectivityChecker,
+    pub metrics: Metrics,
     pub pool: MySqlPool,
     pub queues: Queues,
+    pub scheduler: Scheduler,
     pub stats: Stats,
     pub stores: Stores,
+    pub websocket_peers: WebSocketPeers,
 }
 
 impl AppState {
@@ -44,14 +52,30 @@ impl AppState {
 
         let stores = Stores::new(&pool, &config).await?;
 
+        let mut queues = Queues::new();
+        queues.set_limits(&config.max_queue_lengths, config.queue_full_policy);
+
+        if let Some(directory) = &config.write_ahead_log_directory {
+            print!("Replaying write-ahead logs                             ... ");
+            io::stdout().flush().unwrap();
+            let updates = queues
+                .replay_logs(directory, config.dead_letter_path.as_deref())
+                .await?;
+            println!("[Finished] Records: {updates}");
+        }
+
         let stats = Stats::default();
 
         Ok(Arc::new(AppState {
             config: ArcSwap::from_pointee(config),
+            connectivity_checker: ConnectivityChecker::default(),
+            metrics: Metrics::new(),
             pool,
-            queues: Queues::new(),
+            queues,
+            scheduler: Scheduler::default(),
             stats,
             stores,
+            websocket_peers: WebSocketPeers::new(),
         }))
     }
 }
@@ -3,7 +3,7 @@ use std::{
         Arc,
         atomic::{AtomicU64, Ordering},
     },
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
@@ -12,8 +12,11 @@ use axum::{
     middleware::Next,
     response::Response,
 };
+use indexmap::IndexMap;
+use serde::Serialize;
+use strum::IntoEnumIterator;
 
-use crate::state::AppState;
+use crate::{scheduler::Job, state::AppState};
 
 pub struct Stats {
     created_at: AtomicF64,
@@ -29,6 +32,13 @@ pub struct Stats {
     announce_responses_per_60s: AtomicF64,
     announce_responses_per_900s: AtomicF64,
     announce_responses_per_7200s: AtomicF64,
+    jobs: IndexMap<Job, JobStats>,
+}
+
+#[derive(Default)]
+struct JobStats {
+    last_run_at: AtomicF64,
+    last_run_duration: AtomicF64,
 }
 
 impl Default for Stats {
@@ -53,6 +63,7 @@ impl Default for Stats {
             announce_responses_per_60s: Default::default(),
             announce_responses_per_900s: Default::default(),
             announce_responses_per_7200s: Default::default(),
+            jobs: Job::iter().map(|job| (job, JobStats::default())).collect(),
         }
     }
 }
@@ -123,6 +134,22 @@ impl Stats {
                 .store(now.as_secs_f64(), Ordering::Relaxed);
         }
     }
+
+    /// Records a run of a scheduled job that just finished.
+    pub fn record_job(&self, job: Job, duration: Duration) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+
+        if let Ok(now) = now {
+            let stats = &self.jobs[&job];
+
+            stats
+                .last_run_at
+                .store((now - duration).as_secs_f64(), Ordering::Relaxed);
+            stats
+                .last_run_duration
+                .store(duration.as_secs_f64(), Ordering::Relaxed);
+        }
+    }
 }
 
 pub async fn show(State(state): State<Arc<AppState>>) -> Json<APIGetStats> {
@@ -162,10 +189,25 @@ pub async fn show(State(state): State<Arc<AppState>>) -> Json<APIGetStats> {
             .announce_responses_per_7200s
             .load(Ordering::Relaxed)
             / 7200f64,
+        jobs: state
+            .stats
+            .jobs
+            .iter()
+            .map(|(job, stats)| {
+                (
+                    job.into(),
+                    APIGetJobStats {
+                        last_run_at: stats.last_run_at.load(Ordering::Relaxed),
+                        last_run_duration: stats.last_run_duration.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect(),
+        connectable_ports: state.stores.connectable_ports.read().len(),
     })
 }
 
-#[derive(serde::Serialize)]
+#[derive(Serialize)]
 pub struct APIGetStats {
     created_at: f64,
     last_request_at: f64,
@@ -180,6 +222,17 @@ pub struct APIGetStats {
     announce_responses_per_60s: f64,
     announce_responses_per_900s: f64,
     announce_responses_per_7200s: f64,
+    jobs: IndexMap<&'static str, APIGetJobStats>,
+    /// Amount of sockets with a cached connectivity.
+    connectable_ports: usize,
+}
+
+/// Start time and duration in seconds of the last run of a scheduled job.
+/// The start time is 0 if the job has never run.
+#[derive(Serialize)]
+pub struct APIGetJobStats {
+    last_run_at: f64,
+    last_run_duration: f64,
 }
 
 pub async fn record_request(
@@ -192,7 +245,7 @@ pub async fn record_request(
     next.run(request).await
 }
 
-struct AtomicF64 {
+pub struct AtomicF64 {
     content: AtomicU64,
 }
 
@@ -211,7 +264,7 @@ impl AtomicF64 {
         }
     }
 
-    fn load(&self, order: Ordering) -> f64 {
+    pub fn load(&self, order: Ordering) -> f64 {
         f64::from_bits(self.content.load(order))
     }
 
@@ -219,7 +272,7 @@ impl AtomicF64 {
         self.content.store(value.to_bits(), order)
     }
 
-    fn fetch_update<F>(&self, set_order: Ordering, fetch_order: Ordering, mut f: F)
+    pub fn fetch_update<F>(&self, set_order: Ordering, fetch_order: Ordering, mut f: F)
     where
         F: FnMut(f64) -> f64,
     {
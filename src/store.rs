@@ -1,5 +1,7 @@
+pub mod banned_ip_range;
 pub mod blacklisted_agent;
 pub mod blacklisted_port;
+pub mod client_rule;
 pub mod connectable_port;
 pub mod featured_torrent;
 pub mod freeleech_token;
@@ -17,8 +19,10 @@ use anyhow::{Context, Result};
 
 use crate::{
     config::{self, Config},
+    snapshot::{self, Snapshot},
     store::{
-        blacklisted_agent::BlacklistedAgentStore, blacklisted_port::BlacklistedPortStore,
+        banned_ip_range::BannedIpRangeStore, blacklisted_agent::BlacklistedAgentStore,
+        blacklisted_port::BlacklistedPortStore, client_rule::ClientRuleStore,
         connectable_port::ConnectablePortStore, featured_torrent::FeaturedTorrentStore,
         freeleech_token::FreeleechTokenStore, group::GroupStore, infohash2id::InfoHash2IdStore,
         passkey2id::Passkey2IdStore, personal_freeleech::PersonalFreeleechStore,
@@ -26,11 +30,23 @@ use crate::{
     },
 };
 
-use parking_lot::{Mutex, RwLock};
+use parking_lot::RwLock;
 use std::io::{self, Write};
 
+/// Returns true if the query failed because the table doesn't exist, such as
+/// when an optional table hasn't been created.
+pub fn is_missing_table(error: &sqlx::Error) -> bool {
+    match error {
+        // Base table or view not found
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("42S02"),
+        _ => false,
+    }
+}
+
 pub struct Stores {
     pub agent_blacklist: RwLock<BlacklistedAgentStore>,
+    pub banned_ip_ranges: RwLock<BannedIpRangeStore>,
+    pub client_rules: RwLock<ClientRuleStore>,
     pub connectable_ports: RwLock<ConnectablePortStore>,
     pub featured_torrents: RwLock<FeaturedTorrentStore>,
     pub freeleech_tokens: RwLock<FreeleechTokenStore>,
@@ -39,78 +55,148 @@ pub struct Stores {
     pub passkey2id: RwLock<Passkey2IdStore>,
     pub personal_freeleeches: RwLock<PersonalFreeleechStore>,
     pub port_blacklist: RwLock<BlacklistedPortStore>,
-    pub torrents: Mutex<TorrentStore>,
+    pub torrents: TorrentStore,
     pub users: RwLock<UserStore>,
 }
 
 impl Stores {
-    /// Load all in-memory stores from the database.
+    /// Load all in-memory stores from the database. The peers, connectable
+    /// ports and user rate limits are restored from the snapshot if a recent
+    /// enough one exists.
     pub async fn new(pool: &MySqlPool, config: &Config) -> Result<Stores> {
-        print!("Synchronizing peer counts                              ... ");
-        io::stdout().flush().unwrap();
-        sync_peer_count_aggregates(&pool, &config).await?;
-        println!("[Finished]");
+        let snapshot = if let Some(path) = &config.snapshot_path {
+            print!("Loading snapshot                                       ... ");
+            io::stdout().flush().unwrap();
+
+            match snapshot::load(path, config.snapshot_max_age).await {
+                Ok(snapshot) => {
+                    println!("[Finished]");
+
+                    Some(snapshot)
+                }
+                Err(e) => {
+                    println!("[Skipped] {e:#}");
+
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let (snapshot_torrents, snapshot_users, snapshot_connectable_ports) = match snapshot {
+            Some(Snapshot {
+                torrents,
+                users,
+                connectable_ports,
+            }) => (Some(torrents), Some(users), Some(connectable_ports)),
+            None => {
+                // The peer counts of torrents restored from the snapshot are
+                // recalculated instead
+                print!("Synchronizing peer counts                              ... ");
+                io::stdout().flush().unwrap();
+                sync_peer_count_aggregates(&pool, &config).await?;
+                println!("[Finished]");
+
+                (None, None, None)
+            }
+        };
 
         println!("Loading entities from database into memory...");
-        print!("Starting to load  1/11: blacklisted ports              ... ");
+        print!("Starting to load  1/13: blacklisted ports              ... ");
         io::stdout().flush().unwrap();
-        let port_blacklist = BlacklistedPortStore::default();
+        let port_blacklist = BlacklistedPortStore::from_db(pool).await?;
         println!("[Finished] Records: {:?}", port_blacklist.len());
 
-        print!("Starting to load  2/11: blacklisted user agents        ... ");
+        print!("Starting to load  2/13: blacklisted user agents        ... ");
         io::stdout().flush().unwrap();
         let agent_blacklist = BlacklistedAgentStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", agent_blacklist.len());
 
-        print!("Starting to load  3/11: torrents                       ... ");
+        print!("Starting to load  3/13: torrents                       ... ");
         io::stdout().flush().unwrap();
-        let torrents = TorrentStore::from_db(&pool).await?;
+        let mut torrents = TorrentStore::from_db(pool, config).await?;
+
+        // Torrents are always loaded from the database, since they may have
+        // been created, deleted or moderated while the tracker was offline.
+        // Only the peers are restored from the snapshot.
+        if let Some(snapshot_torrents) = snapshot_torrents {
+            torrents.restore_peers(snapshot_torrents, config);
+        }
         println!("[Finished] Records: {:?}", torrents.len());
 
-        print!("Starting to load  4/11: infohash to torrent id mappings... ");
+        print!("Starting to load  4/13: infohash to torrent id mappings... ");
         io::stdout().flush().unwrap();
         let infohash2id = InfoHash2IdStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", infohash2id.len());
 
-        print!("Starting to load  5/11: users                          ... ");
+        print!("Starting to load  5/13: users                          ... ");
         io::stdout().flush().unwrap();
-        let users = UserStore::from_db(&pool, &config).await?;
+        let mut users = UserStore::from_db(&pool, &config).await?;
+
+        // Users are always loaded from the database, since their passkey,
+        // group or permissions may have changed while the tracker was
+        // offline. Only the rate limits, which are never persisted to the
+        // database, are restored from the snapshot.
+        if let Some(mut snapshot_users) = snapshot_users {
+            for (user_id, snapshot_user) in snapshot_users.drain(..) {
+                if let Some(user) = users.get_mut(&user_id) {
+                    user.receive_seed_list_rates = snapshot_user.receive_seed_list_rates;
+                    user.receive_leech_list_rates = snapshot_user.receive_leech_list_rates;
+                }
+            }
+        }
         println!("[Finished] Records: {:?}", users.len());
 
-        print!("Starting to load  6/11: passkey to user id mappings    ... ");
+        print!("Starting to load  6/13: passkey to user id mappings    ... ");
         io::stdout().flush().unwrap();
         let passkey2id = Passkey2IdStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", passkey2id.len());
 
-        print!("Starting to load  7/11: connectable ports              ... ");
+        print!("Starting to load  7/13: connectable ports              ... ");
         io::stdout().flush().unwrap();
-        let connectable_ports = ConnectablePortStore::from_db(&pool).await?;
+        let connectable_ports = match snapshot_connectable_ports {
+            Some(connectable_ports) => connectable_ports,
+            None => ConnectablePortStore::from_db(&pool).await?,
+        };
         println!("[Finished] Records: {:?}", connectable_ports.len());
 
-        print!("Starting to load  8/11: freeleech tokens               ... ");
+        print!("Starting to load  8/13: freeleech tokens               ... ");
         io::stdout().flush().unwrap();
         let freeleech_tokens = FreeleechTokenStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", freeleech_tokens.len());
 
-        print!("Starting to load  9/11: personal freeleeches           ... ");
+        print!("Starting to load  9/13: personal freeleeches           ... ");
         io::stdout().flush().unwrap();
         let personal_freeleeches = PersonalFreeleechStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", personal_freeleeches.len());
 
-        print!("Starting to load 10/11: featured torrents              ... ");
+        print!("Starting to load 10/13: featured torrents              ... ");
         io::stdout().flush().unwrap();
         let featured_torrents = FeaturedTorrentStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", featured_torrents.len());
 
-        print!("Starting to load 11/11: groups                         ... ");
+        print!("Starting to load 11/13: groups                         ... ");
         io::stdout().flush().unwrap();
         let groups = GroupStore::from_db(&pool).await?;
         println!("[Finished] Records: {:?}", groups.len());
 
+        print!("Starting to load 12/13: client rules                   ... ");
+        io::stdout().flush().unwrap();
+        let client_rules = ClientRuleStore::from_db(pool).await?;
+        println!("[Finished] Records: {:?}", client_rules.len());
+
+        print!("Starting to load 13/13: banned ip ranges               ... ");
+        io::stdout().flush().unwrap();
+        let banned_ip_ranges = BannedIpRangeStore::from_db(pool).await?;
+        println!("[Finished] Records: {:?}", banned_ip_ranges.len());
+
         println!("All entities loaded into memory.");
 
         Ok(Stores {
             agent_blacklist: RwLock::new(agent_blacklist),
+            banned_ip_ranges: RwLock::new(banned_ip_ranges),
+            client_rules: RwLock::new(client_rules),
             connectable_ports: RwLock::new(connectable_ports),
             freeleech_tokens: RwLock::new(freeleech_tokens),
             featured_torrents: RwLock::new(featured_torrents),
@@ -119,7 +205,7 @@ impl Stores {
             passkey2id: RwLock::new(passkey2id),
             personal_freeleeches: RwLock::new(personal_freeleeches),
             port_blacklist: RwLock::new(port_blacklist),
-            torrents: Mutex::new(torrents),
+            torrents,
             users: RwLock::new(users),
         })
     }
@@ -164,3 +250,60 @@ async fn sync_peer_count_aggregates(db: &MySqlPool, config: &config::Config) ->
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, error::Error, fmt};
+
+    use sqlx::error::{DatabaseError, ErrorKind};
+
+    use super::*;
+
+    /// Database error with the given SQLSTATE.
+    #[derive(Debug)]
+    struct StubDatabaseError(&'static str);
+
+    impl fmt::Display for StubDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl Error for StubDatabaseError {}
+
+    impl DatabaseError for StubDatabaseError {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    #[test]
+    fn missing_table() {
+        let error = |sql_state| sqlx::Error::Database(Box::new(StubDatabaseError(sql_state)));
+
+        assert!(is_missing_table(&error("42S02")));
+        // Syntax error or access violation
+        assert!(!is_missing_table(&error("42000")));
+        assert!(!is_missing_table(&sqlx::Error::RowNotFound));
+    }
+}
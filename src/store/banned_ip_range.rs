@@ -0,0 +1,235 @@
+use std::{net::IpAddr, ops::Deref};
+
+use indexmap::IndexSet;
+use ipnet::IpNet;
+use sqlx::{MySqlPool, Row};
+use tracing::error;
+
+use anyhow::{Context, Result};
+
+use super::is_missing_table;
+
+/// IPv4 and IPv6 networks clients may not announce from.
+pub struct BannedIpRangeStore {
+    inner: IndexSet<IpNet>,
+    ipv4: PrefixTrie,
+    ipv6: PrefixTrie,
+}
+
+impl BannedIpRangeStore {
+    pub fn new() -> BannedIpRangeStore {
+        BannedIpRangeStore {
+            inner: IndexSet::new(),
+            ipv4: PrefixTrie::default(),
+            ipv6: PrefixTrie::default(),
+        }
+    }
+
+    /// Loads the ranges from the `banned_ip_ranges` table, or no ranges if
+    /// the table doesn't exist. Ranges that can't be parsed are skipped.
+    pub async fn from_db(db: &MySqlPool) -> Result<BannedIpRangeStore> {
+        let rows = sqlx::query(
+            r#"
+                SELECT
+                    ip_range
+                FROM
+                    banned_ip_ranges
+            "#,
+        )
+        .fetch_all(db)
+        .await;
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) if is_missing_table(&e) => {
+                return Ok(BannedIpRangeStore::new());
+            }
+            Err(e) => return Err(e).context("Failed loading banned ip ranges."),
+        };
+
+        let mut store = BannedIpRangeStore::new();
+
+        for row in rows {
+            let ip_range: String = row
+                .try_get("ip_range")
+                .context("Failed loading banned ip ranges.")?;
+
+            match ip_range.parse() {
+                Ok(ip_range) => {
+                    store.insert(ip_range);
+                }
+                Err(e) => error!("Skipping banned ip range `{ip_range}`: {e}"),
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Bans the range. Host bits are ignored, so that `192.0.2.1/24` bans
+    /// `192.0.2.0/24`. Returns false if the range was already banned.
+    pub fn insert(&mut self, ip_range: IpNet) -> bool {
+        let ip_range = ip_range.trunc();
+
+        if !self.inner.insert(ip_range) {
+            return false;
+        }
+
+        self.insert_into_trie(ip_range);
+
+        true
+    }
+
+    /// Unbans the range. Returns false if the range wasn't banned.
+    pub fn remove(&mut self, ip_range: &IpNet) -> bool {
+        if !self.inner.shift_remove(&ip_range.trunc()) {
+            return false;
+        }
+
+        // Removals are rare, so the tries are rebuilt instead of pruned
+        self.ipv4 = PrefixTrie::default();
+        self.ipv6 = PrefixTrie::default();
+
+        for ip_range in self.inner.clone() {
+            self.insert_into_trie(ip_range);
+        }
+
+        true
+    }
+
+    /// Whether the ip address is within a banned range. IPv4-mapped IPv6
+    /// addresses are matched against the IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.ipv4.contains(u128::from(ip.to_bits()) << 96),
+            IpAddr::V6(ip) => self.ipv6.contains(ip.to_bits()),
+        }
+    }
+
+    fn insert_into_trie(&mut self, ip_range: IpNet) {
+        match ip_range {
+            IpNet::V4(net) => self
+                .ipv4
+                .insert(u128::from(net.addr().to_bits()) << 96, net.prefix_len()),
+            IpNet::V6(net) => self.ipv6.insert(net.addr().to_bits(), net.prefix_len()),
+        }
+    }
+}
+
+impl Deref for BannedIpRangeStore {
+    type Target = IndexSet<IpNet>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// Binary trie of the banned prefixes of one address family. Addresses are
+/// left-aligned in 128 bits and walked from the most significant bit, so a
+/// lookup takes at most one step per prefix bit regardless of the amount of
+/// banned ranges.
+#[derive(Default)]
+struct PrefixTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    /// Indexes of the child nodes for a 0 and a 1 bit. The root is never a
+    /// child, so 0 means there's no child.
+    children: [u32; 2],
+    /// Whether the prefix ending at this node is banned.
+    is_banned: bool,
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: u128, prefix_len: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+
+        let mut node = 0;
+
+        for depth in 0..prefix_len {
+            let bit = bit_at(bits, depth);
+
+            if self.nodes[node].children[bit] == 0 {
+                self.nodes[node].children[bit] = self.nodes.len() as u32;
+                self.nodes.push(Node::default());
+            }
+
+            node = self.nodes[node].children[bit] as usize;
+        }
+
+        self.nodes[node].is_banned = true;
+    }
+
+    fn contains(&self, bits: u128) -> bool {
+        let Some(mut node) = self.nodes.first() else {
+            return false;
+        };
+
+        for depth in 0..128 {
+            if node.is_banned {
+                return true;
+            }
+
+            match node.children[bit_at(bits, depth)] {
+                0 => return false,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+
+        node.is_banned
+    }
+}
+
+fn bit_at(bits: u128, depth: u8) -> usize {
+    (bits >> (127 - depth) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(ip_ranges: &[&str]) -> BannedIpRangeStore {
+        let mut store = BannedIpRangeStore::new();
+
+        for ip_range in ip_ranges {
+            store.insert(ip_range.parse().unwrap());
+        }
+
+        store
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let store = store(&["192.0.2.0/24", "198.51.100.7/32", "2001:db8::/32"]);
+
+        for (ip, is_banned) in [
+            ("192.0.2.1", true),
+            ("192.0.3.1", false),
+            ("198.51.100.7", true),
+            ("198.51.100.8", false),
+            ("::ffff:192.0.2.1", true),
+            ("2001:db8:1::1", true),
+            ("2001:db9::1", false),
+        ] {
+            assert_eq!(store.contains(ip.parse().unwrap()), is_banned, "{ip}");
+        }
+    }
+
+    #[test]
+    fn removes_ranges() {
+        let mut store = store(&["192.0.2.0/24", "192.0.0.0/16", "0.0.0.0/0"]);
+
+        assert!(store.contains("203.0.113.1".parse().unwrap()));
+        assert!(!store.contains("2001:db8::1".parse().unwrap()));
+
+        assert!(store.remove(&"0.0.0.0/0".parse().unwrap()));
+        assert!(store.remove(&"192.0.2.9/24".parse().unwrap()));
+        assert!(!store.remove(&"192.0.2.0/24".parse().unwrap()));
+
+        assert!(!store.contains("203.0.113.1".parse().unwrap()));
+        assert!(store.contains("192.0.2.1".parse().unwrap()));
+    }
+}
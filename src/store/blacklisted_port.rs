@@ -1,44 +1,118 @@
 use std::ops::{Deref, DerefMut};
 
 use indexmap::IndexSet;
+use serde::{Deserialize, Serialize};
+use sqlx::{MySqlPool, Row};
+use tracing::error;
+
+use anyhow::{Context, Result};
+
+use super::is_missing_table;
 
 pub struct BlacklistedPortStore {
-    inner: IndexSet<u16>,
+    inner: IndexSet<PortRange>,
 }
 
 impl Default for BlacklistedPortStore {
+    /// Ports used when the `blacklisted_ports` table doesn't exist.
     #[rustfmt::skip]
     fn default() -> BlacklistedPortStore {
-        let mut set = IndexSet::from([
-            // Hyper Text Transfer Protocol (HTTP) - port used for web traffic
-            8080,
-            8081,
-            // Kazaa - peer-to-peer file sharing, some known vulnerabilities, and at least one worm (Benjamin) targeting it.
-            1214,
-            // IANA registered for Microsoft WBT Server, used for Windows Remote Desktop and Remote Assistance connections
-            3389,
-            // eDonkey 2000 P2P file sharing service. http://www.edonkey2000.com/
-            4662,
-            // Gnutella (FrostWire, Limewire, Shareaza, etc.), BearShare file sharing app
-            6346,
-            6347,
-            // Port used by p2p software, such as WinMX, Napster.
-            6699,
-        ]);
-
-        // Block system-reserved ports (requires root for clients to listen on these ports)
-        for system_reserved_port in 0..1024 {
-            set.insert(system_reserved_port);
+        BlacklistedPortStore {
+            inner: IndexSet::from([
+                // Block system-reserved ports (requires root for clients to listen on these ports)
+                PortRange { min_port: 0, max_port: 1023 },
+                // Kazaa - peer-to-peer file sharing, some known vulnerabilities, and at least one worm (Benjamin) targeting it.
+                PortRange { min_port: 1214, max_port: 1214 },
+                // IANA registered for Microsoft WBT Server, used for Windows Remote Desktop and Remote Assistance connections
+                PortRange { min_port: 3389, max_port: 3389 },
+                // eDonkey 2000 P2P file sharing service. http://www.edonkey2000.com/
+                PortRange { min_port: 4662, max_port: 4662 },
+                // Gnutella (FrostWire, Limewire, Shareaza, etc.), BearShare file sharing app
+                PortRange { min_port: 6346, max_port: 6347 },
+                // Port used by p2p software, such as WinMX, Napster.
+                PortRange { min_port: 6699, max_port: 6699 },
+                // Hyper Text Transfer Protocol (HTTP) - port used for web traffic
+                PortRange { min_port: 8080, max_port: 8081 },
+            ]),
         }
+    }
+}
 
+impl BlacklistedPortStore {
+    pub fn new() -> BlacklistedPortStore {
         BlacklistedPortStore {
-            inner: set
+            inner: IndexSet::new(),
         }
     }
+
+    /// Loads the port ranges from the `blacklisted_ports` table, or the
+    /// default ports if the table doesn't exist. Ranges whose min port is
+    /// greater than their max port are skipped.
+    pub async fn from_db(db: &MySqlPool) -> Result<BlacklistedPortStore> {
+        let rows = sqlx::query(
+            r#"
+                SELECT
+                    min_port,
+                    max_port
+                FROM
+                    blacklisted_ports
+            "#,
+        )
+        .fetch_all(db)
+        .await;
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) if is_missing_table(&e) => {
+                return Ok(BlacklistedPortStore::default());
+            }
+            Err(e) => return Err(e).context("Failed loading blacklisted ports."),
+        };
+
+        let mut store = BlacklistedPortStore::new();
+
+        for row in rows {
+            let port_range = PortRange {
+                min_port: row
+                    .try_get("min_port")
+                    .context("Failed loading blacklisted ports.")?,
+                max_port: row
+                    .try_get("max_port")
+                    .context("Failed loading blacklisted ports.")?,
+            };
+
+            if port_range.is_valid() {
+                store.insert(port_range);
+            } else {
+                error!("Skipping blacklisted port range {port_range}: min port exceeds max port.");
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Whether the port is within a blacklisted range.
+    pub fn contains(&self, port: u16) -> bool {
+        self.iter().any(|port_range| port_range.contains(port))
+    }
+
+    /// Lists the blacklisted ranges in ascending order, such as
+    /// `0-1023, 1214, 6346-6347`.
+    pub fn describe(&self) -> String {
+        let mut port_ranges: Vec<_> = self.iter().collect();
+
+        port_ranges.sort_unstable();
+
+        port_ranges
+            .iter()
+            .map(|port_range| port_range.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
 impl Deref for BlacklistedPortStore {
-    type Target = IndexSet<u16>;
+    type Target = IndexSet<PortRange>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
@@ -50,3 +124,58 @@ impl DerefMut for BlacklistedPortStore {
         &mut self.inner
     }
 }
+
+/// Inclusive range of blacklisted ports.
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PortRange {
+    pub min_port: u16,
+    pub max_port: u16,
+}
+
+impl PortRange {
+    pub fn is_valid(&self) -> bool {
+        self.min_port <= self.max_port
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.min_port..=self.max_port).contains(&port)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min_port == self.max_port {
+            write!(f, "{}", self.min_port)
+        } else {
+            write!(f, "{}-{}", self.min_port, self.max_port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_ports() {
+        let store = BlacklistedPortStore::default();
+
+        for (port, is_blacklisted) in [
+            (0, true),
+            (80, true),
+            (1023, true),
+            (1024, false),
+            (6347, true),
+            (6881, false),
+            (8081, true),
+            (65535, false),
+        ] {
+            assert_eq!(store.contains(port), is_blacklisted, "{port}");
+        }
+
+        assert_eq!(
+            store.describe(),
+            "0-1023, 1214, 3389, 4662, 6346-6347, 6699, 8080-8081"
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use std::ops::{Deref, DerefMut};
+
+use indexmap::IndexMap;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::{MySqlPool, Row, mysql::MySqlRow};
+use strum::EnumString;
+use tracing::error;
+
+use anyhow::{Context, Result};
+
+use crate::error::AnnounceError::{self, BlockedClient, ClientNotAllowed};
+
+use super::is_missing_table;
+
+/// Failure reason of block rules without a custom reason.
+const DEFAULT_BLOCK_REASON: &str = "Client is not acceptable. Please check our blacklist.";
+
+/// Rules deciding which clients may announce, keyed by rule id.
+pub struct ClientRuleStore {
+    inner: IndexMap<u32, ClientRule>,
+}
+
+impl ClientRuleStore {
+    pub fn new() -> ClientRuleStore {
+        ClientRuleStore {
+            inner: IndexMap::new(),
+        }
+    }
+
+    /// Rules used when the `client_rules` table doesn't exist, blocking
+    /// browsers and crawlers.
+    pub fn default_rules() -> ClientRuleStore {
+        let mut store = ClientRuleStore::new();
+
+        store.insert(
+            1,
+            ClientRule::new(DBImportClientRule {
+                id: 1,
+                action: Action::Block,
+                user_agent_pattern: Some(
+                    "mozilla|browser|chrome|safari|applewebkit|opera|links|lynx|bot|unknown"
+                        .to_string(),
+                ),
+                peer_id_prefix: None,
+                min_version: None,
+                max_version: None,
+                reason: Some("Browser, crawler or cheater is not allowed.".to_string()),
+            })
+            .expect("Default client rule is invalid."),
+        );
+
+        store
+    }
+
+    /// Loads the rules from the `client_rules` table, or the default rules if
+    /// the table doesn't exist. Rules that can't be parsed are skipped.
+    pub async fn from_db(db: &MySqlPool) -> Result<ClientRuleStore> {
+        let rows = sqlx::query(
+            r#"
+                SELECT
+                    id,
+                    action,
+                    user_agent_pattern,
+                    peer_id_prefix,
+                    min_version,
+                    max_version,
+                    reason
+                FROM
+                    client_rules
+            "#,
+        )
+        .fetch_all(db)
+        .await;
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) if is_missing_table(&e) => {
+                return Ok(ClientRuleStore::default_rules());
+            }
+            Err(e) => return Err(e).context("Failed loading client rules."),
+        };
+
+        let mut store = ClientRuleStore::new();
+
+        for row in rows {
+            let id: u32 = row.try_get("id").context("Failed loading client rules.")?;
+
+            match DBImportClientRule::from_row(&row).and_then(ClientRule::new) {
+                Ok(rule) => {
+                    store.insert(id, rule);
+                }
+                Err(e) => error!("Skipping client rule {id}: {e:#}"),
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Checks whether the client may announce. Block rules take precedence
+    /// over allow rules. If the allow list is enabled, clients have to match
+    /// at least one allow rule. The user agent is `None` for transports that
+    /// don't send one.
+    pub fn check(
+        &self,
+        user_agent: Option<&str>,
+        peer_id: &[u8],
+        is_allow_list_enabled: bool,
+    ) -> Result<(), AnnounceError> {
+        let mut is_allowed = !is_allow_list_enabled;
+
+        for rule in self.values() {
+            if !rule.matches(user_agent, peer_id) {
+                continue;
+            }
+
+            match rule.action {
+                Action::Allow => is_allowed = true,
+                Action::Block => {
+                    return Err(BlockedClient(
+                        rule.reason
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_BLOCK_REASON.to_string()),
+                    ));
+                }
+            }
+        }
+
+        if is_allowed {
+            Ok(())
+        } else {
+            Err(ClientNotAllowed)
+        }
+    }
+}
+
+impl Deref for ClientRuleStore {
+    type Target = IndexMap<u32, ClientRule>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ClientRuleStore {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, EnumString, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    /// Clients matching the rule are permitted when the allow list is
+    /// enabled.
+    Allow,
+    /// Clients matching the rule are refused.
+    Block,
+}
+
+/// Client rule as stored in the database and received by the API.
+#[derive(Clone, Deserialize)]
+pub struct DBImportClientRule {
+    pub id: u32,
+    pub action: Action,
+    /// Case insensitive regex matched against the user agent.
+    pub user_agent_pattern: Option<String>,
+    #[serde(default, with = "serde_bytes")]
+    pub peer_id_prefix: Option<Vec<u8>>,
+    /// Lowest version allowed to match, compared bytewise against the bytes
+    /// of the peer id following the prefix, such as `4500` for `-qB4500-`.
+    #[serde(default, with = "serde_bytes")]
+    pub min_version: Option<Vec<u8>>,
+    /// Highest version allowed to match, compared the same way as the min
+    /// version.
+    #[serde(default, with = "serde_bytes")]
+    pub max_version: Option<Vec<u8>>,
+    /// Failure reason returned to blocked clients.
+    pub reason: Option<String>,
+}
+
+/// Rule matching clients by user agent, peer id prefix and version. A rule
+/// matches if every condition it specifies matches.
+#[derive(Clone, Serialize)]
+pub struct ClientRule {
+    pub action: Action,
+    #[serde(serialize_with = "serialize_regex")]
+    pub user_agent_pattern: Option<Regex>,
+    #[serde(with = "serde_bytes")]
+    pub peer_id_prefix: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes")]
+    pub min_version: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes")]
+    pub max_version: Option<Vec<u8>>,
+    pub reason: Option<String>,
+}
+
+impl DBImportClientRule {
+    fn from_row(row: &MySqlRow) -> Result<DBImportClientRule> {
+        let action: String = row.try_get("action")?;
+
+        Ok(DBImportClientRule {
+            id: row.try_get("id")?,
+            action: action
+                .parse()
+                .with_context(|| format!("Invalid action `{action}`."))?,
+            user_agent_pattern: row.try_get("user_agent_pattern")?,
+            peer_id_prefix: row.try_get("peer_id_prefix")?,
+            min_version: row.try_get("min_version")?,
+            max_version: row.try_get("max_version")?,
+            reason: row.try_get("reason")?,
+        })
+    }
+}
+
+impl ClientRule {
+    pub fn new(rule: DBImportClientRule) -> Result<ClientRule> {
+        let user_agent_pattern = rule
+            .user_agent_pattern
+            .map(|pattern| {
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Invalid user agent pattern `{pattern}`."))
+            })
+            .transpose()?;
+
+        Ok(ClientRule {
+            action: rule.action,
+            user_agent_pattern,
+            peer_id_prefix: rule.peer_id_prefix,
+            min_version: rule.min_version,
+            max_version: rule.max_version,
+            reason: rule.reason,
+        })
+    }
+
+    /// Without a user agent, allow rules are matched by their peer id
+    /// conditions only, so that UDP and WebTorrent clients can be allowed.
+    /// Rules that would have to be matched by their user agent alone, and
+    /// block rules depending on the user agent, never match.
+    fn matches(&self, user_agent: Option<&str>, peer_id: &[u8]) -> bool {
+        let has_peer_id_conditions = self.peer_id_prefix.is_some()
+            || self.min_version.is_some()
+            || self.max_version.is_some();
+
+        match (&self.user_agent_pattern, user_agent) {
+            (Some(pattern), Some(user_agent)) if !pattern.is_match(user_agent) => return false,
+            (Some(_), None) if self.action == Action::Block || !has_peer_id_conditions => {
+                return false;
+            }
+            _ => (),
+        }
+
+        let prefix = self.peer_id_prefix.as_deref().unwrap_or_default();
+
+        let Some(version) = peer_id.strip_prefix(prefix) else {
+            return false;
+        };
+
+        self.min_version.as_ref().is_none_or(|min| {
+            version
+                .get(..min.len())
+                .is_some_and(|v| v >= min.as_slice())
+        }) && self.max_version.as_ref().is_none_or(|max| {
+            version
+                .get(..max.len())
+                .is_some_and(|v| v <= max.as_slice())
+        })
+    }
+}
+
+fn serialize_regex<S: serde::Serializer>(
+    regex: &Option<Regex>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    regex.as_ref().map(Regex::as_str).serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: Action) -> DBImportClientRule {
+        DBImportClientRule {
+            id: 1,
+            action,
+            user_agent_pattern: None,
+            peer_id_prefix: None,
+            min_version: None,
+            max_version: None,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn version_range() -> Result<()> {
+        let rule = ClientRule::new(DBImportClientRule {
+            peer_id_prefix: Some(b"-qB".to_vec()),
+            min_version: Some(b"4500".to_vec()),
+            max_version: Some(b"4699".to_vec()),
+            ..rule(Action::Block)
+        })?;
+
+        assert!(rule.matches(Some(""), b"-qB4650-000000000000"));
+        assert!(!rule.matches(Some(""), b"-qB4400-000000000000"));
+        assert!(!rule.matches(Some(""), b"-qB5000-000000000000"));
+        assert!(!rule.matches(Some(""), b"-TR4650-000000000000"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_reason() {
+        let store = ClientRuleStore::default_rules();
+
+        assert!(
+            matches!(store.check(Some("Mozilla/5.0"), b"-qB4650-000000000000", false), Err(BlockedClient(reason)) if reason == "Browser, crawler or cheater is not allowed.")
+        );
+        assert!(
+            store
+                .check(Some("qBittorrent/4.6.5"), b"-qB4650-000000000000", false)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn allow_list() -> Result<()> {
+        let mut store = ClientRuleStore::new();
+
+        store.insert(
+            1,
+            ClientRule::new(DBImportClientRule {
+                user_agent_pattern: Some("^qBittorrent/".to_string()),
+                ..rule(Action::Allow)
+            })?,
+        );
+
+        assert!(
+            store
+                .check(Some("qBittorrent/4.6.5"), b"-qB4650-", true)
+                .is_ok()
+        );
+        assert!(matches!(
+            store.check(Some("Transmission/4.0.6"), b"-TR4060-", true),
+            Err(ClientNotAllowed)
+        ));
+        assert!(
+            store
+                .check(Some("Transmission/4.0.6"), b"-TR4060-", false)
+                .is_ok()
+        );
+
+        // Without a user agent, only the peer id half of a rule is checked
+        assert!(matches!(
+            store.check(None, b"-qB4650-", true),
+            Err(ClientNotAllowed)
+        ));
+
+        store.insert(
+            2,
+            ClientRule::new(DBImportClientRule {
+                user_agent_pattern: Some("^qBittorrent/".to_string()),
+                peer_id_prefix: Some(b"-qB".to_vec()),
+                ..rule(Action::Allow)
+            })?,
+        );
+
+        assert!(store.check(None, b"-qB4650-", true).is_ok());
+        assert!(matches!(
+            store.check(None, b"-TR4060-", true),
+            Err(ClientNotAllowed)
+        ));
+
+        // An empty user agent is still checked against the user agent half
+        assert!(matches!(
+            store.check(Some(""), b"-qB4650-", true),
+            Err(ClientNotAllowed)
+        ));
+
+        Ok(())
+    }
+}
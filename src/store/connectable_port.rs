@@ -4,6 +4,7 @@ use std::str::FromStr;
 
 use futures_util::TryStreamExt;
 use indexmap::IndexMap;
+use serde::Serialize;
 use sqlx::MySqlPool;
 use sqlx::types::chrono::{DateTime, Utc};
 
@@ -14,7 +15,7 @@ pub struct ConnectablePortStore {
     inner: IndexMap<SocketAddr, ConnectablePort>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct ConnectablePort {
     pub connectable: bool,
     pub updated_at: DateTime<Utc>,
@@ -66,6 +67,17 @@ impl ConnectablePortStore {
         .await
         .context("Failed loading peers.")
     }
+
+    /// Removes the sockets last checked before the cutoff, so that sockets
+    /// no longer announced from don't accumulate. Returns the amount of
+    /// sockets removed.
+    pub fn expire(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let len = self.len();
+
+        self.retain(|_, connectable_port| connectable_port.updated_at >= cutoff);
+
+        len - self.len()
+    }
 }
 
 impl Deref for ConnectablePortStore {
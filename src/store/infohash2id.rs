@@ -1,18 +1,31 @@
 use std::ops::{Deref, DerefMut};
 
-use crate::model::info_hash::InfoHash;
+use crate::model::{
+    info_hash::InfoHash, info_hash_v2::InfoHashV2, info_hash_version::InfoHashVersion,
+};
 use futures_util::TryStreamExt;
 use indexmap::IndexMap;
 use sqlx::MySqlPool;
 
 use anyhow::{Context, Result};
 
+/// Maps the v1 info hash and the truncated v2 info hash of each torrent to
+/// the torrent's id.
 pub struct InfoHash2IdStore {
-    inner: IndexMap<InfoHash, u32>,
+    inner: IndexMap<InfoHash, Mapping>,
+    /// Truncated v2 info hash mapped to each torrent, so that it can be
+    /// removed without visiting every mapping when it changes.
+    info_hashes_v2: IndexMap<u32, InfoHash>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Mapping {
+    pub torrent_id: u32,
+    pub version: InfoHashVersion,
 }
 
 impl Deref for InfoHash2IdStore {
-    type Target = IndexMap<InfoHash, u32>;
+    type Target = IndexMap<InfoHash, Mapping>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
@@ -29,6 +42,37 @@ impl InfoHash2IdStore {
     pub fn new() -> InfoHash2IdStore {
         InfoHash2IdStore {
             inner: IndexMap::new(),
+            info_hashes_v2: IndexMap::new(),
+        }
+    }
+
+    /// Maps the truncated v2 info hash to the torrent, replacing the previous
+    /// v2 info hash of the torrent. A truncated v2 info hash colliding with
+    /// the info hash of another torrent doesn't replace it.
+    pub fn set_v2(&mut self, torrent_id: u32, info_hash_v2: Option<InfoHash>) {
+        let is_mapped_to_torrent = |mapping: &Mapping| {
+            mapping.torrent_id == torrent_id && mapping.version == InfoHashVersion::V2
+        };
+
+        if let Some(old_info_hash_v2) = self.info_hashes_v2.swap_remove(&torrent_id)
+            && Some(old_info_hash_v2) != info_hash_v2
+            && self
+                .inner
+                .get(&old_info_hash_v2)
+                .is_some_and(is_mapped_to_torrent)
+        {
+            self.inner.swap_remove(&old_info_hash_v2);
+        }
+
+        if let Some(info_hash_v2) = info_hash_v2 {
+            let mapping = self.inner.entry(info_hash_v2).or_insert(Mapping {
+                torrent_id,
+                version: InfoHashVersion::V2,
+            });
+
+            if is_mapped_to_torrent(mapping) {
+                self.info_hashes_v2.insert(torrent_id, info_hash_v2);
+            }
         }
     }
 
@@ -36,7 +80,7 @@ impl InfoHash2IdStore {
         // Load one torrent per info hash. If multiple are found, prefer
         // undeleted torrents. If multiple are still found, prefer approved
         // torrents. If multiple are still found, prefer the oldest.
-        sqlx::query_as!(
+        let store = sqlx::query_as!(
             InfoHash2Id,
             r#"
                 SELECT
@@ -61,12 +105,59 @@ impl InfoHash2IdStore {
         )
         .fetch(db)
         .try_fold(InfoHash2IdStore::new(), |mut store, torrent| async move {
-            store.insert(torrent.info_hash, torrent.id);
+            store.insert(
+                torrent.info_hash,
+                Mapping {
+                    torrent_id: torrent.id,
+                    version: InfoHashVersion::V1,
+                },
+            );
 
             Ok(store)
         })
         .await
-        .context("Failed loading torrent infohash to id mappings.")
+        .context("Failed loading torrent infohash to id mappings.")?;
+
+        // Load the v2 info hashes of v2 and hybrid torrents using the same
+        // preferences. If a truncated v2 info hash collides with a v1 info
+        // hash, the v1 info hash is kept.
+        sqlx::query_as::<_, InfoHashV2ToId>(
+            r#"
+                SELECT
+                    torrents.id,
+                    torrents.info_hash_v2
+                FROM
+                    torrents
+                JOIN (
+                    SELECT
+                        COALESCE(
+                            MIN(CASE WHEN deleted_at IS NULL AND status = 1 THEN id END),
+                            MIN(CASE WHEN deleted_at IS NULL AND status != 1 THEN id END),
+                            MIN(CASE WHEN deleted_at IS NOT NULL THEN id END)
+                        ) AS id
+                    FROM
+                        torrents
+                    WHERE
+                        info_hash_v2 IS NOT NULL
+                    GROUP BY
+                        info_hash_v2
+                ) AS distinct_torrents
+                    ON distinct_torrents.id = torrents.id
+            "#,
+        )
+        .fetch(db)
+        .map_err(anyhow::Error::from)
+        .try_fold(store, |mut store, torrent| async move {
+            let info_hash_v2 = InfoHashV2::try_from(torrent.info_hash_v2.as_slice())?;
+
+            store.set_v2(torrent.id, Some(info_hash_v2.truncate()));
+
+            Ok(store)
+        })
+        .await
+        .context(
+            "Failed loading torrent v2 infohash to id mappings. Has the `info_hash_v2` column been added to the `torrents` table?",
+        )
     }
 }
 
@@ -74,3 +165,48 @@ pub struct InfoHash2Id {
     pub id: u32,
     pub info_hash: InfoHash,
 }
+
+#[derive(sqlx::FromRow)]
+pub struct InfoHashV2ToId {
+    pub id: u32,
+    pub info_hash_v2: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_id(store: &InfoHash2IdStore, info_hash: [u8; 20]) -> Option<u32> {
+        store
+            .get(&InfoHash(info_hash))
+            .map(|mapping| mapping.torrent_id)
+    }
+
+    #[test]
+    fn replaces_v2_info_hash() {
+        let mut store = InfoHash2IdStore::new();
+
+        store.insert(
+            InfoHash([1; 20]),
+            Mapping {
+                torrent_id: 1,
+                version: InfoHashVersion::V1,
+            },
+        );
+
+        store.set_v2(2, Some(InfoHash([2; 20])));
+        store.set_v2(2, Some(InfoHash([3; 20])));
+        assert_eq!(torrent_id(&store, [2; 20]), None);
+        assert_eq!(torrent_id(&store, [3; 20]), Some(2));
+
+        // Colliding info hashes of other torrents are kept
+        store.set_v2(2, Some(InfoHash([1; 20])));
+        assert_eq!(torrent_id(&store, [1; 20]), Some(1));
+        assert_eq!(torrent_id(&store, [3; 20]), None);
+
+        store.set_v2(3, Some(InfoHash([4; 20])));
+        store.set_v2(3, None);
+        assert_eq!(torrent_id(&store, [4; 20]), None);
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -25,6 +25,10 @@ pub struct Index {
 #[derive(Clone, Copy, Debug, Serialize)]
 pub struct Peer {
     pub ip_address: std::net::IpAddr,
+    /// Address of the other ip family reported by a dual-stack peer through
+    /// the BEP 7 `ipv4` or `ipv6` announce parameters. Not stored in the
+    /// database.
+    pub alternate_ip_address: Option<std::net::IpAddr>,
     pub port: u16,
     pub is_seeder: bool,
     pub is_active: bool,
@@ -35,6 +39,9 @@ pub struct Peer {
     pub updated_at: DateTime<Utc>,
     pub uploaded: u64,
     pub downloaded: u64,
+    /// Peer announced through the websocket tracker. WebRTC peers are only
+    /// included in the peer lists of other WebRTC peers.
+    pub is_webrtc: bool,
 }
 
 impl Peer {
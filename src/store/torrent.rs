@@ -1,29 +1,86 @@
 use std::net::IpAddr;
-use std::ops::{Deref, DerefMut};
 
 use futures_util::TryStreamExt;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, map::Entry};
+use parking_lot::{Mutex, MutexGuard};
 use serde::Serialize;
 use sqlx::MySqlPool;
 use sqlx::types::chrono::{DateTime, Utc};
 
 use anyhow::{Context, Result};
 
+use crate::config::Config;
 use crate::model::{peer_id::PeerId, torrent_status::TorrentStatus};
 use crate::store::peer::{Index, Peer, PeerStore};
 
+/// Amount of shards the torrents are split into. Announces of torrents in
+/// different shards never wait on each other.
+const SHARD_COUNT: usize = 256;
+
+pub type Shard = IndexMap<u32, Torrent>;
+
+/// Torrents split by id into separately locked shards, so that announces
+/// only contend with announces of torrents in the same shard.
 pub struct TorrentStore {
-    inner: IndexMap<u32, Torrent>,
+    shards: Box<[Mutex<Shard>]>,
+}
+
+/// Copies the store one shard at a time, so that announces only wait on the
+/// shard being copied.
+impl Clone for TorrentStore {
+    fn clone(&self) -> TorrentStore {
+        TorrentStore {
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| Mutex::new(shard.lock().clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Default for TorrentStore {
+    fn default() -> TorrentStore {
+        TorrentStore::new()
+    }
 }
 
 impl TorrentStore {
     pub fn new() -> TorrentStore {
         TorrentStore {
-            inner: IndexMap::new(),
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(IndexMap::new()))
+                .collect(),
         }
     }
 
-    pub async fn from_db(db: &MySqlPool) -> Result<TorrentStore> {
+    /// Locks the shard holding the torrent with the given id.
+    pub fn lock(&self, torrent_id: u32) -> MutexGuard<'_, Shard> {
+        self.shards[shard_index(torrent_id)].lock()
+    }
+
+    /// Gets the shard holding the torrent with the given id without locking,
+    /// which requires exclusive access to the store.
+    pub fn shard_mut(&mut self, torrent_id: u32) -> &mut Shard {
+        self.shards[shard_index(torrent_id)].get_mut()
+    }
+
+    /// All shards, to be locked one at a time when visiting every torrent.
+    pub fn shards(&self) -> &[Mutex<Shard>] {
+        &self.shards
+    }
+
+    /// Amount of torrents in the store. Each shard is locked separately, so
+    /// the count may be off if torrents are concurrently inserted.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.lock().is_empty())
+    }
+
+    pub async fn from_db(db: &MySqlPool, config: &Config) -> Result<TorrentStore> {
         // Load one torrent per info hash. If multiple are found, prefer
         // undeleted torrents. If multiple are still found, prefer approved
         // torrents. If multiple are still found, prefer the oldest.
@@ -58,7 +115,7 @@ impl TorrentStore {
         )
         .fetch(db)
         .try_fold(TorrentStore::new(), |mut store, torrent| async move {
-            store.insert(
+            store.shard_mut(torrent.id).insert(
                 torrent.id,
                 Torrent {
                     id: torrent.id,
@@ -78,72 +135,143 @@ impl TorrentStore {
         .await
         .context("Failed loading torrents.")?;
 
-        // Load peers into each torrent
-        sqlx::query!(
-            r#"
-                SELECT
-                    INET6_NTOA(peers.ip) as `ip_address: IpAddr`,
-                    peers.user_id as `user_id: u32`,
-                    peers.torrent_id as `torrent_id: u32`,
-                    peers.port as `port: u16`,
-                    peers.seeder as `is_seeder: bool`,
-                    peers.active as `is_active: bool`,
-                    peers.visible as `is_visible: bool`,
-                    peers.connectable as `is_connectable: bool`,
-                    peers.updated_at as `updated_at: DateTime<Utc>`,
-                    peers.uploaded as `uploaded: u64`,
-                    peers.downloaded as `downloaded: u64`,
-                    peers.peer_id as `peer_id: PeerId`
-                FROM
-                    peers
-            "#
-        )
-        .fetch(db)
-        .try_fold(torrents, |mut store, peer| async move {
-            store.entry(peer.torrent_id).and_modify(|torrent| {
-                torrent.peers.insert(
-                    Index {
-                        user_id: peer.user_id,
-                        peer_id: peer.peer_id,
-                    },
-                    Peer {
-                        ip_address: peer
-                            .ip_address
-                            .expect("INET6_NTOA failed to decode peer ip."),
-                        port: peer.port,
-                        is_seeder: peer.is_seeder,
-                        is_active: peer.is_active,
-                        is_visible: peer.is_visible,
-                        is_connectable: peer.is_connectable,
-                        has_sent_completed: false,
-                        updated_at: peer
-                            .updated_at
-                            .expect("Peer with a null updated_at found in database."),
-                        uploaded: peer.uploaded,
-                        downloaded: peer.downloaded,
-                    },
-                );
-            });
+        // Load peers into each torrent. The `webrtc` column is only required
+        // once the websocket tracker is enabled, since all peers are TCP
+        // peers otherwise.
+        let peers = if config.is_websocket_tracker_enabled {
+            sqlx::query_as!(
+                DBImportPeer,
+                r#"
+                    SELECT
+                        INET6_NTOA(peers.ip) as `ip_address: IpAddr`,
+                        peers.user_id as `user_id: u32`,
+                        peers.torrent_id as `torrent_id: u32`,
+                        peers.port as `port: u16`,
+                        peers.seeder as `is_seeder: bool`,
+                        peers.active as `is_active: bool`,
+                        peers.visible as `is_visible: bool`,
+                        peers.connectable as `is_connectable: bool`,
+                        peers.updated_at as `updated_at: DateTime<Utc>`,
+                        peers.uploaded as `uploaded: u64`,
+                        peers.downloaded as `downloaded: u64`,
+                        peers.peer_id as `peer_id: PeerId`,
+                        peers.webrtc as `is_webrtc: bool`
+                    FROM
+                        peers
+                "#
+            )
+            .fetch(db)
+        } else {
+            sqlx::query_as!(
+                DBImportPeer,
+                r#"
+                    SELECT
+                        INET6_NTOA(peers.ip) as `ip_address: IpAddr`,
+                        peers.user_id as `user_id: u32`,
+                        peers.torrent_id as `torrent_id: u32`,
+                        peers.port as `port: u16`,
+                        peers.seeder as `is_seeder: bool`,
+                        peers.active as `is_active: bool`,
+                        peers.visible as `is_visible: bool`,
+                        peers.connectable as `is_connectable: bool`,
+                        peers.updated_at as `updated_at: DateTime<Utc>`,
+                        peers.uploaded as `uploaded: u64`,
+                        peers.downloaded as `downloaded: u64`,
+                        peers.peer_id as `peer_id: PeerId`,
+                        FALSE as `is_webrtc: bool`
+                    FROM
+                        peers
+                "#
+            )
+            .fetch(db)
+        };
 
-            Ok(store)
-        })
-        .await
-        .context("Failed loading peers.")
+        peers
+            .try_fold(torrents, |mut store, peer| async move {
+                store
+                    .shard_mut(peer.torrent_id)
+                    .entry(peer.torrent_id)
+                    .and_modify(|torrent| {
+                        torrent.peers.insert(
+                            Index {
+                                user_id: peer.user_id,
+                                peer_id: peer.peer_id,
+                            },
+                            Peer {
+                                ip_address: peer
+                                    .ip_address
+                                    .expect("INET6_NTOA failed to decode peer ip."),
+                                alternate_ip_address: None,
+                                port: peer.port,
+                                is_seeder: peer.is_seeder,
+                                is_active: peer.is_active,
+                                is_visible: peer.is_visible,
+                                is_connectable: peer.is_connectable,
+                                has_sent_completed: false,
+                                updated_at: peer
+                                    .updated_at
+                                    .expect("Peer with a null updated_at found in database."),
+                                uploaded: peer.uploaded,
+                                downloaded: peer.downloaded,
+                                is_webrtc: peer.is_webrtc,
+                            },
+                        );
+                    });
+
+                Ok(store)
+            })
+            .await
+            .context("Failed loading peers.")
     }
-}
 
-impl Deref for TorrentStore {
-    type Target = IndexMap<u32, Torrent>;
+    /// Restores the peers of the torrents in the snapshot that still exist,
+    /// unless they were updated in the database since the snapshot was
+    /// written. Whether the peer sent its completed event and its alternate
+    /// ip address aren't stored in the database, so they're restored either
+    /// way. The peer counts of the restored torrents are recalculated.
+    pub fn restore_peers(&mut self, snapshot: TorrentStore, config: &Config) {
+        for shard in snapshot.shards {
+            for (torrent_id, snapshot_torrent) in shard.into_inner() {
+                let Some(torrent) = self.shard_mut(torrent_id).get_mut(&torrent_id) else {
+                    continue;
+                };
+
+                for (index, snapshot_peer) in snapshot_torrent.peers.iter() {
+                    match torrent.peers.entry(*index) {
+                        Entry::Occupied(mut entry)
+                            if entry.get().updated_at >= snapshot_peer.updated_at =>
+                        {
+                            let peer = entry.get_mut();
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+                            peer.alternate_ip_address = snapshot_peer.alternate_ip_address;
+                            peer.has_sent_completed = snapshot_peer.has_sent_completed;
+                        }
+                        Entry::Occupied(mut entry) => {
+                            entry.insert(*snapshot_peer);
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(*snapshot_peer);
+                        }
+                    }
+                }
+
+                torrent.seeders = torrent
+                    .peers
+                    .values()
+                    .filter(|peer| peer.is_included_in_seed_list(config))
+                    .count() as u32;
+                torrent.leechers = torrent
+                    .peers
+                    .values()
+                    .filter(|peer| peer.is_included_in_leech_list(config))
+                    .count() as u32;
+            }
+        }
     }
 }
 
-impl DerefMut for TorrentStore {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
-    }
+fn shard_index(torrent_id: u32) -> usize {
+    torrent_id as usize % SHARD_COUNT
 }
 
 #[derive(Clone, Default)]
@@ -158,6 +286,22 @@ pub struct DBImportTorrent {
     pub is_deleted: bool,
 }
 
+pub struct DBImportPeer {
+    pub ip_address: Option<IpAddr>,
+    pub user_id: u32,
+    pub torrent_id: u32,
+    pub port: u16,
+    pub is_seeder: bool,
+    pub is_active: bool,
+    pub is_visible: bool,
+    pub is_connectable: bool,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub peer_id: PeerId,
+    pub is_webrtc: bool,
+}
+
 #[derive(Clone, Default, Serialize)]
 pub struct Torrent {
     pub id: u32,
@@ -13,7 +13,7 @@ use crate::rate::RateCollection;
 
 use crate::model::passkey::Passkey;
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct UserStore {
     inner: IndexMap<u32, User>,
 }
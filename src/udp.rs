@@ -0,0 +1,482 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{Semaphore, oneshot},
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::error;
+
+use crate::{
+    announce::{self, Announce, Event},
+    error::AnnounceError::{self, InvalidConnectionId, InvalidPasskey, MalformedRequest},
+    model::{info_hash::InfoHash, peer_id::PeerId},
+    scrape,
+    state::AppState,
+};
+
+/// Magic constant sent by clients in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Length of an announce request without any BEP 41 options.
+const ANNOUNCE_REQUEST_LENGTH: usize = 98;
+
+/// Amount of seconds per connection id generation. Connection ids from the
+/// current and previous window are accepted, so clients can use them for at
+/// least the one minute required by the spec.
+const CONNECTION_ID_WINDOW: u64 = 60;
+
+/// Large enough to hold an announce request including BEP 41 options.
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// Receives UDP tracker requests (BEP 15) and answers them using the same
+/// announce pipeline as the HTTP tracker. Once shutdown is signaled, stops
+/// receiving and waits for the requests in progress to be answered, so that
+/// their updates are queued before the final flush.
+pub async fn listen(state: Arc<AppState>, socket: UdpSocket, mut shutdown: oneshot::Receiver<()>) {
+    let socket = Arc::new(socket);
+    let connection_ids = Arc::new(ConnectionIds::new());
+    let permits = Arc::new(Semaphore::new(
+        state.config.load().max_concurrent_udp_requests,
+    ));
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    let mut tasks = JoinSet::new();
+
+    loop {
+        // Reap the finished tasks so that the set doesn't grow unbounded
+        while tasks.try_join_next().is_some() {}
+
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            _ = &mut shutdown => break,
+        };
+
+        let (len, addr) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Failed to receive UDP packet: {e}");
+
+                continue;
+            }
+        };
+
+        // Packets received while too many requests are in progress are
+        // dropped, so that a flood of packets can't spawn tasks without
+        // bound. Clients retransmit requests that aren't answered.
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            continue;
+        };
+
+        let packet = buffer[..len].to_vec();
+
+        tasks.spawn({
+            let state = state.clone();
+            let socket = socket.clone();
+            let connection_ids = connection_ids.clone();
+
+            async move {
+                state.stats.increment_request();
+
+                if let Some(response) = handle(&state, &connection_ids, &packet, addr).await
+                    && let Err(e) = socket.send_to(&response, addr).await
+                {
+                    error!("Failed to send UDP packet to {addr}: {e}");
+                }
+
+                drop(permit);
+            }
+        });
+    }
+
+    while tasks.join_next().await.is_some() {}
+}
+
+/// Generates stateless connection ids by hashing the client's socket and
+/// the current time window with a key that is random per process.
+struct ConnectionIds {
+    hasher: RandomState,
+}
+
+impl ConnectionIds {
+    fn new() -> ConnectionIds {
+        ConnectionIds {
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn current_window() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time must go forwards.")
+            .as_secs()
+            / CONNECTION_ID_WINDOW
+    }
+
+    fn connection_id(&self, addr: SocketAddr, window: u64) -> u64 {
+        self.hasher.hash_one((addr, window))
+    }
+
+    /// Creates a new connection id for the client.
+    fn generate(&self, addr: SocketAddr) -> u64 {
+        self.connection_id(addr, Self::current_window())
+    }
+
+    /// Checks that the connection id was recently generated for the client.
+    fn is_valid(&self, connection_id: u64, addr: SocketAddr) -> bool {
+        let window = Self::current_window();
+
+        connection_id == self.connection_id(addr, window)
+            || connection_id == self.connection_id(addr, window.saturating_sub(1))
+    }
+}
+
+/// Handles a single UDP packet and returns the response to send back, if any.
+async fn handle(
+    state: &Arc<AppState>,
+    connection_ids: &ConnectionIds,
+    packet: &[u8],
+    addr: SocketAddr,
+) -> Option<Vec<u8>> {
+    let connection_id = read_u64(packet, 0)?;
+    let action = read_u32(packet, 8)?;
+    let transaction_id = read_u32(packet, 12)?;
+
+    let result = match action {
+        ACTION_CONNECT => {
+            // Not a bittorrent client, don't answer.
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+
+            Ok(connect(connection_ids, transaction_id, addr))
+        }
+        ACTION_ANNOUNCE if !connection_ids.is_valid(connection_id, addr) => {
+            Err(InvalidConnectionId)
+        }
+        ACTION_ANNOUNCE => {
+            let start = Instant::now();
+            let result = announce(state, transaction_id, packet, addr).await;
+
+            state
+                .metrics
+                .record_announce(start.elapsed(), result.as_ref().err());
+
+            result
+        }
+        ACTION_SCRAPE if !connection_ids.is_valid(connection_id, addr) => Err(InvalidConnectionId),
+        ACTION_SCRAPE => scrape(state, transaction_id, packet),
+        _ => Err(MalformedRequest),
+    };
+
+    Some(result.unwrap_or_else(|e| error_response(transaction_id, &e)))
+}
+
+fn connect(connection_ids: &ConnectionIds, transaction_id: u32, addr: SocketAddr) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::with_capacity(16);
+
+    response.extend(ACTION_CONNECT.to_be_bytes());
+    response.extend(transaction_id.to_be_bytes());
+    response.extend(connection_ids.generate(addr).to_be_bytes());
+
+    response
+}
+
+async fn announce(
+    state: &Arc<AppState>,
+    transaction_id: u32,
+    packet: &[u8],
+    addr: SocketAddr,
+) -> Result<Vec<u8>, AnnounceError> {
+    if packet.len() < ANNOUNCE_REQUEST_LENGTH {
+        return Err(MalformedRequest);
+    }
+
+    let info_hash = InfoHash::from(read_array::<20>(packet, 16).ok_or(MalformedRequest)?);
+    let peer_id = PeerId::from(read_array::<20>(packet, 36).ok_or(MalformedRequest)?);
+    let downloaded = read_u64(packet, 56).ok_or(MalformedRequest)?;
+    let left = read_u64(packet, 64).ok_or(MalformedRequest)?;
+    let uploaded = read_u64(packet, 72).ok_or(MalformedRequest)?;
+    let event = match read_u32(packet, 80).ok_or(MalformedRequest)? {
+        0 => Event::Empty,
+        1 => Event::Completed,
+        2 => Event::Started,
+        3 => Event::Stopped,
+        _ => return Err(AnnounceError::UnsupportedEvent),
+    };
+    // The ip address field (offset 84) is ignored so that peers can't
+    // announce on behalf of others.
+    let key = read_u32(packet, 88).ok_or(MalformedRequest)?;
+    let numwant = read_u32(packet, 92).ok_or(MalformedRequest)? as i32;
+    let port = read_u16(packet, 96).ok_or(MalformedRequest)?;
+
+    let url_data = url_data(&packet[ANNOUNCE_REQUEST_LENGTH..]);
+    let passkey = passkey(&url_data)?;
+
+    let numwant = {
+        let config = state.config.load();
+
+        if event == Event::Stopped {
+            0
+        } else if numwant < 0 {
+            config.numwant_default.min(config.numwant_max)
+        } else {
+            (numwant as usize).min(config.numwant_max)
+        }
+    };
+
+    let client_ip = addr.ip().to_canonical();
+
+    let queries = Announce {
+        info_hash,
+        info_hash_version: None,
+        peer_id,
+        port,
+        uploaded,
+        downloaded,
+        left,
+        event,
+        numwant,
+        corrupt: None,
+        key: Some(format!("{key:08X}")),
+        compact: true,
+        no_peer_id: false,
+        ipv4: None,
+        ipv6: None,
+        is_webrtc: false,
+    };
+
+    let (interval, leechers, seeders, peers) =
+        match announce::process(state, passkey, queries, None, client_ip).await {
+            Ok(announce_response) => (
+                announce_response.interval,
+                announce_response.incomplete,
+                announce_response.complete,
+                match client_ip {
+                    IpAddr::V4(_) => announce_response.peers_ipv4,
+                    IpAddr::V6(_) => announce_response.peers_ipv6,
+                },
+            ),
+            // The UDP protocol has no warning messages, so critical
+            // warnings are sent as successful announces with no peers.
+            Err(e) if e.is_critical_warning() => (e.interval(), 0, 0, Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+    let mut response: Vec<u8> = Vec::with_capacity(20 + peers.len());
+
+    response.extend(ACTION_ANNOUNCE.to_be_bytes());
+    response.extend(transaction_id.to_be_bytes());
+    response.extend(interval.to_be_bytes());
+    response.extend(leechers.to_be_bytes());
+    response.extend(seeders.to_be_bytes());
+    response.extend(peers);
+
+    Ok(response)
+}
+
+fn scrape(state: &AppState, transaction_id: u32, packet: &[u8]) -> Result<Vec<u8>, AnnounceError> {
+    let (info_hashes, options) = split_scrape_request(&packet[16..]).ok_or(MalformedRequest)?;
+    let url_data = url_data(options);
+    let passkey = passkey(&url_data)?;
+
+    let info_hashes: Vec<InfoHash> = info_hashes
+        .chunks_exact(20)
+        .take(scrape::MAX_INFO_HASHES)
+        .map(|chunk| InfoHash::from(<[u8; 20]>::try_from(chunk).expect("Chunk is 20 bytes.")))
+        .collect();
+
+    let mut response: Vec<u8> = Vec::with_capacity(8 + info_hashes.len() * 12);
+
+    response.extend(ACTION_SCRAPE.to_be_bytes());
+    response.extend(transaction_id.to_be_bytes());
+
+    // Unlike HTTP scrapes, the statistics are positional, so torrents that
+    // can't be scraped are returned as empty swarms.
+    for scraped_torrent in scrape::scrape_torrents_as_user(state, passkey, &info_hashes)? {
+        let (seeders, completed, leechers) = scraped_torrent.map_or((0, 0, 0), |torrent| {
+            (torrent.complete, torrent.downloaded, torrent.incomplete)
+        });
+
+        response.extend(seeders.to_be_bytes());
+        response.extend(completed.to_be_bytes());
+        response.extend(leechers.to_be_bytes());
+    }
+
+    Ok(response)
+}
+
+fn error_response(transaction_id: u32, error: &AnnounceError) -> Vec<u8> {
+    let message = error.to_string();
+    let mut response: Vec<u8> = Vec::with_capacity(8 + message.len());
+
+    response.extend(ACTION_ERROR.to_be_bytes());
+    response.extend(transaction_id.to_be_bytes());
+    response.extend(message.as_bytes());
+
+    response
+}
+
+/// The passkey is sent as the path of the announce url
+/// (e.g. `/announce/<passkey>?<query>`) using the BEP 41 url data option.
+fn passkey(url_data: &[u8]) -> Result<&str, AnnounceError> {
+    let path = url_data
+        .split(|&byte| byte == b'?')
+        .next()
+        .unwrap_or_default();
+    let passkey = path.rsplit(|&byte| byte == b'/').next().unwrap_or_default();
+
+    std::str::from_utf8(passkey).or(Err(InvalidPasskey))
+}
+
+/// Splits the body of a scrape request into its info hashes and its BEP 41
+/// options. Since scrape requests have no length field, the options start
+/// after the most info hashes that leave a well formed list of options
+/// behind. Returns `None` if there are no info hashes.
+fn split_scrape_request(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    (1..=body.len() / 20)
+        .rev()
+        .map(|info_hash_count| body.split_at(info_hash_count * 20))
+        .find(|(_, options)| is_well_formed(options))
+}
+
+/// Whether the BEP 41 options are complete and only contain known option
+/// types.
+fn is_well_formed(options: &[u8]) -> bool {
+    const END_OF_OPTIONS: u8 = 0x0;
+    const NOP: u8 = 0x1;
+    const URL_DATA: u8 = 0x2;
+
+    let mut pos = 0;
+
+    while let Some(&option_type) = options.get(pos) {
+        match option_type {
+            END_OF_OPTIONS => return true,
+            NOP => pos += 1,
+            URL_DATA => match options.get(pos + 1) {
+                Some(&len) if pos + 2 + len as usize <= options.len() => pos += 2 + len as usize,
+                _ => return false,
+            },
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Concatenates the data of all BEP 41 url data options.
+fn url_data(options: &[u8]) -> Vec<u8> {
+    const END_OF_OPTIONS: u8 = 0x0;
+    const NOP: u8 = 0x1;
+    const URL_DATA: u8 = 0x2;
+
+    let mut url_data: Vec<u8> = Vec::new();
+    let mut pos = 0;
+
+    while let Some(&option_type) = options.get(pos) {
+        match option_type {
+            END_OF_OPTIONS => break,
+            NOP => pos += 1,
+            URL_DATA => {
+                let Some(&len) = options.get(pos + 1) else {
+                    break;
+                };
+                let Some(data) = options.get(pos + 2..pos + 2 + len as usize) else {
+                    break;
+                };
+
+                url_data.extend(data);
+                pos += 2 + len as usize;
+            }
+            // Unknown options can't be skipped since their length is unknown
+            _ => break,
+        }
+    }
+
+    url_data
+}
+
+#[inline(always)]
+fn read_array<const N: usize>(packet: &[u8], offset: usize) -> Option<[u8; N]> {
+    packet.get(offset..offset + N)?.try_into().ok()
+}
+
+#[inline(always)]
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    read_array(packet, offset).map(u16::from_be_bytes)
+}
+
+#[inline(always)]
+fn read_u32(packet: &[u8], offset: usize) -> Option<u32> {
+    read_array(packet, offset).map(u32::from_be_bytes)
+}
+
+#[inline(always)]
+fn read_u64(packet: &[u8], offset: usize) -> Option<u64> {
+    read_array(packet, offset).map(u64::from_be_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_data_single_option() {
+        let options = b"\x02\x0C/announce/ab\x00";
+        assert_eq!(url_data(options), b"/announce/ab");
+    }
+
+    #[test]
+    fn url_data_multiple_options() {
+        let options = b"\x02\x05/anno\x01\x02\x07unce/ab";
+        assert_eq!(url_data(options), b"/announce/ab");
+    }
+
+    #[test]
+    fn url_data_truncated_option() {
+        let options = b"\x02\x0C/announce";
+        assert!(url_data(options).is_empty());
+    }
+
+    #[test]
+    fn scrape_request_options() {
+        let info_hashes = [[b'a'; 20], [b'b'; 20]].concat();
+        let options = b"\x02\x2A/announce/0123456789abcdef0123456789abcdef\x00";
+        let body = [info_hashes.as_slice(), options].concat();
+
+        assert_eq!(
+            split_scrape_request(&body),
+            Some((info_hashes.as_slice(), options.as_slice()))
+        );
+        assert_eq!(
+            passkey(&url_data(options)).ok(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(
+            split_scrape_request(&info_hashes),
+            Some((info_hashes.as_slice(), [].as_slice()))
+        );
+        assert_eq!(split_scrape_request(b"\x02\x2C/announce"), None);
+    }
+
+    #[test]
+    fn connection_id_is_valid_for_same_socket_only() {
+        let connection_ids = ConnectionIds::new();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let other_addr = SocketAddr::from(([127, 0, 0, 1], 6882));
+        let connection_id = connection_ids.generate(addr);
+
+        assert!(connection_ids.is_valid(connection_id, addr));
+        assert!(!connection_ids.is_valid(connection_id, other_addr));
+    }
+}
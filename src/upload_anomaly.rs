@@ -0,0 +1,258 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::store::peer::PeerStore;
+
+/// Limits above which uploads are flagged. Unset limits aren't checked.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Thresholds {
+    /// Max average upload speed since the previous announce, in bytes per
+    /// second.
+    pub max_upload_speed: Option<u64>,
+    /// Max amount of bytes uploaded since the previous announce while no other
+    /// user is leeching the torrent.
+    pub max_upload_without_leechers: Option<u64>,
+    /// Max amount of bytes uploaded since the previous announce in excess of
+    /// the amount the other users' peers downloaded during their sessions.
+    pub max_upload_over_swarm_download: Option<u64>,
+}
+
+/// Reasons an announced upload is implausible, as detected by the thresholds
+/// configured in the .env file. An upload can be flagged for several reasons
+/// at once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UploadAnomaly {
+    /// Average upload speed since the previous announce, in bytes per second.
+    pub upload_speed: u64,
+    /// The upload speed exceeds `MAX_UPLOAD_SPEED`. Only uploads flagged for
+    /// their speed have their credit withheld, since the swarm based checks
+    /// don't see the leechers that stopped since the previous announce.
+    pub exceeded_max_upload_speed: bool,
+    /// More than `MAX_UPLOAD_WITHOUT_LEECHERS` bytes were uploaded while no
+    /// other user was leeching the torrent.
+    pub uploaded_without_leechers: bool,
+    /// The upload exceeds the amount the other users' peers downloaded by
+    /// more than `MAX_UPLOAD_OVER_SWARM_DOWNLOAD` bytes.
+    pub exceeded_swarm_download: bool,
+}
+
+/// Active peers of other users in the swarm of a torrent.
+#[derive(Default)]
+pub struct Swarm {
+    /// Leechers, including the peers that completed the torrent since the
+    /// given time.
+    pub leechers: u32,
+    /// Sum of the amounts the peers reported downloading during their current
+    /// sessions.
+    pub downloaded: u64,
+}
+
+impl Swarm {
+    pub fn of_other_users(peers: &PeerStore, user_id: u32, since: DateTime<Utc>) -> Swarm {
+        let mut swarm = Swarm::default();
+
+        for (index, peer) in peers.iter() {
+            if index.user_id == user_id || !peer.is_active {
+                continue;
+            }
+
+            let has_completed_since = peer.has_sent_completed && peer.updated_at >= since;
+
+            swarm.leechers += (!peer.is_seeder || has_completed_since) as u32;
+            swarm.downloaded = swarm.downloaded.saturating_add(peer.downloaded);
+        }
+
+        swarm
+    }
+}
+
+/// Checks the amount uploaded since the previous announce against the
+/// thresholds. The swarm is only collected if the amount exceeds a threshold
+/// depending on it, since walking the peers of large torrents on every
+/// announce is expensive.
+pub fn detect(
+    thresholds: &Thresholds,
+    uploaded_delta: u64,
+    elapsed: Duration,
+    swarm: impl FnOnce() -> Swarm,
+) -> Option<UploadAnomaly> {
+    if uploaded_delta == 0 {
+        return None;
+    }
+
+    // Announces within the same second are treated as a second apart
+    let upload_speed = uploaded_delta / elapsed.num_seconds().max(1) as u64;
+    let mut anomaly = UploadAnomaly {
+        upload_speed,
+        exceeded_max_upload_speed: thresholds
+            .max_upload_speed
+            .is_some_and(|max| upload_speed > max),
+        ..Default::default()
+    };
+
+    let exceeds_max_upload_without_leechers = thresholds
+        .max_upload_without_leechers
+        .is_some_and(|max| uploaded_delta > max);
+    let exceeds_max_upload_over_swarm_download = thresholds
+        .max_upload_over_swarm_download
+        .is_some_and(|max| uploaded_delta > max);
+
+    if exceeds_max_upload_without_leechers || exceeds_max_upload_over_swarm_download {
+        let swarm = swarm();
+
+        anomaly.uploaded_without_leechers =
+            exceeds_max_upload_without_leechers && swarm.leechers == 0;
+        anomaly.exceeded_swarm_download = exceeds_max_upload_over_swarm_download
+            && thresholds
+                .max_upload_over_swarm_download
+                .is_some_and(|max| uploaded_delta.saturating_sub(swarm.downloaded) > max);
+    }
+
+    (anomaly.exceeded_max_upload_speed
+        || anomaly.uploaded_without_leechers
+        || anomaly.exceeded_swarm_download)
+        .then_some(anomaly)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::{
+        model::peer_id::PeerId,
+        store::peer::{Index, Peer},
+    };
+
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        max_upload_speed: Some(100_000_000),
+        max_upload_without_leechers: Some(1_000_000),
+        max_upload_over_swarm_download: Some(10_000_000),
+    };
+
+    fn swarm(leechers: u32, downloaded: u64) -> impl FnOnce() -> Swarm {
+        move || Swarm {
+            leechers,
+            downloaded,
+        }
+    }
+
+    #[test]
+    fn plausible_upload() {
+        let anomaly = detect(
+            &THRESHOLDS,
+            1_000_000_000,
+            Duration::minutes(30),
+            swarm(3, 2_000_000_000),
+        );
+
+        assert_eq!(anomaly, None);
+        assert_eq!(
+            detect(
+                &Thresholds::default(),
+                u64::MAX,
+                Duration::zero(),
+                swarm(0, 0)
+            ),
+            None
+        );
+        assert_eq!(
+            detect(&THRESHOLDS, 1_000, Duration::minutes(30), || unreachable!()),
+            None
+        );
+    }
+
+    #[test]
+    fn implausible_upload() {
+        let anomaly = detect(
+            &THRESHOLDS,
+            500_000_000_000,
+            Duration::minutes(30),
+            swarm(0, 0),
+        );
+
+        assert_eq!(
+            anomaly,
+            Some(UploadAnomaly {
+                upload_speed: 277_777_777,
+                exceeded_max_upload_speed: true,
+                uploaded_without_leechers: true,
+                exceeded_swarm_download: true,
+            })
+        );
+        assert!(
+            detect(
+                &THRESHOLDS,
+                50_000_000,
+                Duration::minutes(30),
+                swarm(1, 30_000_000)
+            )
+            .is_some_and(
+                |anomaly| anomaly.exceeded_swarm_download && !anomaly.uploaded_without_leechers
+            )
+        );
+    }
+
+    #[test]
+    fn swarm_of_other_users() {
+        let previous_announced_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let peer = Peer {
+            ip_address: IpAddr::from([192, 0, 2, 1]),
+            alternate_ip_address: None,
+            port: 6881,
+            is_seeder: true,
+            is_active: true,
+            is_visible: true,
+            is_connectable: true,
+            has_sent_completed: true,
+            updated_at: previous_announced_at + Duration::minutes(10),
+            uploaded: 0,
+            downloaded: 1_000,
+            is_webrtc: false,
+        };
+        let mut peers = PeerStore::new();
+
+        for (user_id, peer) in [
+            // Completed since the previous announce
+            (2, peer),
+            // Completed before the previous announce
+            (
+                3,
+                Peer {
+                    updated_at: previous_announced_at - Duration::minutes(10),
+                    ..peer
+                },
+            ),
+            (
+                4,
+                Peer {
+                    is_seeder: false,
+                    has_sent_completed: false,
+                    ..peer
+                },
+            ),
+            (
+                5,
+                Peer {
+                    is_active: false,
+                    ..peer
+                },
+            ),
+            // The uploader's own peers
+            (1, peer),
+        ] {
+            peers.insert(
+                Index {
+                    user_id,
+                    peer_id: PeerId(*b"-UT0001-000000000000"),
+                },
+                peer,
+            );
+        }
+
+        let swarm = Swarm::of_other_users(&peers, 1, previous_announced_at);
+
+        assert_eq!(swarm.leechers, 2);
+        assert_eq!(swarm.downloaded, 3_000);
+    }
+}
@@ -1,16 +1,18 @@
-use anyhow::{Result, bail};
+use std::net::{IpAddr, SocketAddr};
 
-/// Decodes a url-encoded string to 20 bytes.
+use anyhow::{Context, Result, bail};
+
+/// Decodes a url-encoded string to N bytes.
 ///
 /// Used for decoding the peer_id and infohash from the HTTP GET request query string.
 #[inline(always)]
-pub fn urlencoded_to_bytes(input: &str) -> Result<[u8; 20]> {
-    let mut output: [u8; 20] = [0; 20];
+pub fn urlencoded_to_bytes<const N: usize>(input: &str) -> Result<[u8; N]> {
+    let mut output: [u8; N] = [0; N];
     let input = input.as_bytes();
     let percent_sign_count = memchr::memchr_iter(b'%', input).count();
 
-    if input.len() != 20 + 2 * percent_sign_count {
-        bail!("Invalid 'info_hash' or 'peer_id' (must be 20 bytes long)");
+    if input.len() != N + 2 * percent_sign_count {
+        bail!("Invalid 'info_hash' or 'peer_id' (must be {N} bytes long)");
     }
 
     let mut in_pos = 0;
@@ -30,6 +32,71 @@ pub fn urlencoded_to_bytes(input: &str) -> Result<[u8; 20]> {
     Ok(output)
 }
 
+/// Decodes a url-encoded ip address or socket address to an ip address.
+///
+/// Used for decoding the BEP 7 `ipv4` and `ipv6` parameters from the HTTP GET
+/// request query string. The port of a socket address is discarded.
+pub fn urlencoded_to_ip(input: &str) -> Result<IpAddr> {
+    let input = input.as_bytes();
+    let mut decoded = String::with_capacity(input.len());
+    let mut in_pos = 0;
+
+    while let Some(&byte) = input.get(in_pos) {
+        if byte == b'%' {
+            let chars = input
+                .get(in_pos + 1..in_pos + 3)
+                .context("Invalid URL encoding.")?;
+            decoded.push(hex_decode([chars[0], chars[1]])?.into());
+            in_pos += 3;
+        } else {
+            decoded.push(byte.into());
+            in_pos += 1;
+        }
+    }
+
+    decoded
+        .parse()
+        .or_else(|_| decoded.parse::<SocketAddr>().map(|socket| socket.ip()))
+        .context("Invalid ip address.")
+}
+
+/// Whether the ip address is publicly routable, as opposed to private,
+/// shared, link-local, documentation and other special-purpose addresses.
+/// Stand-in for the unstable `IpAddr::is_global`.
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.octets()[0] == 0 // "This network"
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                // Shared address space (100.64.0.0/10)
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0b1100_0000 == 0b0100_0000)
+                // IETF protocol assignments (192.0.0.0/24)
+                || (ip.octets()[..3] == [192, 0, 0])
+                // Benchmarking (198.18.0.0/15)
+                || (ip.octets()[0] == 198 && ip.octets()[1] & 0b1111_1110 == 18)
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Reserved (240.0.0.0/4), including broadcast
+                || ip.octets()[0] >= 240)
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // IPv4-mapped addresses (::ffff:0:0/96)
+                || ip.to_ipv4_mapped().is_some()
+                // Discard-only (100::/64)
+                || ip.segments()[..4] == [0x100, 0, 0, 0]
+                // Documentation (2001:db8::/32)
+                || ip.segments()[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
 /// Decodes two ascii-encoded hex digits into one byte.
 #[inline(always)]
 pub fn hex_decode(chars: [u8; 2]) -> Result<u8> {
@@ -111,17 +178,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn urlencoded_ip_addresses() -> Result<()> {
+        assert_eq!(
+            urlencoded_to_ip("192.0.2.1")?,
+            "192.0.2.1".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            urlencoded_to_ip("192.0.2.1%3A6881")?,
+            "192.0.2.1".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            urlencoded_to_ip("2001%3Adb8%3A%3A1")?,
+            "2001:db8::1".parse::<IpAddr>()?
+        );
+        assert_eq!(
+            urlencoded_to_ip("%5B2001%3Adb8%3A%3A1%5D%3A6881")?,
+            "2001:db8::1".parse::<IpAddr>()?
+        );
+        assert!(urlencoded_to_ip("2001%3Adb8%3A%3A1%").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn urlencoded_32_bytes() -> Result<()> {
+        let url_encoded = "%00%01%02%03%04%05%06%07%08%09%0A%0B%0C%0D%0E%0F3333333333333333";
+        let bytes = urlencoded_to_bytes::<32>(url_encoded)?;
+        assert_eq!(
+            bytes,
+            [
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+                0x0E, 0x0F, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33,
+                0x33, 0x33, 0x33, 0x33
+            ]
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn urlencoded_incorrect_length() {
         let url_encoded = "%00";
-        let bytes = urlencoded_to_bytes(url_encoded);
+        let bytes = urlencoded_to_bytes::<20>(url_encoded);
         assert!(bytes.is_err());
     }
 
     #[tokio::test]
     async fn urlencoded_too_many_percents() {
         let url_encoded = "%0%%01%02%03%04%05%06%07%08%09%0A%0B%0C%0D%0E%0F%00%01%02%03";
-        let bytes = urlencoded_to_bytes(url_encoded);
+        let bytes = urlencoded_to_bytes::<20>(url_encoded);
+        assert!(bytes.is_err());
+    }
+
+    #[tokio::test]
+    async fn urlencoded_wrong_length_for_32_bytes() {
+        let url_encoded = "33333333333333333333";
+        let bytes = urlencoded_to_bytes::<32>(url_encoded);
         assert!(bytes.is_err());
     }
 
@@ -217,4 +328,31 @@ mod tests {
         let chars = hex_encode(hex);
         assert_eq!(chars, [b'7', b'C']);
     }
+
+    #[test]
+    fn global_ip_addresses() {
+        for (ip, is_global_ip) in [
+            ("1.1.1.1", true),
+            ("100.128.0.1", true),
+            ("0.1.2.3", false),
+            ("10.0.0.1", false),
+            ("100.64.0.1", false),
+            ("169.254.0.1", false),
+            ("172.16.0.1", false),
+            ("192.0.0.1", false),
+            ("192.168.1.1", false),
+            ("198.19.0.1", false),
+            ("203.0.113.1", false),
+            ("255.255.255.255", false),
+            ("2606:4700::1111", true),
+            ("::1", false),
+            ("::ffff:1.1.1.1", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            ("2001:db8::1", false),
+        ] {
+            assert_eq!(is_global(ip.parse().unwrap()), is_global_ip, "{ip}");
+        }
+    }
 }
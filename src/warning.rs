@@ -1,8 +1,9 @@
+use strum::IntoStaticStr;
 use thiserror::Error;
 
-/// Announce warnings that don't stop the announce processing but still return
-/// an empty peer list..
-#[derive(Error, Debug, Clone, Copy, PartialEq)]
+/// Announce warnings that don't stop the announce processing but usually still
+/// return an empty peer list.
+#[derive(Error, Debug, Clone, Copy, PartialEq, IntoStaticStr)]
 pub enum AnnounceWarning {
     #[error("Rate limit exceeded. Please wait.")]
     RateLimitExceeded,
@@ -10,6 +11,8 @@ pub enum AnnounceWarning {
     HitDownloadSlotLimit,
     #[error("Connectivity issue detected. Enable port-forwarding to resolve.")]
     ConnectivityIssueDetected,
+    #[error("Tracker is overloaded. Stats may not be recorded.")]
+    TrackerOverloaded,
 }
 
 impl AnnounceWarning {
@@ -23,6 +26,15 @@ impl AnnounceWarning {
             _ => false,
         }
     }
+
+    /// Returns true if the warning should cause an empty peer list.
+    fn withholds_peers(&self) -> bool {
+        match self {
+            // The client isn't at fault.
+            Self::TrackerOverloaded => false,
+            _ => true,
+        }
+    }
 }
 
 const SEPARATOR: &[u8] = b"; ";
@@ -56,10 +68,14 @@ impl WarningCollection {
         self.warnings.push(warning);
     }
 
-    /// Returns true if there are no warnings.
-    #[inline(always)]
-    pub fn is_empty(&self) -> bool {
-        self.warnings.is_empty()
+    /// Returns true if any warning should cause an empty peer list.
+    pub fn withholds_peers(&self) -> bool {
+        self.warnings.iter().any(AnnounceWarning::withholds_peers)
+    }
+
+    /// Iterates over the warnings in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &AnnounceWarning> {
+        self.warnings.iter()
     }
 
     /// Create the warning message to be returned to the user.
@@ -0,0 +1,526 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{
+        Path, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use indexmap::{IndexMap, map::Entry};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    sync::mpsc::{self, Sender},
+    time::Instant,
+};
+
+use crate::{
+    announce::{self, Announce, ClientIp, Event},
+    error::AnnounceError::{
+        self, InvalidInfoHash, InvalidPasskey, InvalidPeerId, MalformedRequest, MissingLeft,
+        PasskeyNotFound, PeerIdInUse,
+    },
+    model::{info_hash::InfoHash, passkey::Passkey, peer_id::PeerId},
+    queue::torrent_update::{self, TorrentUpdate},
+    state::AppState,
+    store::peer,
+};
+
+/// User agent recorded for peers announcing through the websocket tracker.
+/// Browser user agents are too long to be stored.
+pub const USER_AGENT: &str = "WebTorrent";
+
+/// Max amount of relayed offers and answers waiting to be sent to a peer.
+/// Further messages are dropped until the peer catches up, so that slow
+/// connections don't buffer messages without bound.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// Websocket connections of the WebRTC peers that announced to this tracker.
+/// Used to relay offers and answers between peers.
+pub struct WebSocketPeers {
+    inner: Mutex<IndexMap<Index, Connection>>,
+}
+
+/// Websocket connection of a peer and the user it belongs to.
+struct Connection {
+    user_id: u32,
+    sender: Sender<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Index {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+impl WebSocketPeers {
+    pub fn new() -> WebSocketPeers {
+        WebSocketPeers {
+            inner: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    /// Registers the connection of the peer, replacing the previous
+    /// connection of the same user. Returns false if another user's peer with
+    /// the same peer id is connected, so that it can't be hijacked to receive
+    /// the offers and answers meant for that peer.
+    fn insert(&self, index: Index, user_id: u32, sender: &Sender<String>) -> bool {
+        match self.inner.lock().entry(index) {
+            Entry::Occupied(entry)
+                if entry.get().user_id != user_id && !entry.get().sender.is_closed() =>
+            {
+                false
+            }
+            Entry::Occupied(mut entry) => {
+                entry.insert(Connection {
+                    user_id,
+                    sender: sender.clone(),
+                });
+
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Connection {
+                    user_id,
+                    sender: sender.clone(),
+                });
+
+                true
+            }
+        }
+    }
+
+    /// Removes the peer unless it has since reconnected through another
+    /// connection. Returns whether the peer was removed.
+    fn remove(&self, index: &Index, sender: &Sender<String>) -> bool {
+        let mut inner = self.inner.lock();
+
+        if let Some(connection) = inner.get(index)
+            && connection.sender.same_channel(sender)
+        {
+            inner.swap_remove(index);
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Sends the message to the peer if it's connected. The message is
+    /// dropped if too many messages are already waiting to be sent.
+    fn send(&self, index: &Index, message: String) {
+        if let Some(connection) = self.inner.lock().get(index) {
+            let _ = connection.sender.try_send(message);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Request {
+    Announce(AnnounceRequest),
+}
+
+/// Announce message of the WebTorrent tracker protocol. Info hashes and peer
+/// ids are sent as binary strings, where each char holds one byte.
+#[derive(Deserialize)]
+struct AnnounceRequest {
+    info_hash: String,
+    peer_id: String,
+    #[serde(default)]
+    uploaded: u64,
+    #[serde(default)]
+    downloaded: u64,
+    left: Option<u64>,
+    event: Option<String>,
+    numwant: Option<usize>,
+    /// WebRTC offers to relay to other peers in the swarm, one per peer.
+    #[serde(default)]
+    offers: Vec<Offer>,
+    /// WebRTC answer to relay back to the peer that sent the offer.
+    answer: Option<Value>,
+    offer_id: Option<String>,
+    to_peer_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Offer {
+    offer_id: String,
+    offer: Value,
+}
+
+/// Upgrades the connection of a WebTorrent client to a websocket.
+pub async fn upgrade(
+    State(state): State<Arc<AppState>>,
+    Path(passkey): Path<String>,
+    ClientIp(client_ip): ClientIp,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, AnnounceError> {
+    if !state.config.load().is_websocket_tracker_enabled {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    // Validate passkey
+    let parsed_passkey = Passkey::from_str(&passkey).or(Err(InvalidPasskey))?;
+    let user_id = state
+        .stores
+        .passkey2id
+        .read()
+        .get(&parsed_passkey)
+        .cloned()
+        .ok_or(PasskeyNotFound)?;
+
+    Ok(upgrade.on_upgrade(move |socket| handle(state, socket, passkey, user_id, client_ip)))
+}
+
+/// Answers the messages of a websocket connection and forwards the offers
+/// and answers relayed from other peers until the connection is closed.
+async fn handle(
+    state: Arc<AppState>,
+    mut socket: WebSocket,
+    passkey: String,
+    user_id: u32,
+    client_ip: IpAddr,
+) {
+    let (sender, mut receiver) = mpsc::channel(MAX_QUEUED_MESSAGES);
+    let mut indices: Vec<Index> = Vec::new();
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let response = handle_message(
+                        &state,
+                        &passkey,
+                        user_id,
+                        client_ip,
+                        text.as_str(),
+                        &sender,
+                        &mut indices,
+                    )
+                    .await;
+
+                    match response {
+                        Some(response) => response,
+                        None => continue,
+                    }
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // Pings are answered automatically
+                Some(Ok(_)) => continue,
+            },
+            Some(message) = receiver.recv() => message,
+        };
+
+        if socket.send(Message::Text(message.into())).await.is_err() {
+            break;
+        }
+    }
+
+    for index in indices.iter() {
+        if state.websocket_peers.remove(index, &sender) {
+            deactivate(&state, user_id, index);
+        }
+    }
+}
+
+/// Marks the peer of a closed connection as inactive, since other peers can
+/// no longer reach it through the tracker. Its peer count is removed from
+/// the torrent and user the same as when the peer expires.
+fn deactivate(state: &AppState, user_id: u32, index: &Index) {
+    let Some(torrent_id) = state
+        .stores
+        .infohash2id
+        .read()
+        .get(&index.info_hash)
+        .map(|mapping| mapping.torrent_id)
+    else {
+        return;
+    };
+
+    let config = state.config.load();
+    let mut torrent_guard = state.stores.torrents.lock(torrent_id);
+
+    let Some(torrent) = torrent_guard.get_mut(&torrent_id) else {
+        return;
+    };
+
+    let Some(peer) = torrent.peers.get_mut(&peer::Index {
+        user_id,
+        peer_id: index.peer_id,
+    }) else {
+        return;
+    };
+
+    if !peer.is_active {
+        return;
+    }
+
+    let seeder_delta = -(peer.is_included_in_seed_list(&config) as i32);
+    let leecher_delta = -(peer.is_included_in_leech_list(&config) as i32);
+
+    peer.is_active = false;
+
+    if seeder_delta == 0 && leecher_delta == 0 {
+        return;
+    }
+
+    torrent.seeders = torrent.seeders.saturating_add_signed(seeder_delta);
+    torrent.leechers = torrent.leechers.saturating_add_signed(leecher_delta);
+
+    drop(torrent_guard);
+
+    if let Some(user) = state.stores.users.write().get_mut(&user_id) {
+        user.num_seeding = user.num_seeding.saturating_add_signed(seeder_delta);
+        user.num_leeching = user.num_leeching.saturating_add_signed(leecher_delta);
+    }
+
+    state.queues.torrents.lock().upsert(
+        torrent_update::Index { torrent_id },
+        TorrentUpdate {
+            seeder_delta,
+            leecher_delta,
+            times_completed_delta: 0,
+            balance_delta: 0,
+        },
+    );
+}
+
+/// Processes a message and returns the response to send back, if any.
+async fn handle_message(
+    state: &Arc<AppState>,
+    passkey: &str,
+    user_id: u32,
+    client_ip: IpAddr,
+    text: &str,
+    sender: &Sender<String>,
+    indices: &mut Vec<Index>,
+) -> Option<String> {
+    let request = match serde_json::from_str(text) {
+        Ok(Request::Announce(request)) => request,
+        Err(_) => return Some(error_response(None, MalformedRequest)),
+    };
+
+    let info_hash = request.info_hash.clone();
+    let start = Instant::now();
+    let result = announce(state, passkey, user_id, client_ip, request, sender, indices).await;
+
+    state
+        .metrics
+        .record_announce(start.elapsed(), result.as_ref().err());
+
+    match result {
+        Ok(response) => response,
+        Err(e) => Some(error_response(Some(info_hash), e)),
+    }
+}
+
+async fn announce(
+    state: &Arc<AppState>,
+    passkey: &str,
+    user_id: u32,
+    client_ip: IpAddr,
+    request: AnnounceRequest,
+    sender: &Sender<String>,
+    indices: &mut Vec<Index>,
+) -> Result<Option<String>, AnnounceError> {
+    let index = Index {
+        info_hash: InfoHash(binary_string_to_bytes(&request.info_hash).ok_or(InvalidInfoHash)?),
+        peer_id: PeerId(binary_string_to_bytes(&request.peer_id).ok_or(InvalidPeerId)?),
+    };
+
+    if let Some(answer) = request.answer {
+        // Only relay answers of peers that announced through this connection
+        if !indices.contains(&index) {
+            return Ok(None);
+        }
+
+        let to_peer_id = request
+            .to_peer_id
+            .as_deref()
+            .and_then(binary_string_to_bytes)
+            .ok_or(InvalidPeerId)?;
+
+        state.websocket_peers.send(
+            &Index {
+                info_hash: index.info_hash,
+                peer_id: PeerId(to_peer_id),
+            },
+            json!({
+                "action": "announce",
+                "answer": answer,
+                "offer_id": request.offer_id,
+                "peer_id": request.peer_id,
+                "info_hash": request.info_hash,
+            })
+            .to_string(),
+        );
+
+        return Ok(None);
+    }
+
+    let event = match request.event.as_deref() {
+        None | Some("update") => Event::Empty,
+        Some(event) => event.parse()?,
+    };
+
+    // One offer is needed for each peer in the peer list
+    let numwant = if event == Event::Stopped {
+        0
+    } else {
+        request
+            .numwant
+            .unwrap_or(request.offers.len())
+            .min(request.offers.len())
+            .min(state.config.load().numwant_max)
+    };
+
+    let response = announce::process(
+        state,
+        passkey,
+        Announce {
+            info_hash: index.info_hash,
+            info_hash_version: None,
+            peer_id: index.peer_id,
+            port: 0,
+            uploaded: request.uploaded,
+            downloaded: request.downloaded,
+            left: request.left.ok_or(MissingLeft)?,
+            event,
+            numwant,
+            corrupt: None,
+            key: None,
+            compact: false,
+            no_peer_id: false,
+            ipv4: None,
+            ipv6: None,
+            is_webrtc: true,
+        },
+        None,
+        client_ip,
+    )
+    .await?;
+
+    if event == Event::Stopped {
+        state.websocket_peers.remove(&index, sender);
+        indices.retain(|&announced_index| announced_index != index);
+    } else if !indices.contains(&index) {
+        if !state.websocket_peers.insert(index, user_id, sender) {
+            return Err(PeerIdInUse);
+        }
+
+        indices.push(index);
+    }
+
+    // Relay each offer to a different peer of the peer list
+    let peer_ids = response
+        .peers_dictionary
+        .into_iter()
+        .flatten()
+        .filter_map(|peer| peer.peer_id);
+
+    for (peer_id, offer) in peer_ids.zip(request.offers) {
+        state.websocket_peers.send(
+            &Index {
+                info_hash: index.info_hash,
+                peer_id,
+            },
+            json!({
+                "action": "announce",
+                "offer": offer.offer,
+                "offer_id": offer.offer_id,
+                "peer_id": request.peer_id,
+                "info_hash": request.info_hash,
+            })
+            .to_string(),
+        );
+    }
+
+    let mut message = json!({
+        "action": "announce",
+        "info_hash": request.info_hash,
+        "interval": response.interval,
+        "complete": response.complete,
+        "incomplete": response.incomplete,
+    });
+
+    if let Some(warning_message) = response.warning_message {
+        message["warning message"] = String::from_utf8_lossy(&warning_message).into();
+    }
+
+    Ok(Some(message.to_string()))
+}
+
+fn error_response(info_hash: Option<String>, error: AnnounceError) -> String {
+    if error.is_critical_warning() {
+        json!({
+            "action": "announce",
+            "info_hash": info_hash,
+            "interval": error.interval(),
+            "complete": 0,
+            "incomplete": 0,
+            "warning message": error.to_string(),
+        })
+    } else {
+        json!({
+            "action": "announce",
+            "info_hash": info_hash,
+            "failure reason": error.to_string(),
+        })
+    }
+    .to_string()
+}
+
+/// Decodes a binary string, where each char holds one byte, into N bytes.
+fn binary_string_to_bytes<const N: usize>(string: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    let mut chars = string.chars();
+
+    for byte in bytes.iter_mut() {
+        *byte = u8::try_from(chars.next()?).ok()?;
+    }
+
+    chars.next().is_none().then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_string_decoding() {
+        assert_eq!(
+            binary_string_to_bytes::<4>("\u{0}a\u{7F}\u{FF}"),
+            Some([0x00, 0x61, 0x7F, 0xFF])
+        );
+        assert_eq!(binary_string_to_bytes::<4>("\u{0}a\u{7F}\u{100}"), None);
+        assert_eq!(binary_string_to_bytes::<4>("abc"), None);
+        assert_eq!(binary_string_to_bytes::<4>("abcde"), None);
+    }
+
+    #[test]
+    fn announce_request_parsing() {
+        let request = serde_json::from_str(
+            r#"{
+                "action": "announce",
+                "info_hash": "aaaaaaaaaaaaaaaaaaaa",
+                "peer_id": "-WW0208-bbbbbbbbbbbb",
+                "left": 0,
+                "event": "started",
+                "numwant": 1,
+                "offers": [{"offer_id": "cccccccccccccccccccc", "offer": {"type": "offer", "sdp": ""}}]
+            }"#,
+        );
+
+        let Ok(Request::Announce(request)) = request else {
+            panic!("Failed to parse announce request.");
+        };
+
+        assert_eq!(request.left, Some(0));
+        assert_eq!(request.offers.len(), 1);
+        assert!(request.answer.is_none());
+    }
+}